@@ -0,0 +1,238 @@
+use crate::contracts::{AnalysisJob, ContentType, Envelope, ProgressEvent};
+use crate::database_function::connection_mongo::Pool as MongoPool;
+#[cfg(feature = "kafka")]
+use crate::kafka::connect_kafka;
+use crate::memory_broker::{DatabaseResults, MemoryBroker};
+use crate::nats::connect_nats;
+use crate::rabbit::connect_rabbit;
+use crate::structure::analysis_structures::AnalysisKind;
+use crate::utils::env_or;
+use deadpool_postgres::Pool as PostgresPool;
+use futures::stream::BoxStream;
+use log::warn;
+use rocket::{Build, Rocket};
+use std::{fmt, sync::Arc};
+use uuid::Uuid;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A progress event, or the reason it could not be decoded, at its position in the task's feed.
+#[derive(Clone)]
+pub struct ProgressMessage {
+    /// Increases with every message of the task; clients send it back as `Last-Event-ID`.
+    pub sequence: u64,
+    pub event: Result<Envelope<ProgressEvent>, String>,
+}
+
+pub type ProgressStream = BoxStream<'static, ProgressMessage>;
+
+#[derive(Debug)]
+pub enum PublishError {
+    Broker(BoxError),
+    Nack,
+    Timeout,
+    Unavailable,
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Broker(e) => write!(f, "broker error: {}", e),
+            Self::Nack => write!(f, "message was nacked by the broker"),
+            Self::Timeout => write!(f, "publisher confirm timed out"),
+            Self::Unavailable => write!(f, "broker is not connected"),
+        }
+    }
+}
+
+impl From<lapin::Error> for PublishError {
+    fn from(e: lapin::Error) -> Self {
+        Self::Broker(Box::new(e))
+    }
+}
+
+impl From<BoxError> for PublishError {
+    fn from(e: BoxError) -> Self {
+        Self::Broker(e)
+    }
+}
+
+/// Delivers analysis jobs and control commands to the workers.
+#[rocket::async_trait]
+pub trait JobPublisher: Send + Sync {
+    /// Publishes an already encoded job of `task_id` to the workers of `kind`.
+    async fn publish_job(
+        &self,
+        task_id: &Uuid,
+        kind: AnalysisKind,
+        content_type: ContentType,
+        payload: &[u8],
+        priority: u8,
+        attempt: u32,
+    ) -> Result<(), PublishError>;
+
+    async fn publish_cancel(&self, task_id: &Uuid) -> Result<(), PublishError>;
+}
+
+/// Carries the progress events of running tasks from the workers to the clients.
+#[rocket::async_trait]
+pub trait ProgressSubscriber: Send + Sync {
+    /// Streams the task's progress, starting after sequence `after` when resuming.
+    async fn subscribe(
+        &self,
+        task_id: Uuid,
+        after: Option<u64>,
+    ) -> Result<ProgressStream, BoxError>;
+
+    /// Returns the task's events after sequence `after` the broker still retains, without
+    /// waiting for new ones.
+    async fn history(&self, task_id: Uuid, after: u64) -> Result<Vec<ProgressMessage>, BoxError>;
+
+    /// Returns a sequence every event the task has so far is at or before, and every later
+    /// one is after, so a run started now is followed without the events of earlier runs.
+    async fn position(&self, task_id: Uuid) -> Result<u64, BoxError>;
+
+    /// Publishes an event on behalf of the server, e.g. a cancellation or a failure.
+    async fn publish(&self, task_id: Uuid, event: ProgressEvent) -> Result<(), PublishError>;
+}
+
+enum BrokerBackend {
+    RabbitMq,
+    #[cfg(feature = "kafka")]
+    Kafka,
+    Memory,
+}
+
+impl BrokerBackend {
+    /// Reads `BROKER_BACKEND`: `rabbitmq` (RabbitMQ jobs with NATS progress), `kafka` or `memory`.
+    fn configured() -> Self {
+        match env_or("BROKER_BACKEND", "rabbitmq".to_string()).as_str() {
+            "rabbitmq" => Self::RabbitMq,
+            #[cfg(feature = "kafka")]
+            "kafka" => Self::Kafka,
+            #[cfg(not(feature = "kafka"))]
+            "kafka" => panic!("BROKER_BACKEND=kafka requires building with the kafka feature"),
+            "memory" => Self::Memory,
+            other => panic!("Unknown BROKER_BACKEND {}", other),
+        }
+    }
+}
+
+pub async fn init_broker(rocket: Rocket<Build>) -> Rocket<Build> {
+    match BrokerBackend::configured() {
+        BrokerBackend::RabbitMq => {
+            let channel = connect_rabbit().await;
+            let progress = connect_nats().await;
+            rocket
+                .manage(channel.clone())
+                .manage(Arc::new(channel) as Arc<dyn JobPublisher>)
+                .manage(Arc::new(progress) as Arc<dyn ProgressSubscriber>)
+        }
+        #[cfg(feature = "kafka")]
+        BrokerBackend::Kafka => {
+            let broker = connect_kafka();
+            rocket
+                .manage(Arc::new(broker.clone()) as Arc<dyn JobPublisher>)
+                .manage(Arc::new(broker) as Arc<dyn ProgressSubscriber>)
+        }
+        BrokerBackend::Memory => {
+            warn!("Using the in-memory broker, jobs are answered by a local echo worker");
+            let postgre_pool = rocket.state::<PostgresPool>().cloned();
+            let mongo_pool = rocket.state::<MongoPool>().cloned();
+            let broker = match (postgre_pool, mongo_pool) {
+                (Some(postgre_pool), Some(mongo_pool)) => MemoryBroker::with_results(Arc::new(
+                    DatabaseResults::new(postgre_pool, mongo_pool),
+                )),
+                _ => {
+                    warn!("Databases are not ready, echoed analyses will not be stored");
+                    MemoryBroker::default()
+                }
+            };
+            rocket
+                .manage(Arc::new(broker.clone()) as Arc<dyn JobPublisher>)
+                .manage(Arc::new(broker) as Arc<dyn ProgressSubscriber>)
+        }
+    }
+}
+
+pub fn text_analysis_job(task_id: &Uuid, run: u32, payload: Vec<&str>) -> Envelope<AnalysisJob> {
+    let payload = payload.into_iter().map(str::to_string).collect();
+    Envelope::for_run(*task_id, run, AnalysisJob::Text(payload))
+}
+
+pub fn photo_analysis_job(task_id: &Uuid, run: u32, payload: Vec<&str>) -> Envelope<AnalysisJob> {
+    let payload = payload.into_iter().map(str::to_string).collect();
+    Envelope::for_run(*task_id, run, AnalysisJob::Photo(payload))
+}
+
+pub fn reviews_analysis_job(
+    task_id: &Uuid,
+    run: u32,
+    payload: Vec<Vec<String>>,
+) -> Envelope<AnalysisJob> {
+    Envelope::for_run(*task_id, run, AnalysisJob::Reviews(payload))
+}
+
+async fn send_message(
+    publisher: &dyn JobPublisher,
+    job: Envelope<AnalysisJob>,
+    priority: u8,
+) -> Result<(), PublishError> {
+    let content_type = ContentType::configured();
+    let payload = job.encode(content_type);
+    publisher
+        .publish_job(
+            &job.correlation_id,
+            job.body.kind(),
+            content_type,
+            &payload,
+            priority,
+            0,
+        )
+        .await
+}
+
+pub async fn send_task_to_text_analysis_queue(
+    publisher: &dyn JobPublisher,
+    task_id: &Uuid,
+    run: u32,
+    payload: Vec<&str>,
+    priority: u8,
+) -> Result<(), PublishError> {
+    send_message(
+        publisher,
+        text_analysis_job(task_id, run, payload),
+        priority,
+    )
+    .await
+}
+
+pub async fn send_task_to_photo_analysis_queue(
+    publisher: &dyn JobPublisher,
+    task_id: &Uuid,
+    run: u32,
+    payload: Vec<&str>,
+    priority: u8,
+) -> Result<(), PublishError> {
+    send_message(
+        publisher,
+        photo_analysis_job(task_id, run, payload),
+        priority,
+    )
+    .await
+}
+
+pub async fn send_task_to_reviews_analysis_queue(
+    publisher: &dyn JobPublisher,
+    task_id: &Uuid,
+    run: u32,
+    payload: Vec<Vec<String>>,
+    priority: u8,
+) -> Result<(), PublishError> {
+    send_message(
+        publisher,
+        reviews_analysis_job(task_id, run, payload),
+        priority,
+    )
+    .await
+}
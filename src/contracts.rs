@@ -0,0 +1,614 @@
+//! Messages exchanged with the analysis workers over RabbitMQ and NATS.
+//!
+//! Every message is wrapped in an [`Envelope`] and encoded as JSON or protobuf depending on its
+//! content type. Messages from workers that still speak the pre-envelope format are decoded
+//! through the legacy types at the bottom of this module.
+
+use crate::api;
+use crate::structure::analysis_structures::AnalysisKind;
+use chrono::{DateTime, TimeZone, Utc};
+use prost::Message as _;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const CONTRACT_VERSION: u32 = 1;
+
+/// Version reported for messages decoded from the pre-envelope format.
+pub const LEGACY_CONTRACT_VERSION: u32 = 0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub schema_version: u32,
+    pub message_id: Uuid,
+    /// Id of the task the message belongs to.
+    pub correlation_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    /// Run of the task a job belongs to; workers return it with their results so a late result
+    /// of an earlier run is not stored over the current one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run: Option<u32>,
+    pub body: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(correlation_id: Uuid, body: T) -> Self {
+        Self {
+            schema_version: CONTRACT_VERSION,
+            message_id: Uuid::new_v4(),
+            correlation_id,
+            created_at: Utc::now(),
+            run: None,
+            body,
+        }
+    }
+
+    /// A job of run `run` of the task.
+    pub fn for_run(correlation_id: Uuid, run: u32, body: T) -> Self {
+        Self {
+            run: Some(run),
+            ..Self::new(correlation_id, body)
+        }
+    }
+
+    fn legacy(correlation_id: Uuid, body: T) -> Self {
+        Self {
+            schema_version: LEGACY_CONTRACT_VERSION,
+            ..Self::new(correlation_id, body)
+        }
+    }
+}
+
+impl<T: Contract> Envelope<T> {
+    pub fn encode(&self, content_type: ContentType) -> Vec<u8> {
+        match content_type {
+            ContentType::Json => {
+                serde_json::to_vec(self).expect("Failed to serialize message to JSON")
+            }
+            ContentType::Protobuf => T::to_proto(self).encode_to_vec(),
+        }
+    }
+
+    fn decode(data: &[u8], content_type: ContentType) -> Result<Self, String> {
+        match content_type {
+            ContentType::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            ContentType::Protobuf => T::Proto::decode(data)
+                .map_err(|e| e.to_string())
+                .and_then(T::from_proto),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentType {
+    Json,
+    Protobuf,
+}
+
+impl ContentType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    /// Missing or unknown content types are read as JSON, which is what legacy publishers send.
+    pub fn from_header(value: Option<&str>) -> Self {
+        match value {
+            Some("application/x-protobuf" | "application/protobuf") => Self::Protobuf,
+            _ => Self::Json,
+        }
+    }
+
+    /// Content type of outgoing messages, `BROKER_CONTENT_TYPE`.
+    pub fn configured() -> Self {
+        Self::from_header(std::env::var("BROKER_CONTENT_TYPE").ok().as_deref())
+    }
+}
+
+/// Envelope bodies with a protobuf counterpart in `proto/api.proto`.
+pub trait Contract: Sized + Serialize + DeserializeOwned {
+    type Proto: prost::Message + Default;
+
+    fn to_proto(envelope: &Envelope<Self>) -> Self::Proto;
+    fn from_proto(proto: Self::Proto) -> Result<Envelope<Self>, String>;
+}
+
+fn header_to_proto<T>(envelope: &Envelope<T>) -> Option<api::EnvelopeHeader> {
+    Some(api::EnvelopeHeader {
+        schema_version: envelope.schema_version,
+        message_id: envelope.message_id.to_string(),
+        correlation_id: envelope.correlation_id.to_string(),
+        created_at_ms: envelope.created_at.timestamp_millis(),
+        run: envelope.run.unwrap_or(0),
+    })
+}
+
+fn envelope_from_proto<T>(
+    header: Option<api::EnvelopeHeader>,
+    body: T,
+) -> Result<Envelope<T>, String> {
+    let header = header.ok_or("missing envelope header")?;
+    Ok(Envelope {
+        schema_version: header.schema_version,
+        message_id: Uuid::parse_str(&header.message_id).map_err(|e| e.to_string())?,
+        correlation_id: Uuid::parse_str(&header.correlation_id).map_err(|e| e.to_string())?,
+        created_at: Utc
+            .timestamp_millis_opt(header.created_at_ms)
+            .single()
+            .ok_or("invalid envelope timestamp")?,
+        run: (header.run > 0).then_some(header.run),
+        body,
+    })
+}
+
+fn kind_to_proto(kind: AnalysisKind) -> i32 {
+    match kind {
+        AnalysisKind::Text => api::AnalysisKind::Text,
+        AnalysisKind::Photo => api::AnalysisKind::Photo,
+        AnalysisKind::Reviews => api::AnalysisKind::Reviews,
+    }
+    .into()
+}
+
+fn kind_from_proto(kind: i32) -> Result<AnalysisKind, String> {
+    match api::AnalysisKind::try_from(kind) {
+        Ok(api::AnalysisKind::Text) => Ok(AnalysisKind::Text),
+        Ok(api::AnalysisKind::Photo) => Ok(AnalysisKind::Photo),
+        Ok(api::AnalysisKind::Reviews) => Ok(AnalysisKind::Reviews),
+        _ => Err(format!("unknown analysis kind {}", kind)),
+    }
+}
+
+/// Job sent to the worker pool of one analysis kind.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "lowercase")]
+pub enum AnalysisJob {
+    Text(Vec<String>),
+    Photo(Vec<String>),
+    Reviews(Vec<Vec<String>>),
+}
+
+impl AnalysisJob {
+    pub fn kind(&self) -> AnalysisKind {
+        match self {
+            Self::Text(_) => AnalysisKind::Text,
+            Self::Photo(_) => AnalysisKind::Photo,
+            Self::Reviews(_) => AnalysisKind::Reviews,
+        }
+    }
+}
+
+impl Contract for AnalysisJob {
+    type Proto = api::AnalysisJobMessage;
+
+    fn to_proto(envelope: &Envelope<Self>) -> Self::Proto {
+        let (items, review_batches) = match &envelope.body {
+            Self::Text(items) | Self::Photo(items) => (items.clone(), Vec::new()),
+            Self::Reviews(batches) => (
+                Vec::new(),
+                batches
+                    .iter()
+                    .map(|reviews| api::ReviewBatch {
+                        reviews: reviews.clone(),
+                    })
+                    .collect(),
+            ),
+        };
+        api::AnalysisJobMessage {
+            header: header_to_proto(envelope),
+            kind: kind_to_proto(envelope.body.kind()),
+            items,
+            review_batches,
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Envelope<Self>, String> {
+        let body = match kind_from_proto(proto.kind)? {
+            AnalysisKind::Text => Self::Text(proto.items),
+            AnalysisKind::Photo => Self::Photo(proto.items),
+            AnalysisKind::Reviews => Self::Reviews(
+                proto
+                    .review_batches
+                    .into_iter()
+                    .map(|batch| batch.reviews)
+                    .collect(),
+            ),
+        };
+        envelope_from_proto(proto.header, body)
+    }
+}
+
+/// Broadcast to every worker on the control exchange.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ControlMessage {
+    Cancel,
+}
+
+impl Contract for ControlMessage {
+    type Proto = api::ControlMessage;
+
+    fn to_proto(envelope: &Envelope<Self>) -> Self::Proto {
+        let command = match envelope.body {
+            Self::Cancel => api::control_message::Command::Cancel(api::CancelCommand {}),
+        };
+        api::ControlMessage {
+            header: header_to_proto(envelope),
+            command: Some(command),
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Envelope<Self>, String> {
+        let body = match proto.command.ok_or("missing control command")? {
+            api::control_message::Command::Cancel(_) => Self::Cancel,
+        };
+        envelope_from_proto(proto.header, body)
+    }
+}
+
+/// Progress of a task, published on `ai_stream.<task_id>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ProgressEvent {
+    Chunk { kind: AnalysisKind, text: String },
+    End { kind: AnalysisKind },
+    Cancelled,
+    Failed { reason: String },
+}
+
+impl Contract for ProgressEvent {
+    type Proto = api::ProgressEventMessage;
+
+    fn to_proto(envelope: &Envelope<Self>) -> Self::Proto {
+        use api::progress_event_message::Event;
+        let event = match &envelope.body {
+            Self::Chunk { kind, text } => Event::Chunk(api::ProgressChunk {
+                kind: kind_to_proto(*kind),
+                text: text.clone(),
+            }),
+            Self::End { kind } => Event::End(api::ProgressEnd {
+                kind: kind_to_proto(*kind),
+            }),
+            Self::Cancelled => Event::Cancelled(api::ProgressCancelled {}),
+            Self::Failed { reason } => Event::Failed(api::ProgressFailed {
+                reason: reason.clone(),
+            }),
+        };
+        api::ProgressEventMessage {
+            header: header_to_proto(envelope),
+            event: Some(event),
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Envelope<Self>, String> {
+        use api::progress_event_message::Event;
+        let body = match proto.event.ok_or("missing progress event")? {
+            Event::Chunk(chunk) => Self::Chunk {
+                kind: kind_from_proto(chunk.kind)?,
+                text: chunk.text,
+            },
+            Event::End(end) => Self::End {
+                kind: kind_from_proto(end.kind)?,
+            },
+            Event::Cancelled(_) => Self::Cancelled,
+            Event::Failed(failed) => Self::Failed {
+                reason: failed.reason,
+            },
+        };
+        envelope_from_proto(proto.header, body)
+    }
+}
+
+/// Decodes a job, accepting envelopes of either content type and the legacy JSON format.
+pub fn decode_job(data: &[u8], content_type: ContentType) -> Result<Envelope<AnalysisJob>, String> {
+    let decoded = Envelope::decode(data, content_type);
+    if decoded.is_ok() || content_type == ContentType::Protobuf {
+        return decoded;
+    }
+    let legacy: LegacyJob = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+    let task_id = Uuid::parse_str(&legacy.task_id).map_err(|e| e.to_string())?;
+    let kind = AnalysisKind::parse(&legacy.task_type)
+        .ok_or_else(|| format!("unknown job type {}", legacy.task_type))?;
+    let payload = legacy.payload;
+    let job = match kind {
+        AnalysisKind::Text => serde_json::from_value(payload).map(AnalysisJob::Text),
+        AnalysisKind::Photo => serde_json::from_value(payload).map(AnalysisJob::Photo),
+        AnalysisKind::Reviews => serde_json::from_value(payload).map(AnalysisJob::Reviews),
+    }
+    .map_err(|e| e.to_string())?;
+    Ok(Envelope::legacy(task_id, job))
+}
+
+/// Decodes a progress message of `task_id`, accepting envelopes of either content type and the
+/// legacy JSON format.
+pub fn decode_progress(
+    task_id: Uuid,
+    data: &[u8],
+    content_type: ContentType,
+) -> Result<Envelope<ProgressEvent>, String> {
+    let decoded = Envelope::decode(data, content_type);
+    if decoded.is_ok() || content_type == ContentType::Protobuf {
+        return decoded;
+    }
+    let legacy: LegacyProgress = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+    let event = match (legacy.task_type.as_str(), legacy.message.as_str()) {
+        (LEGACY_SYSTEM_TYPE, LEGACY_CANCELLED) => ProgressEvent::Cancelled,
+        (LEGACY_SYSTEM_TYPE, LEGACY_FAILED) => ProgressEvent::Failed {
+            reason: legacy.reason.unwrap_or_default(),
+        },
+        (task_type, message) => {
+            let kind = AnalysisKind::parse(task_type)
+                .ok_or_else(|| format!("unknown progress type {}", task_type))?;
+            if message == LEGACY_END {
+                ProgressEvent::End { kind }
+            } else {
+                ProgressEvent::Chunk {
+                    kind,
+                    text: message.to_string(),
+                }
+            }
+        }
+    };
+    Ok(Envelope::legacy(task_id, event))
+}
+
+const LEGACY_SYSTEM_TYPE: &str = "system";
+const LEGACY_END: &str = "__end__";
+const LEGACY_CANCELLED: &str = "__cancelled__";
+const LEGACY_FAILED: &str = "__failed__";
+
+#[derive(Deserialize)]
+struct LegacyJob {
+    task_type: String,
+    payload: serde_json::Value,
+    task_id: String,
+}
+
+#[derive(Deserialize)]
+struct LegacyProgress {
+    message: String,
+    task_type: String,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn jobs() -> Vec<AnalysisJob> {
+        vec![
+            AnalysisJob::Text(vec!["main".to_string(), "competitor".to_string()]),
+            AnalysisJob::Photo(vec![
+                "https://example.com/main.jpg".to_string(),
+                String::new(),
+            ]),
+            AnalysisJob::Reviews(vec![vec!["good".to_string(), "bad".to_string()], vec![]]),
+        ]
+    }
+
+    fn events() -> Vec<ProgressEvent> {
+        vec![
+            ProgressEvent::Chunk {
+                kind: AnalysisKind::Photo,
+                text: "sharp".to_string(),
+            },
+            ProgressEvent::End {
+                kind: AnalysisKind::Reviews,
+            },
+            ProgressEvent::Cancelled,
+            ProgressEvent::Failed {
+                reason: "worker crashed".to_string(),
+            },
+        ]
+    }
+
+    /// Asserts that `decoded` carries what `sent` did, to the millisecond the wire keeps.
+    fn assert_same<T: Serialize>(sent: &Envelope<T>, decoded: &Envelope<T>) {
+        assert_eq!(decoded.schema_version, sent.schema_version);
+        assert_eq!(decoded.message_id, sent.message_id);
+        assert_eq!(decoded.correlation_id, sent.correlation_id);
+        assert_eq!(
+            decoded.created_at.timestamp_millis(),
+            sent.created_at.timestamp_millis()
+        );
+        assert_eq!(decoded.run, sent.run);
+        assert_eq!(
+            serde_json::to_value(&decoded.body).unwrap(),
+            serde_json::to_value(&sent.body).unwrap()
+        );
+    }
+
+    #[test]
+    fn jobs_round_trip_as_json() {
+        for job in jobs() {
+            let sent = Envelope::for_run(Uuid::new_v4(), 3, job);
+            let decoded = decode_job(&sent.encode(ContentType::Json), ContentType::Json).unwrap();
+            assert_same(&sent, &decoded);
+        }
+    }
+
+    #[test]
+    fn progress_events_round_trip_as_json() {
+        for event in events() {
+            let sent = Envelope::new(Uuid::new_v4(), event);
+            let decoded = decode_progress(
+                sent.correlation_id,
+                &sent.encode(ContentType::Json),
+                ContentType::Json,
+            )
+            .unwrap();
+            assert_same(&sent, &decoded);
+        }
+    }
+
+    #[test]
+    fn content_type_is_read_from_the_header() {
+        for (header, content_type) in [
+            (Some("application/x-protobuf"), ContentType::Protobuf),
+            (Some("application/protobuf"), ContentType::Protobuf),
+            (Some("application/json"), ContentType::Json),
+            (Some("text/plain"), ContentType::Json),
+            (None, ContentType::Json),
+        ] {
+            assert_eq!(
+                ContentType::from_header(header),
+                content_type,
+                "{:?}",
+                header
+            );
+        }
+        for content_type in [ContentType::Json, ContentType::Protobuf] {
+            assert_eq!(
+                ContentType::from_header(Some(content_type.as_str())),
+                content_type
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_sentinels_decode_to_events() {
+        let task_id = Uuid::new_v4();
+        for (message, expected) in [
+            (
+                r#"{"message":"__end__","task_type":"text"}"#,
+                json!({ "type": "end", "kind": "text" }),
+            ),
+            (
+                r#"{"message":"__cancelled__","task_type":"system"}"#,
+                json!({ "type": "cancelled" }),
+            ),
+            (
+                r#"{"message":"__failed__","task_type":"system","reason":"timeout"}"#,
+                json!({ "type": "failed", "reason": "timeout" }),
+            ),
+            (
+                r#"{"message":"__failed__","task_type":"system"}"#,
+                json!({ "type": "failed", "reason": "" }),
+            ),
+            (
+                r#"{"message":"keywords","task_type":"reviews"}"#,
+                json!({ "type": "chunk", "kind": "reviews", "text": "keywords" }),
+            ),
+        ] {
+            let decoded = decode_progress(task_id, message.as_bytes(), ContentType::Json).unwrap();
+            assert_eq!(decoded.schema_version, LEGACY_CONTRACT_VERSION);
+            assert_eq!(decoded.correlation_id, task_id);
+            assert_eq!(
+                serde_json::to_value(&decoded.body).unwrap(),
+                expected,
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_sentinels_of_unknown_types_are_refused() {
+        for message in [
+            r#"{"message":"__end__","task_type":"system"}"#,
+            r#"{"message":"__cancelled__","task_type":"video"}"#,
+            r#"{"task_type":"text"}"#,
+        ] {
+            assert!(
+                decode_progress(Uuid::new_v4(), message.as_bytes(), ContentType::Json).is_err(),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_jobs_decode_to_envelopes() {
+        let task_id = Uuid::new_v4();
+        let legacy = json!({
+            "task_type": "reviews",
+            "payload": [["good"], []],
+            "task_id": task_id.to_string(),
+        });
+        let decoded = decode_job(legacy.to_string().as_bytes(), ContentType::Json).unwrap();
+        assert_eq!(decoded.schema_version, LEGACY_CONTRACT_VERSION);
+        assert_eq!(decoded.correlation_id, task_id);
+        assert_eq!(decoded.run, None);
+        assert!(matches!(decoded.body, AnalysisJob::Reviews(ref batches) if batches.len() == 2));
+
+        let unknown =
+            json!({ "task_type": "video", "payload": [], "task_id": task_id.to_string() });
+        assert!(decode_job(unknown.to_string().as_bytes(), ContentType::Json).is_err());
+    }
+
+    #[test]
+    fn jobs_round_trip_as_protobuf() {
+        for job in jobs() {
+            let sent = Envelope::for_run(Uuid::new_v4(), 3, job);
+            let decoded =
+                decode_job(&sent.encode(ContentType::Protobuf), ContentType::Protobuf).unwrap();
+            assert_same(&sent, &decoded);
+        }
+    }
+
+    #[test]
+    fn progress_events_round_trip_as_protobuf() {
+        for event in events() {
+            let sent = Envelope::new(Uuid::new_v4(), event);
+            let decoded = decode_progress(
+                sent.correlation_id,
+                &sent.encode(ContentType::Protobuf),
+                ContentType::Protobuf,
+            )
+            .unwrap();
+            assert_same(&sent, &decoded);
+        }
+    }
+
+    #[test]
+    fn protobuf_and_json_decode_to_the_same_envelope() {
+        for job in jobs() {
+            let sent = Envelope::for_run(Uuid::new_v4(), 2, job);
+            let json = decode_job(&sent.encode(ContentType::Json), ContentType::Json).unwrap();
+            let protobuf =
+                decode_job(&sent.encode(ContentType::Protobuf), ContentType::Protobuf).unwrap();
+            assert_same(&json, &protobuf);
+        }
+        for event in events() {
+            let sent = Envelope::new(Uuid::new_v4(), event);
+            let task_id = sent.correlation_id;
+            let json = decode_progress(task_id, &sent.encode(ContentType::Json), ContentType::Json)
+                .unwrap();
+            let protobuf = decode_progress(
+                task_id,
+                &sent.encode(ContentType::Protobuf),
+                ContentType::Protobuf,
+            )
+            .unwrap();
+            assert_same(&json, &protobuf);
+        }
+    }
+
+    #[test]
+    fn unknown_content_type_is_decoded_as_json() {
+        let sent = Envelope::new(Uuid::new_v4(), ProgressEvent::Cancelled);
+        let content_type = ContentType::from_header(Some("application/octet-stream"));
+        let decoded = decode_progress(
+            sent.correlation_id,
+            &sent.encode(ContentType::Json),
+            content_type,
+        )
+        .unwrap();
+        assert_same(&sent, &decoded);
+
+        // Protobuf bytes are not mistaken for JSON.
+        let protobuf = sent.encode(ContentType::Protobuf);
+        assert!(decode_progress(sent.correlation_id, &protobuf, content_type).is_err());
+    }
+
+    #[test]
+    fn protobuf_without_a_run_has_none() {
+        let sent = Envelope::new(Uuid::new_v4(), jobs().remove(0));
+        let decoded =
+            decode_job(&sent.encode(ContentType::Protobuf), ContentType::Protobuf).unwrap();
+        assert_eq!(decoded.run, None);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::database_function::function_postgre::check_and_create_schema;
 use deadpool_postgres::{Pool, Runtime};
 use rocket::{Build, Rocket};
 use tokio_postgres::NoTls;
@@ -31,6 +32,7 @@ pub async fn init_db_pool(rocket: Rocket<Build>) -> Rocket<Build> {
         .runtime(Runtime::Tokio1)
         .build()
         .unwrap();
+    check_and_create_schema(&pool).await.unwrap();
 
     rocket.manage(pool)
 }
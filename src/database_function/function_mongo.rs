@@ -1,16 +1,33 @@
 use crate::database_function::connection_mongo::Pool;
 use crate::database_function::connection_mongo::PoolError;
+use futures::TryStreamExt;
 use mongodb::{
-    bson::{doc, Binary, DateTime},
-    Client, Collection,
+    bson::{doc, Binary, DateTime, Document},
+    options::IndexOptions,
+    Client, Collection, IndexModel,
 };
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock};
 use uuid::Uuid;
 
+use crate::contracts::ProgressEvent;
+use crate::structure::analysis_structures::{
+    deserialize_legacy, AnalysisKind, PhotoAnalysis, ReviewAnalysis, TextAnalysis,
+};
 use serde::{Deserialize, Serialize};
 
 const DB_NAME: &str = "ai_tasks";
+const DEAD_LETTERS_COLLECTION: &str = "dead_letters";
+const PROGRESS_LOGS_COLLECTION: &str = "progress_logs";
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Review {
+    pub text: String,
+    pub pros: String,
+    pub cons: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Product {
     pub id: u64,
     pub name: String,
@@ -18,9 +35,13 @@ pub struct Product {
     pub root: u64,
     pub price: u64,
     pub review: f32,
+    #[serde(default)]
+    pub image_url: String,
+    #[serde(default)]
+    pub reviews: Vec<Review>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WordsAnalysis {
     pub used_words: Vec<String>,
     pub unused_words: Vec<String>,
@@ -30,13 +51,104 @@ pub struct WordsAnalysis {
 pub struct ProductAnalysis {
     #[serde(rename = "_id")]
     pub id: Uuid,
+    #[serde(default = "first_run")]
+    pub run: u32,
     pub created_at: DateTime,
     pub main_product: Product,
     pub competitors: Vec<Product>,
     pub words_analysis: WordsAnalysis,
-    pub text_analysis: Option<String>,
-    pub photo_analysis: Option<String>,
-    pub review_analysis: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_legacy")]
+    pub text_analysis: Option<TextAnalysis>,
+    #[serde(default, deserialize_with = "deserialize_legacy")]
+    pub photo_analysis: Option<PhotoAnalysis>,
+    #[serde(default, deserialize_with = "deserialize_legacy")]
+    pub review_analysis: Option<ReviewAnalysis>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskRun {
+    pub task_id: Uuid,
+    pub run: u32,
+    pub created_at: DateTime,
+    pub archived_at: DateTime,
+    pub main_product: Product,
+    pub competitors: Vec<Product>,
+    pub words_analysis: WordsAnalysis,
+    pub text_analysis: Option<TextAnalysis>,
+    pub photo_analysis: Option<PhotoAnalysis>,
+    pub review_analysis: Option<ReviewAnalysis>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub task_id: String,
+    pub task_type: String,
+    pub payload: String,
+    pub reason: String,
+    pub attempts: u32,
+    #[serde(default)]
+    pub priority: u8,
+    pub dead_at: DateTime,
+}
+
+/// Progress events of a task's last finished run, kept after the broker has dropped them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProgressLog {
+    #[serde(rename = "_id")]
+    pub task_id: Uuid,
+    /// Progress sequence the run's events follow, 0 for logs archived before runs were told
+    /// apart.
+    #[serde(default)]
+    pub run_start: i64,
+    pub events: Vec<ArchivedProgress>,
+    pub archived_at: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedProgress {
+    pub sequence: i64,
+    /// `None` for a message that could not be decoded, see `error`.
+    pub event: Option<ProgressEvent>,
+    pub error: Option<String>,
+}
+
+/// Run a task is created on.
+pub const FIRST_RUN: u32 = 1;
+
+fn first_run() -> u32 {
+    FIRST_RUN
+}
+
+fn runs_collection(client: &Client, user_id: &str) -> Collection<TaskRun> {
+    client
+        .database(DB_NAME)
+        .collection(&format!("{}_runs", user_id))
+}
+
+/// Users whose runs collection got its unique `task_id`, `run` index from this process.
+fn indexed_runs() -> &'static Mutex<HashSet<String>> {
+    static INDEXED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    INDEXED.get_or_init(Default::default)
+}
+
+/// Indexes the user's runs collection, once per process. Collections archived into before the
+/// index existed may hold duplicate runs, which keep it from being built; archiving still
+/// doesn't add more, so that is only logged.
+async fn index_runs(client: &Client, user_id: &str) {
+    if let Err(e) = runs_collection(client, user_id)
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "task_id": 1, "run": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        )
+        .await
+    {
+        log::warn!("Failed to index runs of {}: {}", user_id, e);
+    }
+    indexed_runs().lock().unwrap().insert(user_id.to_string());
 }
 
 pub async fn check_and_create_db(client: &Client) -> Result<(), mongodb::error::Error> {
@@ -45,10 +157,16 @@ pub async fn check_and_create_db(client: &Client) -> Result<(), mongodb::error::
         println!("Database 'ai_tasks' not found, creating...");
         client.database(DB_NAME).create_collection("tasks").await?;
     }
+    // Runs collections of users who haven't archived a run yet are indexed on their first one.
+    for name in client.database(DB_NAME).list_collection_names().await? {
+        if let Some(user_id) = name.strip_suffix("_runs") {
+            index_runs(client, user_id).await;
+        }
+    }
     Ok(())
 }
 
-pub async fn create_task<'a>(
+pub async fn create_task(
     mongo_pool: &Pool,
     user_id: &str,
     id: Uuid,
@@ -61,6 +179,7 @@ pub async fn create_task<'a>(
     let collection: Collection<ProductAnalysis> = client.database(DB_NAME).collection(user_id);
     let task = ProductAnalysis {
         id,
+        run: first_run(),
         created_at: DateTime::now(),
         main_product,
         competitors,
@@ -72,10 +191,7 @@ pub async fn create_task<'a>(
         photo_analysis: None,
         review_analysis: None,
     };
-    collection
-        .insert_one(task)
-        .await
-        .map_err(|e| PoolError::from(e))?;
+    collection.insert_one(task).await.map_err(PoolError::from)?;
     Ok(())
 }
 
@@ -89,12 +205,10 @@ pub async fn get_task<'a>(
     let uuid_bytes = id.as_bytes();
     let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
 
-    let not_task = collection
-        .find_one(filter)
-        .await
-        .map_err(|e| PoolError::from(e))?;
+    let not_task = collection.find_one(filter).await.map_err(PoolError::from)?;
     let task = not_task.map(|doc| ProductAnalysis {
         id: doc.id,
+        run: doc.run,
         created_at: doc.created_at,
         main_product: doc.main_product,
         competitors: doc.competitors,
@@ -119,14 +233,32 @@ pub async fn delete_task<'a>(
     let _ = collection
         .delete_one(filter)
         .await
-        .map_err(|e| PoolError::from(e))?;
+        .map_err(PoolError::from)?;
+
+    let filter = doc! { "task_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    runs_collection(&client, user_id)
+        .delete_many(filter)
+        .await
+        .map_err(PoolError::from)?;
+
+    let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    client
+        .database(DB_NAME)
+        .collection::<ProgressLog>(PROGRESS_LOGS_COLLECTION)
+        .delete_one(filter)
+        .await
+        .map_err(PoolError::from)?;
     Ok(())
 }
 
-pub async fn update_task<'a>(
+/// Moves the task from run `previous` to the next one with new inputs. Does nothing if the task
+/// already moved on.
+#[allow(clippy::too_many_arguments)]
+pub async fn update_task(
     mongo_pool: &Pool,
     user_id: &str,
     id: Uuid,
+    previous: u32,
     main_product: Product,
     competitors: Vec<Product>,
     used_words: Vec<String>,
@@ -136,94 +268,308 @@ pub async fn update_task<'a>(
     let collection: Collection<ProductAnalysis> = client.database(DB_NAME).collection(user_id);
 
     let uuid_bytes = id.as_bytes();
-    let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    let filter = doc! {
+        "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() },
+        "$or": [{ "run": previous }, { "run": { "$exists": false } }],
+    };
     let update = doc! {
         "$set": {
+            "run": previous + 1,
             "created_at": DateTime::now(),
             "main_product": bson::to_bson(&main_product).unwrap(),
             "competitors": bson::to_bson(&competitors).unwrap(),
             "words_analysis": {
                 "used_words": used_words,
                 "unused_words": unused_words
-            }
+            },
+            "text_analysis": null,
+            "photo_analysis": null,
+            "review_analysis": null
         }
     };
 
     collection
         .update_one(filter, update)
         .await
-        .map_err(|e| PoolError::from(e))?;
+        .map_err(PoolError::from)?;
     Ok(())
 }
 
-pub async fn update_text_analysis<'a>(
+/// Filter of the task a result is stored on; with `run`, only while that run is the current one.
+fn result_filter(id: Uuid, run: Option<u32>) -> Document {
+    let uuid_bytes = id.as_bytes();
+    let mut filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    match run {
+        // Tasks stored before runs were counted are on their first run.
+        Some(FIRST_RUN) => {
+            filter.insert(
+                "$or",
+                vec![
+                    doc! { "run": FIRST_RUN },
+                    doc! { "run": { "$exists": false } },
+                ],
+            );
+        }
+        Some(run) => {
+            filter.insert("run", run);
+        }
+        None => {}
+    }
+    filter
+}
+
+pub async fn update_text_analysis(
     mongo_pool: &Pool,
     id: Uuid,
     user_id: &str,
-    data: &str,
-) -> Result<(), PoolError> {
+    run: Option<u32>,
+    data: &TextAnalysis,
+) -> Result<bool, PoolError> {
     let client = mongo_pool.get().await?;
     let collection: Collection<ProductAnalysis> = client.database(DB_NAME).collection(user_id);
 
-    let uuid_bytes = id.as_bytes();
-    let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    let filter = result_filter(id, run);
     let update = doc! {
         "$set": {
-            "text_analysis": data
+            "text_analysis": bson::to_bson(data).map_err(mongodb::error::Error::from)?
         }
     };
 
-    collection
+    let result = collection
         .update_one(filter, update)
         .await
-        .map_err(|e| PoolError::from(e))?;
-    Ok(())
+        .map_err(PoolError::from)?;
+    Ok(result.matched_count > 0)
 }
 
-pub async fn update_photo_analysis<'a>(
+pub async fn update_photo_analysis(
     mongo_pool: &Pool,
     id: Uuid,
     user_id: &str,
-    data: &str,
-) -> Result<(), PoolError> {
+    run: Option<u32>,
+    data: &PhotoAnalysis,
+) -> Result<bool, PoolError> {
     let client = mongo_pool.get().await?;
     let collection: Collection<ProductAnalysis> = client.database(DB_NAME).collection(user_id);
 
-    let uuid_bytes = id.as_bytes();
-    let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    let filter = result_filter(id, run);
     let update = doc! {
         "$set": {
-            "photo_analysis": data
+            "photo_analysis": bson::to_bson(data).map_err(mongodb::error::Error::from)?
         }
     };
 
-    collection
+    let result = collection
         .update_one(filter, update)
         .await
-        .map_err(|e| PoolError::from(e))?;
-    Ok(())
+        .map_err(PoolError::from)?;
+    Ok(result.matched_count > 0)
 }
 
-pub async fn update_review_analysis<'a>(
+pub async fn update_review_analysis(
     mongo_pool: &Pool,
     id: Uuid,
     user_id: &str,
-    data: &str,
+    run: Option<u32>,
+    data: &ReviewAnalysis,
+) -> Result<bool, PoolError> {
+    let client = mongo_pool.get().await?;
+    let collection: Collection<ProductAnalysis> = client.database(DB_NAME).collection(user_id);
+
+    let filter = result_filter(id, run);
+    let update = doc! {
+        "$set": {
+            "review_analysis": bson::to_bson(data).map_err(mongodb::error::Error::from)?
+        }
+    };
+
+    let result = collection
+        .update_one(filter, update)
+        .await
+        .map_err(PoolError::from)?;
+    Ok(result.matched_count > 0)
+}
+
+/// Archives the task's current run. Archiving a run again replaces its copy, so a retried
+/// regenerate doesn't leave duplicates.
+pub async fn archive_task_run(
+    mongo_pool: &Pool,
+    user_id: &str,
+    task: &ProductAnalysis,
+) -> Result<(), PoolError> {
+    let client = mongo_pool.get().await?;
+    let run = TaskRun {
+        task_id: task.id,
+        run: task.run,
+        created_at: task.created_at,
+        archived_at: DateTime::now(),
+        main_product: task.main_product.clone(),
+        competitors: task.competitors.clone(),
+        words_analysis: task.words_analysis.clone(),
+        text_analysis: task.text_analysis.clone(),
+        photo_analysis: task.photo_analysis.clone(),
+        review_analysis: task.review_analysis.clone(),
+    };
+    if !indexed_runs().lock().unwrap().contains(user_id) {
+        index_runs(&client, user_id).await;
+    }
+    let collection = runs_collection(&client, user_id);
+    let uuid_bytes = task.id.as_bytes();
+    let filter = doc! {
+        "task_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() },
+        "run": task.run,
+    };
+    collection
+        .replace_one(filter, &run)
+        .upsert(true)
+        .await
+        .map_err(PoolError::from)?;
+    Ok(())
+}
+
+pub async fn get_task_runs(
+    mongo_pool: &Pool,
+    user_id: &str,
+    task_id: Uuid,
+) -> Result<Vec<TaskRun>, PoolError> {
+    let client = mongo_pool.get().await?;
+    let uuid_bytes = task_id.as_bytes();
+    let filter = doc! { "task_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+
+    runs_collection(&client, user_id)
+        .find(filter)
+        .sort(doc! { "run": 1 })
+        .await
+        .map_err(PoolError::from)?
+        .try_collect()
+        .await
+        .map_err(PoolError::from)
+}
+
+pub async fn get_task_run(
+    mongo_pool: &Pool,
+    user_id: &str,
+    task_id: Uuid,
+    run: u32,
+) -> Result<Option<TaskRun>, PoolError> {
+    let client = mongo_pool.get().await?;
+    let uuid_bytes = task_id.as_bytes();
+    let filter = doc! {
+        "task_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() },
+        "run": run
+    };
+
+    runs_collection(&client, user_id)
+        .find_one(filter)
+        .await
+        .map_err(PoolError::from)
+}
+
+/// Moves the task from run `previous` to the next one, clearing the result of `kind`. Does
+/// nothing if the task already moved on.
+pub async fn start_partial_run(
+    mongo_pool: &Pool,
+    user_id: &str,
+    id: Uuid,
+    previous: u32,
+    kind: AnalysisKind,
 ) -> Result<(), PoolError> {
     let client = mongo_pool.get().await?;
     let collection: Collection<ProductAnalysis> = client.database(DB_NAME).collection(user_id);
 
     let uuid_bytes = id.as_bytes();
-    let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    let filter = doc! {
+        "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() },
+        "$or": [{ "run": previous }, { "run": { "$exists": false } }],
+    };
+    let run = previous + 1;
+    let field = match kind {
+        AnalysisKind::Text => "text_analysis",
+        AnalysisKind::Photo => "photo_analysis",
+        AnalysisKind::Reviews => "review_analysis",
+    };
     let update = doc! {
         "$set": {
-            "review_analysis": data
+            "run": run,
+            "created_at": DateTime::now(),
+            field: null
         }
     };
 
     collection
         .update_one(filter, update)
         .await
-        .map_err(|e| PoolError::from(e))?;
+        .map_err(PoolError::from)?;
+    Ok(())
+}
+
+pub async fn insert_dead_letter(
+    mongo_pool: &Pool,
+    dead_letter: DeadLetter,
+) -> Result<(), PoolError> {
+    let client = mongo_pool.get().await?;
+    let collection: Collection<DeadLetter> =
+        client.database(DB_NAME).collection(DEAD_LETTERS_COLLECTION);
+    collection
+        .insert_one(dead_letter)
+        .await
+        .map_err(PoolError::from)?;
+    Ok(())
+}
+
+pub async fn get_dead_letters(mongo_pool: &Pool) -> Result<Vec<DeadLetter>, PoolError> {
+    let client = mongo_pool.get().await?;
+    let collection: Collection<DeadLetter> =
+        client.database(DB_NAME).collection(DEAD_LETTERS_COLLECTION);
+    collection
+        .find(doc! {})
+        .sort(doc! { "dead_at": -1 })
+        .await
+        .map_err(PoolError::from)?
+        .try_collect()
+        .await
+        .map_err(PoolError::from)
+}
+
+pub async fn take_dead_letter(
+    mongo_pool: &Pool,
+    id: Uuid,
+) -> Result<Option<DeadLetter>, PoolError> {
+    let client = mongo_pool.get().await?;
+    let collection: Collection<DeadLetter> =
+        client.database(DB_NAME).collection(DEAD_LETTERS_COLLECTION);
+    let uuid_bytes = id.as_bytes();
+    let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    collection
+        .find_one_and_delete(filter)
+        .await
+        .map_err(PoolError::from)
+}
+
+pub async fn get_progress_log(
+    mongo_pool: &Pool,
+    task_id: Uuid,
+) -> Result<Option<ProgressLog>, PoolError> {
+    let client = mongo_pool.get().await?;
+    let collection: Collection<ProgressLog> = client
+        .database(DB_NAME)
+        .collection(PROGRESS_LOGS_COLLECTION);
+    let uuid_bytes = task_id.as_bytes();
+    let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    collection.find_one(filter).await.map_err(PoolError::from)
+}
+
+pub async fn save_progress_log(mongo_pool: &Pool, log: ProgressLog) -> Result<(), PoolError> {
+    let client = mongo_pool.get().await?;
+    let collection: Collection<ProgressLog> = client
+        .database(DB_NAME)
+        .collection(PROGRESS_LOGS_COLLECTION);
+    let uuid_bytes = log.task_id.as_bytes();
+    let filter = doc! { "_id": Binary { subtype: mongodb::bson::spec::BinarySubtype::Generic, bytes: uuid_bytes.to_vec() } };
+    collection
+        .replace_one(filter, &log)
+        .upsert(true)
+        .await
+        .map_err(PoolError::from)?;
     Ok(())
 }
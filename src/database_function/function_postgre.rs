@@ -1,8 +1,17 @@
+use crate::structure::analysis_structures::AnalysisKind;
 use chrono::{DateTime, Utc};
-use deadpool_postgres::tokio_postgres::Row;
-use deadpool_postgres::{Pool, PoolError};
+use deadpool_postgres::tokio_postgres::{types::ToSql, Row};
+use deadpool_postgres::{Pool, PoolError, Transaction};
+use std::collections::HashMap;
 use uuid::Uuid;
 
+/// Resets a task for a new run, clearing what its previous run left behind.
+const START_RUN: &str = "UPDATE tasks 
+             SET status = $2, deadline_at = $3, failure_reason = NULL, 
+                 finished_at = NULL, progress_archived = FALSE, webhooks_notified = FALSE, 
+                 email_notified = FALSE, progress_start = $4, run_kinds = $5 
+             WHERE id = $1";
+
 #[derive(Debug)]
 pub struct User {
     pub id: String,
@@ -150,23 +159,408 @@ impl UserSession {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+impl TaskStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Completed => "completed",
+            Self::Cancelled => "cancelled",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "running" => Some(Self::Running),
+            "completed" => Some(Self::Completed),
+            "cancelled" => Some(Self::Cancelled),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+
+    /// Whether results posted for the task are stored; a cancelled task keeps none of the
+    /// results its workers finish late.
+    pub fn accepts_results(&self) -> bool {
+        *self != Self::Cancelled
+    }
+}
+
+/// Where the current run of a task starts in its progress feed.
+#[derive(Debug, Clone)]
+pub struct TaskProgress {
+    pub status: TaskStatus,
+    /// Progress sequence after which the run's events follow.
+    pub progress_start: u64,
+    /// Analyses of the run; rerunning one analysis runs only that one.
+    pub kinds: Vec<AnalysisKind>,
+}
+
+/// Where a new run's progress begins and which analyses it runs.
+pub struct RunStart<'a> {
+    pub progress_start: u64,
+    pub kinds: &'a [AnalysisKind],
+}
+
+impl RunStart<'_> {
+    fn params(&self) -> (i64, Vec<&'static str>) {
+        (
+            i64::try_from(self.progress_start).unwrap_or(i64::MAX),
+            self.kinds.iter().map(AnalysisKind::as_str).collect(),
+        )
+    }
+}
+
+/// What came of claiming a new run of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunClaim {
+    Started,
+    /// The task is in a status the run may not start from.
+    Refused(TaskStatus),
+    /// The task is gone or belongs to another user.
+    NotFound,
+}
+
+/// Subscription tier; decides how early a user's analysis jobs are picked up by workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionPlan {
+    Trial,
+    #[default]
+    Standard,
+    Premium,
+}
+
+impl SubscriptionPlan {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Trial => "trial",
+            Self::Standard => "standard",
+            Self::Premium => "premium",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "trial" => Some(Self::Trial),
+            "standard" => Some(Self::Standard),
+            "premium" => Some(Self::Premium),
+            _ => None,
+        }
+    }
+
+    pub fn priority(&self) -> u8 {
+        match self {
+            Self::Trial => 0,
+            Self::Standard => 3,
+            Self::Premium => 6,
+        }
+    }
+
+    /// How many of the user's tasks may run on a schedule.
+    pub fn max_schedules(&self) -> i64 {
+        match self {
+            Self::Trial => 1,
+            Self::Standard => 5,
+            Self::Premium => 25,
+        }
+    }
+}
+
+pub async fn check_and_create_schema(pool: &Pool) -> Result<(), PoolError> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "ALTER TABLE tasks ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'running';
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS deadline_at TIMESTAMPTZ;
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS failure_reason TEXT;
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS finished_at TIMESTAMPTZ;
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS progress_archived BOOLEAN NOT NULL DEFAULT FALSE;
+             ALTER TABLE subscribe_users ADD COLUMN IF NOT EXISTS plan TEXT NOT NULL DEFAULT 'standard';
+             CREATE TABLE IF NOT EXISTS outbox (
+                 id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+                 task_id UUID NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+                 kind TEXT NOT NULL,
+                 payload BYTEA NOT NULL,
+                 attempts INTEGER NOT NULL DEFAULT 0,
+                 last_error TEXT,
+                 created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                 next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now()
+             );
+             CREATE INDEX IF NOT EXISTS outbox_next_attempt_at_idx ON outbox (next_attempt_at);
+             ALTER TABLE outbox ADD COLUMN IF NOT EXISTS priority SMALLINT NOT NULL DEFAULT 0;
+             ALTER TABLE outbox ADD COLUMN IF NOT EXISTS content_type TEXT NOT NULL DEFAULT 'application/json';
+             ALTER TABLE outbox ADD COLUMN IF NOT EXISTS failed_at TIMESTAMPTZ;
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS webhooks_notified BOOLEAN NOT NULL DEFAULT FALSE;
+             CREATE TABLE IF NOT EXISTS webhooks (
+                 id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+                 user_id TEXT NOT NULL,
+                 task_id UUID REFERENCES tasks(id) ON DELETE CASCADE,
+                 url TEXT NOT NULL,
+                 secret TEXT NOT NULL,
+                 created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+             );
+             CREATE INDEX IF NOT EXISTS webhooks_user_id_idx ON webhooks (user_id);
+             CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                 id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+                 webhook_id UUID NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+                 task_id UUID,
+                 event TEXT NOT NULL,
+                 payload TEXT NOT NULL,
+                 status TEXT NOT NULL DEFAULT 'pending',
+                 attempts INTEGER NOT NULL DEFAULT 0,
+                 response_status INTEGER,
+                 last_error TEXT,
+                 created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                 next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                 delivered_at TIMESTAMPTZ
+             );
+             CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id_idx ON webhook_deliveries (webhook_id, created_at);
+             CREATE INDEX IF NOT EXISTS webhook_deliveries_pending_idx ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS email_notified BOOLEAN NOT NULL DEFAULT FALSE;
+             ALTER TABLE subscribe_users ADD COLUMN IF NOT EXISTS reminded_for TIMESTAMPTZ;
+             CREATE TABLE IF NOT EXISTS notification_preferences (
+                 user_id TEXT PRIMARY KEY,
+                 task_finished BOOLEAN NOT NULL DEFAULT TRUE,
+                 subscription_expiring BOOLEAN NOT NULL DEFAULT TRUE,
+                 updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+             );
+             CREATE TABLE IF NOT EXISTS notifications (
+                 id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+                 user_id TEXT NOT NULL,
+                 kind TEXT NOT NULL,
+                 recipient TEXT NOT NULL,
+                 subject TEXT NOT NULL,
+                 body TEXT NOT NULL,
+                 attempts INTEGER NOT NULL DEFAULT 0,
+                 last_error TEXT,
+                 created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                 next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                 sent_at TIMESTAMPTZ
+             );
+             CREATE INDEX IF NOT EXISTS notifications_pending_idx ON notifications (next_attempt_at) WHERE sent_at IS NULL;
+             CREATE TABLE IF NOT EXISTS schedules (
+                 id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+                 user_id TEXT NOT NULL,
+                 task_id UUID NOT NULL UNIQUE REFERENCES tasks(id) ON DELETE CASCADE,
+                 cron TEXT,
+                 interval_secs BIGINT,
+                 paused BOOLEAN NOT NULL DEFAULT FALSE,
+                 next_run_at TIMESTAMPTZ NOT NULL,
+                 last_run_at TIMESTAMPTZ,
+                 last_error TEXT,
+                 created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+             );
+             CREATE INDEX IF NOT EXISTS schedules_user_id_idx ON schedules (user_id);
+             CREATE INDEX IF NOT EXISTS schedules_due_idx ON schedules (next_run_at) WHERE NOT paused;
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+             CREATE INDEX IF NOT EXISTS tasks_user_created_at_idx ON tasks (user_id, created_at, id);
+             CREATE INDEX IF NOT EXISTS tasks_tags_idx ON tasks USING GIN (tags);
+             CREATE INDEX IF NOT EXISTS tasks_name_search_idx ON tasks USING GIN (to_tsvector('simple', name));
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS progress_start BIGINT NOT NULL DEFAULT 0;
+             ALTER TABLE tasks ADD COLUMN IF NOT EXISTS run_kinds TEXT[] NOT NULL DEFAULT '{text,photo,reviews}';",
+        )
+        .await?;
+    Ok(())
+}
+
+/// A broker message written together with its task and published later by the relay.
+#[derive(Debug)]
+pub struct OutboxMessage {
+    pub id: Uuid,
+    pub task_id: Uuid,
+    pub kind: String,
+    pub content_type: String,
+    pub payload: Vec<u8>,
+    pub priority: i16,
+    pub attempts: i32,
+}
+
+impl OutboxMessage {
+    pub async fn enqueue(
+        transaction: &Transaction<'_>,
+        task_id: &Uuid,
+        kind: &str,
+        content_type: &str,
+        payload: &[u8],
+        priority: i16,
+    ) -> Result<(), PoolError> {
+        transaction
+            .execute(
+                "INSERT INTO outbox (task_id, kind, content_type, payload, priority) 
+             VALUES ($1, $2, $3, $4, $5)",
+                &[task_id, &kind, &content_type, &payload, &priority],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Claims due messages until `lease_until`, after which they are due again if the relay
+    /// neither published nor rescheduled them. Messages of cancelled tasks are dropped instead.
+    pub async fn claim(
+        pool: &Pool,
+        limit: i64,
+        lease_until: DateTime<Utc>,
+    ) -> Result<Vec<OutboxMessage>, PoolError> {
+        let client = pool.get().await?;
+        let mut rows = client
+            .query(
+                "WITH cancelled AS (
+                 DELETE FROM outbox 
+                 WHERE task_id IN (SELECT id FROM tasks WHERE status = $3)
+             )
+             UPDATE outbox 
+             SET next_attempt_at = $2 
+             WHERE id IN (
+                 SELECT outbox.id FROM outbox 
+                 JOIN tasks ON tasks.id = outbox.task_id 
+                 WHERE outbox.next_attempt_at <= now() AND outbox.failed_at IS NULL 
+                     AND tasks.status <> $3 
+                 ORDER BY outbox.created_at 
+                 LIMIT $1 
+                 FOR UPDATE OF outbox SKIP LOCKED
+             ) 
+             RETURNING id, task_id, kind, content_type, payload, priority, attempts, created_at",
+                &[&limit, &lease_until, &TaskStatus::Cancelled.as_str()],
+            )
+            .await?;
+        rows.sort_by_key(|row| row.get::<_, DateTime<Utc>>("created_at"));
+        Ok(rows
+            .into_iter()
+            .map(|row| OutboxMessage {
+                id: row.get("id"),
+                task_id: row.get("task_id"),
+                kind: row.get("kind"),
+                content_type: row.get("content_type"),
+                payload: row.get("payload"),
+                priority: row.get("priority"),
+                attempts: row.get("attempts"),
+            })
+            .collect())
+    }
+
+    /// Keeps a message that can never be published out of the relay, for inspection.
+    pub async fn fail(pool: &Pool, id: &Uuid, error: &str) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "UPDATE outbox SET failed_at = now(), last_error = $2 WHERE id = $1",
+                &[id, &error],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(pool: &Pool, id: &Uuid) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute("DELETE FROM outbox WHERE id = $1", &[id])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn reschedule(
+        pool: &Pool,
+        id: &Uuid,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "UPDATE outbox 
+             SET attempts = attempts + 1, next_attempt_at = $2, last_error = $3 
+             WHERE id = $1",
+                &[id, &next_attempt_at, &error],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Task {
     pub id: Uuid,
     pub name: String,
     pub _user_id: String,
     pub created_at: DateTime<Utc>,
+    pub status: String,
+    pub tags: Vec<String>,
+}
+
+/// Column the task history is ordered by; `id` breaks ties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HistorySort {
+    #[default]
+    CreatedAt,
+    Name,
+}
+
+impl HistorySort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CreatedAt => "createdAt",
+            Self::Name => "name",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "createdAt" => Some(Self::CreatedAt),
+            "name" => Some(Self::Name),
+            _ => None,
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            Self::CreatedAt => "created_at",
+            Self::Name => "name",
+        }
+    }
+}
+
+/// The sort value and id of the last task on the previous page.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryCursor {
+    CreatedAt(DateTime<Utc>, Uuid),
+    Name(String, Uuid),
+}
+
+#[derive(Debug, Default)]
+pub struct HistoryFilter {
+    pub created_from: Option<DateTime<Utc>>,
+    pub created_to: Option<DateTime<Utc>>,
+    pub statuses: Vec<TaskStatus>,
+    pub tag: Option<String>,
+    /// A `to_tsquery` expression matched against task names.
+    pub search: Option<String>,
+    pub sort: HistorySort,
+    pub descending: bool,
+    pub after: Option<HistoryCursor>,
 }
 
 impl Task {
-    pub async fn create(name: &str, user_id: &str, pool: &Pool) -> Result<Uuid, PoolError> {
-        let client = pool.get().await?;
-        let row = client
+    pub async fn create(
+        transaction: &Transaction<'_>,
+        name: &str,
+        user_id: &str,
+        deadline_at: DateTime<Utc>,
+    ) -> Result<Uuid, PoolError> {
+        let row = transaction
             .query_one(
-                "INSERT INTO tasks (id, name, user_id, created_at) 
-             VALUES (uuid_generate_v7(), $1, $2, $3)
+                "INSERT INTO tasks (id, name, user_id, created_at, deadline_at) 
+             VALUES (uuid_generate_v7(), $1, $2, $3, $4)
              RETURNING id",
-                &[&name, &user_id, &Utc::now()],
+                &[&name, &user_id, &Utc::now(), &deadline_at],
             )
             .await?;
 
@@ -186,15 +580,78 @@ impl Task {
         Ok(())
     }
 
-    pub async fn find_by_user_id(pool: &Pool, id: &str) -> Result<Vec<Self>, PoolError> {
+    /// One page of the user's tasks matching `filter`, at most `limit` long.
+    pub async fn find_page(
+        pool: &Pool,
+        user_id: &str,
+        filter: &HistoryFilter,
+        limit: i64,
+    ) -> Result<Vec<Self>, PoolError> {
+        let statuses: Vec<&str> = filter.statuses.iter().map(TaskStatus::as_str).collect();
+        let mut conditions = vec!["user_id = $1".to_string()];
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&user_id];
+        if let Some(from) = &filter.created_from {
+            params.push(from);
+            conditions.push(format!("created_at >= ${}", params.len()));
+        }
+        if let Some(to) = &filter.created_to {
+            params.push(to);
+            conditions.push(format!("created_at < ${}", params.len()));
+        }
+        if !statuses.is_empty() {
+            params.push(&statuses);
+            conditions.push(format!("status = ANY(${})", params.len()));
+        }
+        if let Some(tag) = &filter.tag {
+            params.push(tag);
+            conditions.push(format!("tags @> ARRAY[${}::TEXT]", params.len()));
+        }
+        if let Some(search) = &filter.search {
+            params.push(search);
+            conditions.push(format!(
+                "to_tsvector('simple', name) @@ to_tsquery('simple', ${})",
+                params.len()
+            ));
+        }
+        let column = filter.sort.column();
+        let (direction, comparison) = if filter.descending {
+            ("DESC", "<")
+        } else {
+            ("ASC", ">")
+        };
+        match &filter.after {
+            Some(HistoryCursor::CreatedAt(created_at, id)) => {
+                params.push(created_at);
+                params.push(id);
+            }
+            Some(HistoryCursor::Name(name, id)) => {
+                params.push(name);
+                params.push(id);
+            }
+            None => {}
+        }
+        if filter.after.is_some() {
+            conditions.push(format!(
+                "({column}, id) {comparison} (${}, ${})",
+                params.len() - 1,
+                params.len()
+            ));
+        }
+        params.push(&limit);
+
         let client = pool.get().await?;
         let rows = client
             .query(
-                "SELECT id, name, user_id, created_at 
+                &format!(
+                    "SELECT id, name, user_id, created_at, status, tags 
              FROM tasks 
-             WHERE user_id = $1 
-             ORDER BY created_at DESC",
-                &[&id],
+             WHERE {} 
+             ORDER BY {column} {direction}, id {direction} 
+             LIMIT ${}",
+                    conditions.join(" AND "),
+                    params.len()
+                ),
+                &params,
             )
             .await?;
 
@@ -205,11 +662,29 @@ impl Task {
                 name: row.get("name"),
                 _user_id: row.get("user_id"),
                 created_at: row.get("created_at"),
+                status: row.get("status"),
+                tags: row.get("tags"),
             })
             .collect();
 
         Ok(results)
     }
+
+    pub async fn update_tags(
+        pool: &Pool,
+        id: &Uuid,
+        user_id: &str,
+        tags: &[String],
+    ) -> Result<bool, PoolError> {
+        let client = pool.get().await?;
+        let updated = client
+            .execute(
+                "UPDATE tasks SET tags = $3 WHERE id = $1 AND user_id = $2",
+                &[&id, &user_id, &tags],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
     pub async fn update_time(pool: &Pool, id: &Uuid) -> Result<(), PoolError> {
         let client = pool.get().await?;
         client
@@ -223,88 +698,1118 @@ impl Task {
         Ok(())
     }
 
-    pub async fn update_name(pool: &Pool, id: Uuid, new_name: &str) -> Result<(), PoolError> {
+    pub async fn find_status(
+        pool: &Pool,
+        id: &Uuid,
+        user_id: &str,
+    ) -> Result<Option<TaskStatus>, PoolError> {
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt(
+                "SELECT status FROM tasks WHERE id = $1 AND user_id = $2",
+                &[&id, &user_id],
+            )
+            .await?
+            .and_then(|row| TaskStatus::parse(row.get("status"))))
+    }
+
+    /// Statuses of the user's tasks among `ids`; tasks that don't exist are left out.
+    pub async fn find_statuses(
+        pool: &Pool,
+        ids: &[Uuid],
+        user_id: &str,
+    ) -> Result<HashMap<Uuid, TaskStatus>, PoolError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT id, status FROM tasks WHERE id = ANY($1) AND user_id = $2",
+                &[&ids, &user_id],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| Some((row.get("id"), TaskStatus::parse(row.get("status"))?)))
+            .collect())
+    }
+
+    pub async fn find_owner(pool: &Pool, id: &Uuid) -> Result<Option<String>, PoolError> {
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt("SELECT user_id FROM tasks WHERE id = $1", &[&id])
+            .await?
+            .map(|row| row.get("user_id")))
+    }
+
+    pub async fn find_progress(
+        pool: &Pool,
+        id: &Uuid,
+        user_id: &str,
+    ) -> Result<Option<TaskProgress>, PoolError> {
+        let client = pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT status, progress_start, run_kinds FROM tasks WHERE id = $1 AND user_id = $2",
+                &[&id, &user_id],
+            )
+            .await?;
+        Ok(row.and_then(|row| {
+            Some(TaskProgress {
+                status: TaskStatus::parse(row.get("status"))?,
+                progress_start: u64::try_from(row.get::<_, i64>("progress_start")).unwrap_or(0),
+                kinds: row
+                    .get::<_, Vec<String>>("run_kinds")
+                    .iter()
+                    .filter_map(|kind| AnalysisKind::parse(kind))
+                    .collect(),
+            })
+        }))
+    }
+
+    /// Starts a run of the user's task unless it is already running.
+    pub async fn start_next_run(
+        transaction: &Transaction<'_>,
+        id: &Uuid,
+        user_id: &str,
+        deadline_at: DateTime<Utc>,
+        run: &RunStart<'_>,
+    ) -> Result<RunClaim, PoolError> {
+        Self::claim_run(
+            transaction,
+            id,
+            user_id,
+            deadline_at,
+            run,
+            &[TaskStatus::Running],
+        )
+        .await
+    }
+
+    /// Starts the run of a requeued job, unless the task has been cancelled or is running again.
+    pub async fn restart_failed_run(
+        transaction: &Transaction<'_>,
+        id: &Uuid,
+        user_id: &str,
+        deadline_at: DateTime<Utc>,
+        run: &RunStart<'_>,
+    ) -> Result<RunClaim, PoolError> {
+        Self::claim_run(
+            transaction,
+            id,
+            user_id,
+            deadline_at,
+            run,
+            &[TaskStatus::Running, TaskStatus::Cancelled],
+        )
+        .await
+    }
+
+    async fn claim_run(
+        transaction: &Transaction<'_>,
+        id: &Uuid,
+        user_id: &str,
+        deadline_at: DateTime<Utc>,
+        run: &RunStart<'_>,
+        refused: &[TaskStatus],
+    ) -> Result<RunClaim, PoolError> {
+        let (progress_start, kinds) = run.params();
+        let refused: Vec<&str> = refused.iter().map(TaskStatus::as_str).collect();
+        let started = transaction
+            .execute(
+                &format!("{} AND user_id = $6 AND status <> ALL($7)", START_RUN),
+                &[
+                    &id,
+                    &TaskStatus::Running.as_str(),
+                    &deadline_at,
+                    &progress_start,
+                    &kinds,
+                    &user_id,
+                    &refused,
+                ],
+            )
+            .await?;
+        if started > 0 {
+            return Ok(RunClaim::Started);
+        }
+        let status = transaction
+            .query_opt(
+                "SELECT status FROM tasks WHERE id = $1 AND user_id = $2",
+                &[&id, &user_id],
+            )
+            .await?
+            .and_then(|row| TaskStatus::parse(row.get("status")));
+        Ok(status.map_or(RunClaim::NotFound, RunClaim::Refused))
+    }
+
+    pub async fn complete(pool: &Pool, id: &Uuid) -> Result<(), PoolError> {
         let client = pool.get().await?;
         client
             .execute(
                 "UPDATE tasks 
-             SET name = $2 
-             WHERE id = $1",
-                &[&id, &new_name],
+             SET status = $2, finished_at = now() 
+             WHERE id = $1 AND status = $3",
+                &[
+                    &id,
+                    &TaskStatus::Completed.as_str(),
+                    &TaskStatus::Running.as_str(),
+                ],
             )
             .await?;
         Ok(())
     }
-}
 
-pub struct SubscribeUser {
-    pub _created_at: DateTime<Utc>,
-    pub valid_to: DateTime<Utc>,
-}
+    pub async fn fail(pool: &Pool, id: &Uuid, reason: &str) -> Result<bool, PoolError> {
+        let client = pool.get().await?;
+        let updated = client
+            .execute(
+                "UPDATE tasks 
+             SET status = $2, failure_reason = $3, finished_at = now() 
+             WHERE id = $1 AND status = $4",
+                &[
+                    &id,
+                    &TaskStatus::Failed.as_str(),
+                    &reason,
+                    &TaskStatus::Running.as_str(),
+                ],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
 
-impl SubscribeUser {
-    pub async fn get_by_user_id(pool: &Pool, user_id: &str) -> Result<Option<Self>, PoolError> {
+    pub async fn fail_expired(pool: &Pool, reason: &str) -> Result<Vec<Uuid>, PoolError> {
         let client = pool.get().await?;
-        client
-            .query_opt(
-                "SELECT created_at, valid_to
-	        FROM public.subscribe_users WHERE user_id = $1;",
-                &[&user_id],
+        let rows = client
+            .query(
+                "UPDATE tasks 
+             SET status = $1, failure_reason = $2, finished_at = now() 
+             WHERE status = $3 AND deadline_at < $4 
+             RETURNING id",
+                &[
+                    &TaskStatus::Failed.as_str(),
+                    &reason,
+                    &TaskStatus::Running.as_str(),
+                    &Utc::now(),
+                ],
             )
-            .await?
-            .map(Self::from_row)
-            .transpose()
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get("id")).collect())
     }
 
-    pub async fn create(
-        user_id: &str,
-        created_at: DateTime<Utc>,
-        valid_to: DateTime<Utc>,
+    pub async fn cancel(pool: &Pool, id: &Uuid, user_id: &str) -> Result<bool, PoolError> {
+        let client = pool.get().await?;
+        let updated = client
+            .execute(
+                "UPDATE tasks 
+             SET status = $3, finished_at = now() 
+             WHERE id = $1 AND user_id = $2 AND status = $4",
+                &[
+                    &id,
+                    &user_id,
+                    &TaskStatus::Cancelled.as_str(),
+                    &TaskStatus::Running.as_str(),
+                ],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    /// Finished tasks whose progress has not been archived yet, oldest first, with the
+    /// progress sequence their run starts after.
+    pub async fn unarchived_finished(
+        pool: &Pool,
+        finished_before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<(Uuid, u64)>, PoolError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT id, progress_start FROM tasks 
+             WHERE status <> $1 AND NOT progress_archived 
+               AND (finished_at IS NULL OR finished_at < $2) 
+             ORDER BY finished_at NULLS FIRST 
+             LIMIT $3",
+                &[&TaskStatus::Running.as_str(), &finished_before, &limit],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let progress_start: i64 = row.get("progress_start");
+                (row.get("id"), u64::try_from(progress_start).unwrap_or(0))
+            })
+            .collect())
+    }
+
+    /// Marks the run that started after `progress_start` archived, unless another one started.
+    pub async fn mark_progress_archived(
         pool: &Pool,
+        id: &Uuid,
+        progress_start: i64,
     ) -> Result<(), PoolError> {
         let client = pool.get().await?;
         client
             .execute(
-                "INSERT INTO public.subscribe_users (user_id, created_at, valid_to) 
-             VALUES ($1, $2, $3)",
-                &[&user_id, &created_at, &valid_to],
+                "UPDATE tasks 
+             SET progress_archived = TRUE 
+             WHERE id = $1 AND status <> $2 AND progress_start = $3",
+                &[&id, &TaskStatus::Running.as_str(), &progress_start],
             )
             .await?;
         Ok(())
     }
-    fn from_row(row: Row) -> Result<Self, PoolError> {
-        Ok(SubscribeUser {
-            _created_at: row.get("created_at"),
-            valid_to: row.get("valid_to"),
-        })
+
+    /// Claims up to `limit` finished tasks not yet handed to the channel behind `flag`.
+    pub async fn take_finished(
+        transaction: &Transaction<'_>,
+        flag: NotifiedFlag,
+        limit: i64,
+    ) -> Result<Vec<FinishedTask>, PoolError> {
+        let column = flag.column();
+        let rows = transaction
+            .query(
+                &format!(
+                    "UPDATE tasks 
+             SET {column} = TRUE 
+             WHERE id IN (
+                 SELECT id FROM tasks 
+                 WHERE status <> $1 AND NOT {column} AND finished_at IS NOT NULL 
+                 ORDER BY finished_at 
+                 LIMIT $2 
+                 FOR UPDATE SKIP LOCKED
+             ) 
+             RETURNING id, user_id, name, status, failure_reason, finished_at"
+                ),
+                &[&TaskStatus::Running.as_str(), &limit],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(FinishedTask {
+                    status: TaskStatus::parse(row.get("status"))?,
+                    id: row.get("id"),
+                    user_id: row.get("user_id"),
+                    name: row.get("name"),
+                    failure_reason: row.get("failure_reason"),
+                    finished_at: row.get("finished_at"),
+                })
+            })
+            .collect())
+    }
+
+    pub async fn update_name(pool: &Pool, id: Uuid, new_name: &str) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "UPDATE tasks 
+             SET name = $2 
+             WHERE id = $1",
+                &[&id, &new_name],
+            )
+            .await?;
+        Ok(())
     }
 }
 
-pub struct FullUser {
-    pub id: String,
-    pub email: String,
-    pub name: String,
-    pub is_admin: bool,
-    pub created_at: Option<DateTime<Utc>>,
-    pub valid_to: Option<DateTime<Utc>>,
+/// Marks a finished task as handed to one notification channel, reset when the task reruns.
+#[derive(Debug, Clone, Copy)]
+pub enum NotifiedFlag {
+    Webhooks,
+    Email,
 }
 
-impl FullUser {
-    pub async fn find_all(pool: &Pool) -> Result<Vec<Self>, PoolError> {
-        let client = pool.get().await?;
-        let rows = client
-            .query("SELECT u.id, u.email, u.name, u.is_admin, s.created_at, s.valid_to FROM public.users u LEFT JOIN public.subscribe_users s ON u.id = s.user_id;", &[])
-            .await?;
-        let results: Vec<Self> = rows
-            .into_iter()
-            .map(Self::from_row)
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(results)
+impl NotifiedFlag {
+    fn column(&self) -> &'static str {
+        match self {
+            Self::Webhooks => "webhooks_notified",
+            Self::Email => "email_notified",
+        }
     }
+}
 
-    fn from_row(row: Row) -> Result<Self, PoolError> {
-        Ok(FullUser {
-            id: row.get("id"),
+#[derive(Debug)]
+pub struct FinishedTask {
+    pub id: Uuid,
+    pub user_id: String,
+    pub name: String,
+    pub status: TaskStatus,
+    pub failure_reason: Option<String>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// A URL told about the user's finished tasks, or only about `task_id` when it is set.
+#[derive(Debug)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub _user_id: String,
+    pub task_id: Option<Uuid>,
+    pub url: String,
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    pub async fn create(
+        pool: &Pool,
+        user_id: &str,
+        task_id: Option<&Uuid>,
+        url: &str,
+        secret: &str,
+    ) -> Result<Self, PoolError> {
+        let client = pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO webhooks (user_id, task_id, url, secret) 
+             VALUES ($1, $2, $3, $4) 
+             RETURNING id, user_id, task_id, url, secret, created_at",
+                &[&user_id, &task_id, &url, &secret],
+            )
+            .await?;
+        Ok(Self::from_row(row))
+    }
+
+    pub async fn find_by_user_id(pool: &Pool, user_id: &str) -> Result<Vec<Self>, PoolError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT id, user_id, task_id, url, secret, created_at FROM webhooks 
+             WHERE user_id = $1 
+             ORDER BY created_at",
+                &[&user_id],
+            )
+            .await?;
+        Ok(rows.into_iter().map(Self::from_row).collect())
+    }
+
+    pub async fn find_by_id(
+        pool: &Pool,
+        id: &Uuid,
+        user_id: &str,
+    ) -> Result<Option<Self>, PoolError> {
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt(
+                "SELECT id, user_id, task_id, url, secret, created_at FROM webhooks 
+             WHERE id = $1 AND user_id = $2",
+                &[&id, &user_id],
+            )
+            .await?
+            .map(Self::from_row))
+    }
+
+    /// Webhooks of the user that want to hear about `task_id`.
+    pub async fn find_for_task(
+        transaction: &Transaction<'_>,
+        user_id: &str,
+        task_id: &Uuid,
+    ) -> Result<Vec<Self>, PoolError> {
+        let rows = transaction
+            .query(
+                "SELECT id, user_id, task_id, url, secret, created_at FROM webhooks 
+             WHERE user_id = $1 AND (task_id IS NULL OR task_id = $2)",
+                &[&user_id, &task_id],
+            )
+            .await?;
+        Ok(rows.into_iter().map(Self::from_row).collect())
+    }
+
+    pub async fn delete(pool: &Pool, id: &Uuid, user_id: &str) -> Result<bool, PoolError> {
+        let client = pool.get().await?;
+        let deleted = client
+            .execute(
+                "DELETE FROM webhooks WHERE id = $1 AND user_id = $2",
+                &[&id, &user_id],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    fn from_row(row: Row) -> Self {
+        Webhook {
+            id: row.get("id"),
+            _user_id: row.get("user_id"),
+            task_id: row.get("task_id"),
+            url: row.get("url"),
+            secret: row.get("secret"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(Self::Pending),
+            "delivered" => Some(Self::Delivered),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// One payload sent to a webhook, retried while `pending`; doubles as the delivery log.
+#[derive(Debug)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub task_id: Option<Uuid>,
+    pub event: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+/// A pending delivery with everything needed to send it.
+#[derive(Debug)]
+pub struct DueDelivery {
+    pub id: Uuid,
+    pub url: String,
+    pub secret: String,
+    pub payload: String,
+    pub attempts: i32,
+}
+
+impl WebhookDelivery {
+    pub async fn enqueue(
+        transaction: &Transaction<'_>,
+        webhook_id: &Uuid,
+        task_id: &Uuid,
+        event: &str,
+        payload: &str,
+    ) -> Result<(), PoolError> {
+        transaction
+            .execute(
+                "INSERT INTO webhook_deliveries (webhook_id, task_id, event, payload) 
+             VALUES ($1, $2, $3, $4)",
+                &[webhook_id, task_id, &event, &payload],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Logs a delivery that was attempted once, right away, such as a test delivery.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn log_attempt(
+        pool: &Pool,
+        id: &Uuid,
+        webhook_id: &Uuid,
+        event: &str,
+        payload: &str,
+        status: DeliveryStatus,
+        response_status: Option<i32>,
+        error: Option<&str>,
+    ) -> Result<Self, PoolError> {
+        let client = pool.get().await?;
+        let row = client
+            .query_one(
+"INSERT INTO webhook_deliveries 
+                 (id, webhook_id, event, payload, status, attempts, response_status, last_error, delivered_at) 
+             VALUES ($1, $2, $3, $4, $5, 1, $6, $7, CASE WHEN $5 = 'delivered' THEN now() END) 
+             RETURNING id, webhook_id, task_id, event, status, attempts, response_status, 
+                 last_error, created_at, delivered_at",
+                &[
+                    id,
+                    webhook_id,
+                    &event,
+                    &payload,
+                    &status.as_str(),
+                    &response_status,
+                    &error,
+                ],
+            )
+            .await?;
+        Ok(Self::from_row(row))
+    }
+
+    /// Claims pending deliveries that are due until `lease_until`, after which they are due
+    /// again if their attempt was never recorded.
+    pub async fn claim_due(
+        pool: &Pool,
+        limit: i64,
+        lease_until: DateTime<Utc>,
+    ) -> Result<Vec<DueDelivery>, PoolError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "UPDATE webhook_deliveries d 
+             SET next_attempt_at = $3 
+             FROM webhooks w 
+             WHERE w.id = d.webhook_id AND d.id IN (
+                 SELECT id FROM webhook_deliveries 
+                 WHERE status = $1 AND next_attempt_at <= now() 
+                 ORDER BY next_attempt_at 
+                 LIMIT $2 
+                 FOR UPDATE SKIP LOCKED
+             ) 
+             RETURNING d.id, w.url, w.secret, d.payload, d.attempts",
+                &[&DeliveryStatus::Pending.as_str(), &limit, &lease_until],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DueDelivery {
+                id: row.get("id"),
+                url: row.get("url"),
+                secret: row.get("secret"),
+                payload: row.get("payload"),
+                attempts: row.get("attempts"),
+            })
+            .collect())
+    }
+
+    /// Records the outcome of an attempt; `next_attempt_at` only matters while still pending.
+    pub async fn record_attempt(
+        pool: &Pool,
+        id: &Uuid,
+        status: DeliveryStatus,
+        response_status: Option<i32>,
+        error: Option<&str>,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "UPDATE webhook_deliveries 
+             SET status = $2, attempts = attempts + 1, response_status = $3, last_error = $4, 
+                 next_attempt_at = $5, delivered_at = CASE WHEN $2 = 'delivered' THEN now() END 
+             WHERE id = $1",
+                &[
+                    id,
+                    &status.as_str(),
+                    &response_status,
+                    &error,
+                    &next_attempt_at,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn find_by_webhook(
+        pool: &Pool,
+        webhook_id: &Uuid,
+        limit: i64,
+    ) -> Result<Vec<Self>, PoolError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT id, webhook_id, task_id, event, status, attempts, response_status, 
+                 last_error, created_at, delivered_at 
+             FROM webhook_deliveries 
+             WHERE webhook_id = $1 
+             ORDER BY created_at DESC 
+             LIMIT $2",
+                &[webhook_id, &limit],
+            )
+            .await?;
+        Ok(rows.into_iter().map(Self::from_row).collect())
+    }
+
+    fn from_row(row: Row) -> Self {
+        WebhookDelivery {
+            id: row.get("id"),
+            webhook_id: row.get("webhook_id"),
+            task_id: row.get("task_id"),
+            event: row.get("event"),
+            status: DeliveryStatus::parse(row.get("status")).unwrap_or(DeliveryStatus::Failed),
+            attempts: row.get("attempts"),
+            response_status: row.get("response_status"),
+            last_error: row.get("last_error"),
+            created_at: row.get("created_at"),
+            delivered_at: row.get("delivered_at"),
+        }
+    }
+}
+
+/// A recurring run of a task, either on a cron expression or every `interval_secs`.
+#[derive(Debug)]
+pub struct Schedule {
+    pub id: Uuid,
+    pub user_id: String,
+    pub task_id: Uuid,
+    pub cron: Option<String>,
+    pub interval_secs: Option<i64>,
+    pub paused: bool,
+    pub next_run_at: DateTime<Utc>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+const SCHEDULE_COLUMNS: &str = "id, user_id, task_id, cron, interval_secs, paused, next_run_at, 
+             last_run_at, last_error, created_at";
+
+impl Schedule {
+    /// Returns `None` when the user already has `limit` schedules or the task has one.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(
+        pool: &Pool,
+        user_id: &str,
+        task_id: &Uuid,
+        cron: Option<&str>,
+        interval_secs: Option<i64>,
+        next_run_at: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Option<Self>, PoolError> {
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt(
+                &format!(
+                    "INSERT INTO schedules (user_id, task_id, cron, interval_secs, next_run_at) 
+             SELECT $1, $2, $3, $4, $5 
+             WHERE (SELECT count(*) FROM schedules WHERE user_id = $1) < $6 
+             ON CONFLICT (task_id) DO NOTHING 
+             RETURNING {SCHEDULE_COLUMNS}"
+                ),
+                &[
+                    &user_id,
+                    task_id,
+                    &cron,
+                    &interval_secs,
+                    &next_run_at,
+                    &limit,
+                ],
+            )
+            .await?
+            .map(Self::from_row))
+    }
+
+    pub async fn find_by_user_id(pool: &Pool, user_id: &str) -> Result<Vec<Self>, PoolError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {SCHEDULE_COLUMNS} FROM schedules 
+             WHERE user_id = $1 
+             ORDER BY created_at"
+                ),
+                &[&user_id],
+            )
+            .await?;
+        Ok(rows.into_iter().map(Self::from_row).collect())
+    }
+
+    pub async fn find_by_id(
+        pool: &Pool,
+        id: &Uuid,
+        user_id: &str,
+    ) -> Result<Option<Self>, PoolError> {
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt(
+                &format!("SELECT {SCHEDULE_COLUMNS} FROM schedules WHERE id = $1 AND user_id = $2"),
+                &[&id, &user_id],
+            )
+            .await?
+            .map(Self::from_row))
+    }
+
+    pub async fn find_by_task(
+        pool: &Pool,
+        task_id: &Uuid,
+        user_id: &str,
+    ) -> Result<Option<Self>, PoolError> {
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt(
+                &format!(
+                    "SELECT {SCHEDULE_COLUMNS} FROM schedules WHERE task_id = $1 AND user_id = $2"
+                ),
+                &[&task_id, &user_id],
+            )
+            .await?
+            .map(Self::from_row))
+    }
+
+    /// Pauses or resumes the schedule; resuming moves its next run to `next_run_at`.
+    pub async fn set_paused(
+        pool: &Pool,
+        id: &Uuid,
+        user_id: &str,
+        paused: bool,
+        next_run_at: DateTime<Utc>,
+    ) -> Result<Option<Self>, PoolError> {
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt(
+                &format!(
+                    "UPDATE schedules 
+             SET paused = $3, next_run_at = CASE WHEN $3 THEN next_run_at ELSE $4 END 
+             WHERE id = $1 AND user_id = $2 
+             RETURNING {SCHEDULE_COLUMNS}"
+                ),
+                &[&id, &user_id, &paused, &next_run_at],
+            )
+            .await?
+            .map(Self::from_row))
+    }
+
+    pub async fn delete(pool: &Pool, id: &Uuid, user_id: &str) -> Result<bool, PoolError> {
+        let client = pool.get().await?;
+        let deleted = client
+            .execute(
+                "DELETE FROM schedules WHERE id = $1 AND user_id = $2",
+                &[&id, &user_id],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Locks up to `limit` active schedules whose next run is due.
+    pub async fn lock_due(
+        transaction: &Transaction<'_>,
+        limit: i64,
+    ) -> Result<Vec<Self>, PoolError> {
+        let rows = transaction
+            .query(
+                &format!(
+                    "SELECT {SCHEDULE_COLUMNS} FROM schedules 
+             WHERE NOT paused AND next_run_at <= $1 
+             ORDER BY next_run_at 
+             LIMIT $2 
+             FOR UPDATE SKIP LOCKED"
+                ),
+                &[&Utc::now(), &limit],
+            )
+            .await?;
+        Ok(rows.into_iter().map(Self::from_row).collect())
+    }
+
+    /// Records that the schedule fired and moves it to `next_run_at`, pausing it when there is
+    /// no next run.
+    pub async fn advance(
+        transaction: &Transaction<'_>,
+        id: &Uuid,
+        next_run_at: Option<DateTime<Utc>>,
+    ) -> Result<(), PoolError> {
+        transaction
+            .execute(
+                "UPDATE schedules 
+             SET last_run_at = now(), next_run_at = COALESCE($2, next_run_at), 
+                 paused = $2 IS NULL 
+             WHERE id = $1",
+                &[id, &next_run_at],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Stores why the last run was skipped or failed, or clears it when it started.
+    pub async fn record_run(pool: &Pool, id: &Uuid, error: Option<&str>) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "UPDATE schedules SET last_error = $2 WHERE id = $1",
+                &[id, &error],
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn from_row(row: Row) -> Self {
+        Schedule {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            task_id: row.get("task_id"),
+            cron: row.get("cron"),
+            interval_secs: row.get("interval_secs"),
+            paused: row.get("paused"),
+            next_run_at: row.get("next_run_at"),
+            last_run_at: row.get("last_run_at"),
+            last_error: row.get("last_error"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// Which emails a user wants; everything is on until they change it.
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationPreferences {
+    pub task_finished: bool,
+    pub subscription_expiring: bool,
+}
+
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        NotificationPreferences {
+            task_finished: true,
+            subscription_expiring: true,
+        }
+    }
+}
+
+impl NotificationPreferences {
+    pub async fn find(pool: &Pool, user_id: &str) -> Result<Self, PoolError> {
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt(
+                "SELECT task_finished, subscription_expiring FROM notification_preferences 
+             WHERE user_id = $1",
+                &[&user_id],
+            )
+            .await?
+            .map(Self::from_row)
+            .unwrap_or_default())
+    }
+
+    pub async fn save(&self, pool: &Pool, user_id: &str) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "INSERT INTO notification_preferences (user_id, task_finished, subscription_expiring) 
+             VALUES ($1, $2, $3) 
+             ON CONFLICT (user_id) DO UPDATE 
+             SET task_finished = $2, subscription_expiring = $3, updated_at = now()",
+                &[&user_id, &self.task_finished, &self.subscription_expiring],
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn from_row(row: Row) -> Self {
+        NotificationPreferences {
+            task_finished: row.get("task_finished"),
+            subscription_expiring: row.get("subscription_expiring"),
+        }
+    }
+}
+
+/// The user an email goes to, with their preferences.
+#[derive(Debug)]
+pub struct Recipient {
+    pub email: String,
+    pub name: String,
+    pub preferences: NotificationPreferences,
+}
+
+impl Recipient {
+    pub async fn find(
+        transaction: &Transaction<'_>,
+        user_id: &str,
+    ) -> Result<Option<Self>, PoolError> {
+        Ok(transaction
+            .query_opt(
+                "SELECT u.email, u.name, 
+                 COALESCE(p.task_finished, TRUE) AS task_finished, 
+                 COALESCE(p.subscription_expiring, TRUE) AS subscription_expiring 
+             FROM users u LEFT JOIN notification_preferences p ON p.user_id = u.id 
+             WHERE u.id = $1",
+                &[&user_id],
+            )
+            .await?
+            .map(|row| Recipient {
+                email: row.get("email"),
+                name: row.get("name"),
+                preferences: NotificationPreferences::from_row(row),
+            }))
+    }
+}
+
+/// An email waiting in the notification outbox.
+#[derive(Debug)]
+pub struct Notification {
+    pub id: Uuid,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub attempts: i32,
+}
+
+impl Notification {
+    pub async fn enqueue(
+        transaction: &Transaction<'_>,
+        user_id: &str,
+        kind: &str,
+        recipient: &str,
+        subject: &str,
+        body: &str,
+    ) -> Result<(), PoolError> {
+        transaction
+            .execute(
+                "INSERT INTO notifications (user_id, kind, recipient, subject, body) 
+             VALUES ($1, $2, $3, $4, $5)",
+                &[&user_id, &kind, &recipient, &subject, &body],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Claims unsent notifications that are due until `lease_until`, after which they are due
+    /// again if they were neither sent nor rescheduled.
+    pub async fn claim_due(
+        pool: &Pool,
+        max_attempts: i32,
+        limit: i64,
+        lease_until: DateTime<Utc>,
+    ) -> Result<Vec<Notification>, PoolError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "UPDATE notifications 
+             SET next_attempt_at = $3 
+             WHERE id IN (
+                 SELECT id FROM notifications 
+                 WHERE sent_at IS NULL AND attempts < $1 AND next_attempt_at <= now() 
+                 ORDER BY next_attempt_at 
+                 LIMIT $2 
+                 FOR UPDATE SKIP LOCKED
+             ) 
+             RETURNING id, recipient, subject, body, attempts",
+                &[&max_attempts, &limit, &lease_until],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Notification {
+                id: row.get("id"),
+                recipient: row.get("recipient"),
+                subject: row.get("subject"),
+                body: row.get("body"),
+                attempts: row.get("attempts"),
+            })
+            .collect())
+    }
+
+    pub async fn mark_sent(pool: &Pool, id: &Uuid) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "UPDATE notifications SET sent_at = now(), attempts = attempts + 1 WHERE id = $1",
+                &[id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn reschedule(
+        pool: &Pool,
+        id: &Uuid,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "UPDATE notifications 
+             SET attempts = attempts + 1, next_attempt_at = $2, last_error = $3 
+             WHERE id = $1",
+                &[id, &next_attempt_at, &error],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct SubscribeUser {
+    pub _created_at: DateTime<Utc>,
+    pub valid_to: DateTime<Utc>,
+    pub plan: SubscriptionPlan,
+}
+
+impl SubscribeUser {
+    pub async fn get_by_user_id(pool: &Pool, user_id: &str) -> Result<Option<Self>, PoolError> {
+        let client = pool.get().await?;
+        client
+            .query_opt(
+                "SELECT created_at, valid_to, plan
+	        FROM public.subscribe_users WHERE user_id = $1;",
+                &[&user_id],
+            )
+            .await?
+            .map(Self::from_row)
+            .transpose()
+    }
+
+    pub async fn create(
+        user_id: &str,
+        created_at: DateTime<Utc>,
+        valid_to: DateTime<Utc>,
+        plan: SubscriptionPlan,
+        pool: &Pool,
+    ) -> Result<(), PoolError> {
+        let client = pool.get().await?;
+        client
+            .execute(
+                "INSERT INTO public.subscribe_users (user_id, created_at, valid_to, plan) 
+             VALUES ($1, $2, $3, $4)",
+                &[&user_id, &created_at, &valid_to, &plan.as_str()],
+            )
+            .await?;
+        Ok(())
+    }
+    /// Claims subscriptions ending before `ends_before` that have not been reminded about since
+    /// their `valid_to` was last set.
+    pub async fn take_expiring(
+        transaction: &Transaction<'_>,
+        ends_before: DateTime<Utc>,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, PoolError> {
+        let rows = transaction
+            .query(
+                "UPDATE subscribe_users 
+             SET reminded_for = valid_to 
+             WHERE valid_to > $1 AND valid_to <= $2 AND reminded_for IS DISTINCT FROM valid_to 
+             RETURNING user_id, valid_to",
+                &[&Utc::now(), &ends_before],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("user_id"), row.get("valid_to")))
+            .collect())
+    }
+
+    fn from_row(row: Row) -> Result<Self, PoolError> {
+        Ok(SubscribeUser {
+            _created_at: row.get("created_at"),
+            valid_to: row.get("valid_to"),
+            plan: SubscriptionPlan::parse(row.get("plan")).unwrap_or_default(),
+        })
+    }
+}
+
+pub struct FullUser {
+    pub id: String,
+    pub email: String,
+    pub name: String,
+    pub is_admin: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub valid_to: Option<DateTime<Utc>>,
+}
+
+impl FullUser {
+    pub async fn find_all(pool: &Pool) -> Result<Vec<Self>, PoolError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query("SELECT u.id, u.email, u.name, u.is_admin, s.created_at, s.valid_to FROM public.users u LEFT JOIN public.subscribe_users s ON u.id = s.user_id;", &[])
+            .await?;
+        let results: Vec<Self> = rows
+            .into_iter()
+            .map(Self::from_row)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(results)
+    }
+
+    fn from_row(row: Row) -> Result<Self, PoolError> {
+        Ok(FullUser {
+            id: row.get("id"),
             email: row.get("email"),
             name: row.get("name"),
             is_admin: row.get("is_admin"),
@@ -313,3 +1818,33 @@ impl FullUser {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_cancelled_tasks_refuse_results() {
+        for (status, accepts) in [
+            (TaskStatus::Running, true),
+            (TaskStatus::Completed, true),
+            (TaskStatus::Failed, true),
+            (TaskStatus::Cancelled, false),
+        ] {
+            assert_eq!(status.accepts_results(), accepts, "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn task_status_round_trips() {
+        for status in [
+            TaskStatus::Running,
+            TaskStatus::Completed,
+            TaskStatus::Cancelled,
+            TaskStatus::Failed,
+        ] {
+            assert_eq!(TaskStatus::parse(status.as_str()), Some(status));
+        }
+        assert_eq!(TaskStatus::parse("paused"), None);
+    }
+}
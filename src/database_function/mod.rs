@@ -2,19 +2,35 @@ pub mod connection_mongo;
 mod connection_postgresql;
 pub mod function_mongo;
 pub mod function_postgre;
+use crate::broker::{photo_analysis_job, reviews_analysis_job, text_analysis_job, ProgressMessage};
+use crate::contracts::{AnalysisJob, ContentType, Envelope};
 use crate::database_function::connection_mongo::PoolError;
 use crate::database_function::function_postgre::FullUser;
+use crate::mail::{subscription_expiring_mail, task_ready_mail, Mail};
+use crate::schedule::{schedule_allowance, Cadence};
 use crate::structure::{
-    receive_structures::{MainProduct, Product},
+    analysis_structures::{AnalysisKind, PhotoAnalysis, ReviewAnalysis, TextAnalysis},
+    receive_structures::{HistoryQuery, MainProduct, Product, Review},
     send_structures::{
-        History, HistoryElement, Product as SendProduct, SendAccount, SendSession, Task,
+        DeadLetterElement, History, HistoryElement, Product as SendProduct, ScheduleElement,
+        SendAccount, SendSession, Task, TaskRunElement, TaskRuns, WebhookDeliveryElement,
+        WebhookElement, WebhookPayload,
     },
 };
+use crate::utils::{analysis_deadline, env_or};
+use crate::webhooks::{generate_secret, TASK_FINISHED_EVENT};
+use chrono::Utc;
 use connection_mongo::{Pool as MongoPool, PoolError as MongoPoolError};
 use deadpool_postgres::{Pool as PostgresPool, PoolError as PostgresPoolError};
 use function_mongo::{update_photo_analysis, update_review_analysis, update_text_analysis};
-use function_postgre::{SubscribeUser, User, UserSession};
+use function_postgre::{
+    DeliveryStatus, DueDelivery, HistoryCursor, HistoryFilter, HistorySort, Notification,
+    NotificationPreferences, NotifiedFlag, OutboxMessage, Recipient, RunClaim, RunStart, Schedule,
+    SubscribeUser, SubscriptionPlan, TaskProgress, TaskStatus, User, UserSession, Webhook,
+    WebhookDelivery,
+};
 use rocket::{Build, Rocket};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Debug)]
@@ -33,6 +49,23 @@ pub enum MixPostgresAndCustomError {
     Custom(String),
 }
 
+pub struct AnalysisInputs {
+    pub text: Vec<String>,
+    pub photo: Vec<String>,
+    pub reviews: Vec<Vec<Review>>,
+}
+
+fn to_mongo_reviews(reviews: &[Review]) -> Vec<function_mongo::Review> {
+    reviews
+        .iter()
+        .map(|r| function_mongo::Review {
+            text: r.text.clone(),
+            pros: r.pros.clone(),
+            cons: r.cons.clone(),
+        })
+        .collect()
+}
+
 pub async fn init_postgre_pools(rocket: Rocket<Build>) -> Rocket<Build> {
     connection_postgresql::init_db_pool(rocket).await
 }
@@ -45,7 +78,7 @@ pub async fn check_client_session_id(
     pool: &PostgresPool,
     id: &Uuid,
 ) -> Result<bool, PostgresPoolError> {
-    UserSession::check_by_id(pool, &id).await
+    UserSession::check_by_id(pool, id).await
 }
 
 pub async fn create_client_session(
@@ -55,7 +88,7 @@ pub async fn create_client_session(
     os: &str,
     device: &str,
 ) -> Result<Uuid, PostgresPoolError> {
-    Ok(UserSession::create(id_user, browser, device, os, pool).await?)
+    UserSession::create(id_user, browser, device, os, pool).await
 }
 
 pub async fn delete_client_session(pool: &PostgresPool, id: Uuid) -> Result<(), PostgresPoolError> {
@@ -67,19 +100,19 @@ pub async fn update_check_session_time(
     pool: &PostgresPool,
     id: &Uuid,
 ) -> Result<bool, PostgresPoolError> {
-    UserSession::update_time(pool, &id).await
+    UserSession::update_time(pool, id).await
 }
 pub async fn update_user_session_id(
     pool: &PostgresPool,
     id: &Uuid,
 ) -> Result<Uuid, PostgresPoolError> {
-    UserSession::update_user_session_id(pool, &id).await
+    UserSession::update_user_session_id(pool, id).await
 }
 pub async fn get_user_session_by_id(
     pool: &PostgresPool,
     id: &Uuid,
 ) -> Result<UserSession, MixPostgresAndCustomError> {
-    let option = UserSession::find_by_id(pool, &id)
+    let option = UserSession::find_by_id(pool, id)
         .await
         .map_err(MixPostgresAndCustomError::Postgres)?;
     let session = option.ok_or(MixPostgresAndCustomError::Custom(
@@ -88,15 +121,20 @@ pub async fn get_user_session_by_id(
     Ok(session)
 }
 
+/// Creates the task and queues its analysis jobs in the outbox within one Postgres transaction.
+/// The Mongo document is written before the commit, so a failed Mongo write rolls the task back
+/// and a failed commit removes the Mongo document again.
+#[allow(clippy::too_many_arguments)]
 pub async fn create_task(
     post_pool: &PostgresPool,
     mongo_pool: &MongoPool,
     user_id: &str,
     name: &str,
     main_product: &MainProduct,
-    competitors: &Vec<Product>,
+    competitors: &[Product],
     used_words: Vec<&str>,
     unused_words: Vec<&str>,
+    jobs: impl FnOnce(&Uuid) -> Vec<Envelope<AnalysisJob>>,
 ) -> Result<Uuid, MixPoolError> {
     let competitors = competitors
         .iter()
@@ -107,12 +145,40 @@ pub async fn create_task(
             root: p.root,
             price: p.price,
             review: p.review_rating,
+            image_url: p.image_url.clone(),
+            reviews: to_mongo_reviews(&p.reviews),
         })
         .collect::<Vec<_>>();
 
-    let uuid = function_postgre::Task::create(name, user_id, post_pool)
+    let priority = job_priority(post_pool, user_id)
+        .await
+        .map_err(MixPoolError::Postgres)?;
+    let mut client = post_pool.get().await.map_err(MixPoolError::Postgres)?;
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|e| MixPoolError::Postgres(e.into()))?;
+    let uuid = function_postgre::Task::create(
+        &transaction,
+        name,
+        user_id,
+        Utc::now() + analysis_deadline(),
+    )
+    .await
+    .map_err(MixPoolError::Postgres)?;
+    let content_type = ContentType::configured();
+    for job in jobs(&uuid) {
+        OutboxMessage::enqueue(
+            &transaction,
+            &uuid,
+            job.body.kind().as_str(),
+            content_type.as_str(),
+            &job.encode(content_type),
+            priority as i16,
+        )
         .await
         .map_err(MixPoolError::Postgres)?;
+    }
 
     function_mongo::create_task(
         mongo_pool,
@@ -125,6 +191,8 @@ pub async fn create_task(
             root: main_product.root,
             price: 0,
             review: 0.0,
+            image_url: main_product.image_url.clone(),
+            reviews: to_mongo_reviews(&main_product.reviews),
         },
         competitors,
         used_words.into_iter().map(|s| s.to_string()).collect(),
@@ -133,16 +201,59 @@ pub async fn create_task(
     .await
     .map_err(MixPoolError::Mongo)?;
 
+    if let Err(e) = transaction.commit().await {
+        if let Err(mongo_error) = function_mongo::delete_task(mongo_pool, user_id, &uuid).await {
+            log::error!(
+                "Failed to remove mongo task {} after aborted commit: {}",
+                uuid,
+                mongo_error
+            );
+        }
+        return Err(MixPoolError::Postgres(e.into()));
+    }
+
     Ok(uuid)
 }
 
+pub async fn claim_outbox_messages(
+    postgre_pool: &PostgresPool,
+    limit: i64,
+    lease: chrono::Duration,
+) -> Result<Vec<OutboxMessage>, PostgresPoolError> {
+    OutboxMessage::claim(postgre_pool, limit, Utc::now() + lease).await
+}
+
+pub async fn fail_outbox_message(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+    error: &str,
+) -> Result<(), PostgresPoolError> {
+    OutboxMessage::fail(postgre_pool, id, error).await
+}
+
+pub async fn mark_outbox_published(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+) -> Result<(), PostgresPoolError> {
+    OutboxMessage::delete(postgre_pool, id).await
+}
+
+pub async fn reschedule_outbox_message(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+    next_attempt_at: chrono::DateTime<Utc>,
+    error: &str,
+) -> Result<(), PostgresPoolError> {
+    OutboxMessage::reschedule(postgre_pool, id, next_attempt_at, error).await
+}
+
 pub async fn delete_task(
     post_pool: &PostgresPool,
     mongo_pool: &MongoPool,
     user_id: &str,
     id: &Uuid,
 ) -> Result<(), MixPoolError> {
-    let uuid = function_postgre::Task::delete_by_id(post_pool, id)
+    function_postgre::Task::delete_by_id(post_pool, id)
         .await
         .map_err(MixPoolError::Postgres)?;
 
@@ -150,46 +261,63 @@ pub async fn delete_task(
         .await
         .map_err(MixPoolError::Mongo)?;
 
-    Ok(uuid)
+    Ok(())
 }
 
+/// Stores a result of the task; `false` when it belongs to an earlier run than the current one.
 pub async fn set_text_analysis(
     mongo_pool: &MongoPool,
     user_id: &str,
     id: Uuid,
-    data: &str,
-) -> Result<(), PoolError> {
-    update_text_analysis(mongo_pool, id, &user_id, data).await
+    run: Option<u32>,
+    data: &TextAnalysis,
+) -> Result<bool, PoolError> {
+    update_text_analysis(mongo_pool, id, user_id, run, data).await
 }
 
 pub async fn set_photo_analysis(
     mongo_pool: &MongoPool,
     user_id: &str,
     id: Uuid,
-    data: &str,
-) -> Result<(), PoolError> {
-    update_photo_analysis(mongo_pool, id, user_id, data).await
+    run: Option<u32>,
+    data: &PhotoAnalysis,
+) -> Result<bool, PoolError> {
+    update_photo_analysis(mongo_pool, id, user_id, run, data).await
 }
 
 pub async fn set_review_analysis(
     mongo_pool: &MongoPool,
     user_id: &str,
     id: Uuid,
-    data: &str,
-) -> Result<(), PoolError> {
-    update_review_analysis(mongo_pool, id, user_id, data).await
+    run: Option<u32>,
+    data: &ReviewAnalysis,
+) -> Result<bool, PoolError> {
+    update_review_analysis(mongo_pool, id, user_id, run, data).await
+}
+
+/// What came of asking to run a task again with new inputs.
+pub enum Regenerate {
+    /// The run with this number started.
+    Started(u32),
+    NotFound,
+    /// The task's current run has not finished.
+    Running,
 }
 
+/// Starts the next run of the user's task with new inputs. The run is claimed in Postgres first,
+/// so only one can start; if recording it in MongoDB then fails, the run is failed.
+#[allow(clippy::too_many_arguments)]
 pub async fn regenerate_task(
     post_pool: &PostgresPool,
     mongo_pool: &MongoPool,
     id: &Uuid,
     user_id: &str,
     main_product: &MainProduct,
-    competitors: &Vec<Product>,
+    competitors: &[Product],
     used_words: Vec<&str>,
     unused_words: Vec<&str>,
-) -> Result<(), MixPoolError> {
+    progress_start: u64,
+) -> Result<Regenerate, MixPoolError> {
     let competitors = competitors
         .iter()
         .map(|p| function_mongo::Product {
@@ -199,73 +327,254 @@ pub async fn regenerate_task(
             root: p.root,
             price: p.price,
             review: p.review_rating,
+            image_url: p.image_url.clone(),
+            reviews: to_mongo_reviews(&p.reviews),
         })
         .collect::<Vec<_>>();
 
-    function_postgre::Task::update_time(post_pool, id)
+    let previous = function_mongo::get_task(mongo_pool, user_id, *id)
         .await
-        .map_err(MixPoolError::Postgres)?;
+        .map_err(MixPoolError::Mongo)?;
 
-    function_mongo::update_task(
-        mongo_pool,
+    let mut client = post_pool.get().await.map_err(MixPoolError::Postgres)?;
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|e| MixPoolError::Postgres(e.into()))?;
+    let run = RunStart {
+        progress_start,
+        kinds: &AnalysisKind::ALL,
+    };
+    match function_postgre::Task::start_next_run(
+        &transaction,
+        id,
         user_id,
-        id.clone(),
-        function_mongo::Product {
-            description: main_product.description.clone(),
-            id: main_product.id,
-            name: main_product.name.clone(),
-            root: main_product.root,
-            price: 0,
-            review: 0.0,
-        },
-        competitors,
-        used_words.into_iter().map(|s| s.to_string()).collect(),
-        unused_words.into_iter().map(|s| s.to_string()).collect(),
+        Utc::now() + analysis_deadline(),
+        &run,
     )
     .await
-    .map_err(MixPoolError::Mongo)?;
+    .map_err(MixPoolError::Postgres)?
+    {
+        RunClaim::Started => {}
+        RunClaim::Refused(_) => return Ok(Regenerate::Running),
+        RunClaim::NotFound => return Ok(Regenerate::NotFound),
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(|e| MixPoolError::Postgres(e.into()))?;
+    function_postgre::Task::update_time(post_pool, id)
+        .await
+        .map_err(MixPoolError::Postgres)?;
 
-    Ok(())
+    let previous_run = previous.as_ref().map_or(0, |task| task.run);
+    let recorded = async {
+        if let Some(task) = &previous {
+            function_mongo::archive_task_run(mongo_pool, user_id, task).await?;
+        }
+        function_mongo::update_task(
+            mongo_pool,
+            user_id,
+            *id,
+            previous_run,
+            function_mongo::Product {
+                description: main_product.description.clone(),
+                id: main_product.id,
+                name: main_product.name.clone(),
+                root: main_product.root,
+                price: 0,
+                review: 0.0,
+                image_url: main_product.image_url.clone(),
+                reviews: to_mongo_reviews(&main_product.reviews),
+            },
+            competitors,
+            used_words.into_iter().map(|s| s.to_string()).collect(),
+            unused_words.into_iter().map(|s| s.to_string()).collect(),
+        )
+        .await
+    }
+    .await;
+    if let Err(e) = recorded {
+        if let Err(e) = function_postgre::Task::fail(post_pool, id, RUN_NOT_RECORDED).await {
+            log::error!("Failed to fail unrecorded run of task {}: {}", id, e);
+        }
+        return Err(MixPoolError::Mongo(e));
+    }
+    Ok(Regenerate::Started(previous_run + 1))
+}
+
+fn parse_history_date(value: &str) -> Option<chrono::DateTime<Utc>> {
+    if let Ok(at) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(at.with_timezone(&Utc));
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+}
+
+/// A prefix `to_tsquery` matching names containing words that start with each word of `text`.
+fn history_search(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| format!("{}:*", term))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" & "))
+}
+
+/// Cursors are the hex of `<sort>|<value>|<id>`, so clients treat them as opaque.
+fn encode_history_cursor(sort: HistorySort, task: &function_postgre::Task) -> String {
+    let value = match sort {
+        HistorySort::CreatedAt => task.created_at.to_rfc3339(),
+        HistorySort::Name => task.name.clone(),
+    };
+    format!("{}|{}|{}", sort.as_str(), value, task.id)
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn decode_history_cursor(sort: HistorySort, cursor: &str) -> Option<HistoryCursor> {
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let text = String::from_utf8(bytes).ok()?;
+    let (cursor_sort, rest) = text.split_once('|')?;
+    let (value, id) = rest.rsplit_once('|')?;
+    let id = Uuid::parse_str(id).ok()?;
+    match HistorySort::parse(cursor_sort)? {
+        HistorySort::CreatedAt if sort == HistorySort::CreatedAt => {
+            let created_at = chrono::DateTime::parse_from_rfc3339(value).ok()?;
+            Some(HistoryCursor::CreatedAt(created_at.with_timezone(&Utc), id))
+        }
+        HistorySort::Name if sort == HistorySort::Name => {
+            Some(HistoryCursor::Name(value.to_string(), id))
+        }
+        _ => None,
+    }
+}
+
+fn history_filter(query: &HistoryQuery) -> Result<HistoryFilter, String> {
+    let sort = match query.sort.as_deref() {
+        Some(sort) => HistorySort::parse(sort).ok_or(format!("unknown sort {}", sort))?,
+        None => HistorySort::default(),
+    };
+    let descending = match query.order.as_deref() {
+        Some("asc") => false,
+        Some("desc") => true,
+        Some(order) => return Err(format!("unknown order {}", order)),
+        None => sort == HistorySort::CreatedAt,
+    };
+    let date = |value: &Option<String>, name: &str| {
+        value
+            .as_deref()
+            .map(|value| parse_history_date(value).ok_or(format!("invalid {} date", name)))
+            .transpose()
+    };
+    let statuses = query
+        .status
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .filter(|status| !status.is_empty())
+        .map(|status| TaskStatus::parse(status).ok_or(format!("unknown status {}", status)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let after = query
+        .cursor
+        .as_deref()
+        .map(|cursor| decode_history_cursor(sort, cursor).ok_or("invalid cursor".to_string()))
+        .transpose()?;
+    Ok(HistoryFilter {
+        created_from: date(&query.from, "from")?,
+        created_to: date(&query.to, "to")?,
+        statuses,
+        tag: query.tag.clone().filter(|tag| !tag.is_empty()),
+        search: query.q.as_deref().and_then(history_search),
+        sort,
+        descending,
+        after,
+    })
 }
 
+/// One page of the user's task history; `Custom` errors describe an invalid query.
 pub async fn get_all_tasks(
     postgre_pool: &PostgresPool,
     user_id: &str,
-) -> Result<History, PostgresPoolError> {
-    let tasks = function_postgre::Task::find_by_user_id(postgre_pool, user_id)
-        .await?
+    query: &HistoryQuery,
+) -> Result<History, MixPostgresAndCustomError> {
+    let filter = history_filter(query).map_err(MixPostgresAndCustomError::Custom)?;
+    let max: i64 = env_or("HISTORY_MAX_PAGE_SIZE", 200);
+    let limit = query
+        .limit
+        .unwrap_or_else(|| env_or("HISTORY_PAGE_SIZE", 50))
+        .clamp(1, max);
+    let mut tasks = function_postgre::Task::find_page(postgre_pool, user_id, &filter, limit + 1)
+        .await
+        .map_err(MixPostgresAndCustomError::Postgres)?;
+    let next_cursor = if tasks.len() as i64 > limit {
+        tasks.truncate(limit as usize);
+        tasks
+            .last()
+            .map(|task| encode_history_cursor(filter.sort, task))
+    } else {
+        None
+    };
+    let elements = tasks
         .into_iter()
         .map(|task| HistoryElement {
             id: task.id,
             name: task.name,
             created_at: task.created_at,
+            status: task.status,
+            tags: task.tags,
         })
         .collect();
-    Ok(History { elements: tasks })
+    Ok(History {
+        elements,
+        next_cursor,
+    })
 }
 
-pub async fn get_task_by_id(
-    mongo_pool: &MongoPool,
+/// Replaces the task's tags, trimmed and without duplicates. Returns `false` if it isn't the
+/// user's task.
+pub async fn set_task_tags(
+    postgre_pool: &PostgresPool,
     user_id: &str,
-    id: Uuid,
-) -> Result<Task, MixMongoAndCustomError> {
-    let option_task = function_mongo::get_task(mongo_pool, user_id, id)
-        .await
-        .map_err(MixMongoAndCustomError::Mongo)?;
-    let real_task =
-        option_task.ok_or(MixMongoAndCustomError::Custom("Task not found".to_string()))?;
-    let task = Task {
+    id: &Uuid,
+    tags: &[String],
+) -> Result<bool, PostgresPoolError> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    function_postgre::Task::update_tags(postgre_pool, id, user_id, &tags).await
+}
+
+fn to_send_task(
+    run: u32,
+    main_product: function_mongo::Product,
+    competitors: Vec<function_mongo::Product>,
+    words_analysis: function_mongo::WordsAnalysis,
+    text_analyses: Option<TextAnalysis>,
+    photo_analyses: Option<PhotoAnalysis>,
+    review_analyses: Option<ReviewAnalysis>,
+) -> Task {
+    Task {
+        run,
         main: SendProduct {
-            id: real_task.main_product.id,
-            root: real_task.main_product.root,
-            name: real_task.main_product.name,
+            id: main_product.id,
+            root: main_product.root,
+            name: main_product.name,
             brand: "".to_string(),
             price: 0.0,
             review_rating: 0.0,
-            description: real_task.main_product.description,
+            description: main_product.description,
         },
-        products: real_task
-            .competitors
+        products: competitors
             .into_iter()
             .map(|p| SendProduct {
                 id: p.id,
@@ -277,13 +586,1019 @@ pub async fn get_task_by_id(
                 description: p.description,
             })
             .collect(),
-        used_words: real_task.words_analysis.used_words,
-        unused_words: real_task.words_analysis.unused_words,
-        text_analyses: real_task.text_analysis,
-        photo_analyses: real_task.photo_analysis,
-        review_analyses: real_task.review_analysis,
+        used_words: words_analysis.used_words,
+        unused_words: words_analysis.unused_words,
+        text_analyses,
+        photo_analyses,
+        review_analyses,
+    }
+}
+
+pub async fn get_task_by_id(
+    mongo_pool: &MongoPool,
+    user_id: &str,
+    id: Uuid,
+) -> Result<Task, MixMongoAndCustomError> {
+    let option_task = function_mongo::get_task(mongo_pool, user_id, id)
+        .await
+        .map_err(MixMongoAndCustomError::Mongo)?;
+    let real_task =
+        option_task.ok_or(MixMongoAndCustomError::Custom("Task not found".to_string()))?;
+    Ok(to_send_task(
+        real_task.run,
+        real_task.main_product,
+        real_task.competitors,
+        real_task.words_analysis,
+        real_task.text_analysis,
+        real_task.photo_analysis,
+        real_task.review_analysis,
+    ))
+}
+
+/// Ids of the task's main product and competitors, the only products its results may cover.
+pub async fn get_task_product_ids(
+    mongo_pool: &MongoPool,
+    user_id: &str,
+    id: Uuid,
+) -> Result<Option<Vec<u64>>, MongoPoolError> {
+    let task = function_mongo::get_task(mongo_pool, user_id, id).await?;
+    Ok(task.map(|task| {
+        std::iter::once(&task.main_product)
+            .chain(&task.competitors)
+            .map(|product| product.id)
+            .collect()
+    }))
+}
+
+pub async fn get_task_runs(
+    mongo_pool: &MongoPool,
+    user_id: &str,
+    id: Uuid,
+) -> Result<TaskRuns, MixMongoAndCustomError> {
+    let current = function_mongo::get_task(mongo_pool, user_id, id)
+        .await
+        .map_err(MixMongoAndCustomError::Mongo)?
+        .ok_or(MixMongoAndCustomError::Custom("Task not found".to_string()))?;
+    let mut runs: Vec<TaskRunElement> = function_mongo::get_task_runs(mongo_pool, user_id, id)
+        .await
+        .map_err(MixMongoAndCustomError::Mongo)?
+        .into_iter()
+        .map(|run| TaskRunElement {
+            run: run.run,
+            created_at: run.created_at.to_chrono(),
+            current: false,
+        })
+        .collect();
+    runs.push(TaskRunElement {
+        run: current.run,
+        created_at: current.created_at.to_chrono(),
+        current: true,
+    });
+    Ok(TaskRuns { runs })
+}
+
+pub async fn get_task_run_by_number(
+    mongo_pool: &MongoPool,
+    user_id: &str,
+    id: Uuid,
+    run: u32,
+) -> Result<Task, MixMongoAndCustomError> {
+    let current = function_mongo::get_task(mongo_pool, user_id, id)
+        .await
+        .map_err(MixMongoAndCustomError::Mongo)?
+        .ok_or(MixMongoAndCustomError::Custom("Task not found".to_string()))?;
+    if current.run == run {
+        return Ok(to_send_task(
+            current.run,
+            current.main_product,
+            current.competitors,
+            current.words_analysis,
+            current.text_analysis,
+            current.photo_analysis,
+            current.review_analysis,
+        ));
+    }
+    let archived = function_mongo::get_task_run(mongo_pool, user_id, id, run)
+        .await
+        .map_err(MixMongoAndCustomError::Mongo)?
+        .ok_or(MixMongoAndCustomError::Custom("Run not found".to_string()))?;
+    Ok(to_send_task(
+        archived.run,
+        archived.main_product,
+        archived.competitors,
+        archived.words_analysis,
+        archived.text_analysis,
+        archived.photo_analysis,
+        archived.review_analysis,
+    ))
+}
+
+/// What came of asking to rerun one analysis of a task.
+pub enum Rerun {
+    /// The run with this number started; these inputs go to its workers.
+    Started {
+        inputs: AnalysisInputs,
+        run: u32,
+    },
+    NotFound,
+    /// The task's current run has not finished.
+    Running,
+    /// The task was stored without inputs for the analysis, e.g. before image URLs and reviews
+    /// were kept.
+    NoInputs,
+}
+
+impl AnalysisInputs {
+    fn has(&self, kind: AnalysisKind) -> bool {
+        match kind {
+            AnalysisKind::Text => self.text.iter().any(|text| !text.is_empty()),
+            AnalysisKind::Photo => self.photo.iter().any(|url| !url.is_empty()),
+            AnalysisKind::Reviews => self.reviews.iter().any(|reviews| !reviews.is_empty()),
+        }
+    }
+}
+
+/// Starts a run of one analysis of a finished task. The run is claimed in Postgres first, so
+/// only one can start; if recording it in MongoDB then fails, the run is failed.
+pub async fn rerun_analysis(
+    post_pool: &PostgresPool,
+    mongo_pool: &MongoPool,
+    user_id: &str,
+    id: &Uuid,
+    kind: AnalysisKind,
+    progress_start: u64,
+) -> Result<Rerun, MixPoolError> {
+    let task = match function_mongo::get_task(mongo_pool, user_id, *id)
+        .await
+        .map_err(MixPoolError::Mongo)?
+    {
+        Some(task) => task,
+        None => return Ok(Rerun::NotFound),
+    };
+    let inputs = analysis_inputs(&task);
+    if !inputs.has(kind) {
+        return Ok(Rerun::NoInputs);
+    }
+
+    let mut client = post_pool.get().await.map_err(MixPoolError::Postgres)?;
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|e| MixPoolError::Postgres(e.into()))?;
+    let run = RunStart {
+        progress_start,
+        kinds: &[kind],
+    };
+    match function_postgre::Task::start_next_run(
+        &transaction,
+        id,
+        user_id,
+        Utc::now() + analysis_deadline(),
+        &run,
+    )
+    .await
+    .map_err(MixPoolError::Postgres)?
+    {
+        RunClaim::Started => {}
+        RunClaim::Refused(_) => return Ok(Rerun::Running),
+        RunClaim::NotFound => return Ok(Rerun::NotFound),
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(|e| MixPoolError::Postgres(e.into()))?;
+
+    let recorded = async {
+        function_mongo::archive_task_run(mongo_pool, user_id, &task).await?;
+        function_mongo::start_partial_run(mongo_pool, user_id, *id, task.run, kind).await
+    }
+    .await;
+    if let Err(e) = recorded {
+        if let Err(e) = function_postgre::Task::fail(post_pool, id, RUN_NOT_RECORDED).await {
+            log::error!("Failed to fail unrecorded rerun of task {}: {}", id, e);
+        }
+        return Err(MixPoolError::Mongo(e));
+    }
+    Ok(Rerun::Started {
+        inputs,
+        run: task.run + 1,
+    })
+}
+
+const RUN_NOT_RECORDED: &str = "run could not be recorded";
+
+fn analysis_inputs(task: &function_mongo::ProductAnalysis) -> AnalysisInputs {
+    let products = std::iter::once(&task.main_product).chain(&task.competitors);
+    let mut inputs = AnalysisInputs {
+        text: vec![],
+        photo: vec![],
+        reviews: vec![],
     };
-    Ok(task)
+    for product in products {
+        inputs.text.push(product.description.clone());
+        inputs.photo.push(product.image_url.clone());
+        inputs.reviews.push(
+            product
+                .reviews
+                .iter()
+                .map(|r| Review {
+                    text: r.text.clone(),
+                    pros: r.pros.clone(),
+                    cons: r.cons.clone(),
+                })
+                .collect(),
+        );
+    }
+    inputs
+}
+
+pub async fn cancel_task(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<bool, PostgresPoolError> {
+    function_postgre::Task::cancel(postgre_pool, id, user_id).await
+}
+
+pub async fn complete_task_if_finished(
+    post_pool: &PostgresPool,
+    mongo_pool: &MongoPool,
+    user_id: &str,
+    id: Uuid,
+) -> Result<bool, MixPoolError> {
+    let finished = function_mongo::get_task(mongo_pool, user_id, id)
+        .await
+        .map_err(MixPoolError::Mongo)?
+        .map(|task| {
+            task.text_analysis.is_some()
+                && task.photo_analysis.is_some()
+                && task.review_analysis.is_some()
+        })
+        .unwrap_or(false);
+    if finished {
+        function_postgre::Task::complete(post_pool, &id)
+            .await
+            .map_err(MixPoolError::Postgres)?;
+    }
+    Ok(finished)
+}
+
+pub async fn fail_task(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+    reason: &str,
+) -> Result<bool, PostgresPoolError> {
+    function_postgre::Task::fail(postgre_pool, id, reason).await
+}
+
+pub async fn fail_expired_tasks(
+    postgre_pool: &PostgresPool,
+    reason: &str,
+) -> Result<Vec<Uuid>, PostgresPoolError> {
+    function_postgre::Task::fail_expired(postgre_pool, reason).await
+}
+
+/// Runs the task again for the analysis of a requeued job, unless it was cancelled or is running
+/// again since the job failed.
+pub async fn restart_task(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+    kind: AnalysisKind,
+    progress_start: u64,
+) -> Result<RunClaim, PostgresPoolError> {
+    let Some(user_id) = function_postgre::Task::find_owner(postgre_pool, id).await? else {
+        return Ok(RunClaim::NotFound);
+    };
+    let mut client = postgre_pool.get().await?;
+    let transaction = client.transaction().await?;
+    let run = RunStart {
+        progress_start,
+        kinds: &[kind],
+    };
+    let claim = function_postgre::Task::restart_failed_run(
+        &transaction,
+        id,
+        &user_id,
+        Utc::now() + analysis_deadline(),
+        &run,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(claim)
+}
+
+pub async fn park_dead_letter(
+    mongo_pool: &MongoPool,
+    dead_letter: function_mongo::DeadLetter,
+) -> Result<(), MongoPoolError> {
+    function_mongo::insert_dead_letter(mongo_pool, dead_letter).await
+}
+
+pub async fn get_dead_letters(
+    mongo_pool: &MongoPool,
+) -> Result<Vec<DeadLetterElement>, MongoPoolError> {
+    Ok(function_mongo::get_dead_letters(mongo_pool)
+        .await?
+        .into_iter()
+        .map(|letter| DeadLetterElement {
+            id: letter.id,
+            task_id: letter.task_id,
+            task_type: letter.task_type,
+            reason: letter.reason,
+            attempts: letter.attempts,
+            dead_at: letter.dead_at.to_chrono(),
+        })
+        .collect())
+}
+
+pub async fn take_dead_letter(
+    mongo_pool: &MongoPool,
+    id: Uuid,
+) -> Result<Option<function_mongo::DeadLetter>, MongoPoolError> {
+    function_mongo::take_dead_letter(mongo_pool, id).await
+}
+
+/// Finished tasks to archive, with the progress sequence their last run starts after.
+pub async fn finished_tasks_to_archive(
+    postgre_pool: &PostgresPool,
+    finished_before: chrono::DateTime<Utc>,
+    limit: i64,
+) -> Result<Vec<(Uuid, u64)>, PostgresPoolError> {
+    function_postgre::Task::unarchived_finished(postgre_pool, finished_before, limit).await
+}
+
+/// Archives the progress of the task's run that started after `run_start`. Events of the run
+/// already archived are kept; the archive of an earlier run is replaced.
+pub async fn archive_progress(
+    postgre_pool: &PostgresPool,
+    mongo_pool: &MongoPool,
+    id: &Uuid,
+    run_start: u64,
+    messages: Vec<ProgressMessage>,
+) -> Result<(), MixPoolError> {
+    let run_start = run_start as i64;
+    let archived = function_mongo::get_progress_log(mongo_pool, *id)
+        .await
+        .map_err(MixPoolError::Mongo)?;
+    let mut events = archived
+        .filter(|log| log.run_start == run_start)
+        .map(|log| log.events)
+        .unwrap_or_default();
+    let last_sequence = events.last().map_or(run_start, |event| event.sequence);
+    let new_events: Vec<function_mongo::ArchivedProgress> = messages
+        .into_iter()
+        .filter(|message| message.sequence as i64 > last_sequence)
+        .map(|message| {
+            let (event, error) = match message.event {
+                Ok(envelope) => (Some(envelope.body), None),
+                Err(e) => (None, Some(e)),
+            };
+            function_mongo::ArchivedProgress {
+                sequence: message.sequence as i64,
+                event,
+                error,
+            }
+        })
+        .collect();
+    if !new_events.is_empty() {
+        events.extend(new_events);
+        let log = function_mongo::ProgressLog {
+            task_id: *id,
+            run_start,
+            events,
+            archived_at: mongodb::bson::DateTime::now(),
+        };
+        function_mongo::save_progress_log(mongo_pool, log)
+            .await
+            .map_err(MixPoolError::Mongo)?;
+    }
+    function_postgre::Task::mark_progress_archived(postgre_pool, id, run_start)
+        .await
+        .map_err(MixPoolError::Postgres)
+}
+
+/// The archived progress of the task's run that started after `run_start`, if that run is
+/// the one archived.
+pub async fn get_archived_progress(
+    mongo_pool: &MongoPool,
+    id: Uuid,
+    run_start: u64,
+) -> Result<Option<Vec<ProgressMessage>>, MongoPoolError> {
+    Ok(function_mongo::get_progress_log(mongo_pool, id)
+        .await?
+        .filter(|log| log.run_start == run_start as i64)
+        .map(|log| {
+            log.events
+                .into_iter()
+                .map(|archived| ProgressMessage {
+                    sequence: archived.sequence as u64,
+                    event: match archived.event {
+                        Some(event) => Ok(Envelope::new(id, event)),
+                        None => Err(archived.error.unwrap_or_default()),
+                    },
+                })
+                .collect()
+        }))
+}
+
+fn to_webhook_element(webhook: Webhook, with_secret: bool) -> WebhookElement {
+    WebhookElement {
+        id: webhook.id,
+        task_id: webhook.task_id,
+        url: webhook.url,
+        secret: with_secret.then_some(webhook.secret),
+        created_at: webhook.created_at,
+    }
+}
+
+fn to_delivery_element(delivery: WebhookDelivery) -> WebhookDeliveryElement {
+    WebhookDeliveryElement {
+        id: delivery.id,
+        webhook_id: delivery.webhook_id,
+        task_id: delivery.task_id,
+        event: delivery.event,
+        status: delivery.status.as_str(),
+        attempts: delivery.attempts,
+        response_status: delivery.response_status,
+        last_error: delivery.last_error,
+        created_at: delivery.created_at,
+        delivered_at: delivery.delivered_at,
+    }
+}
+
+pub async fn create_webhook(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    task_id: Option<&Uuid>,
+    url: &str,
+) -> Result<WebhookElement, PostgresPoolError> {
+    let webhook = Webhook::create(postgre_pool, user_id, task_id, url, &generate_secret()).await?;
+    Ok(to_webhook_element(webhook, true))
+}
+
+pub async fn get_webhooks(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+) -> Result<Vec<WebhookElement>, PostgresPoolError> {
+    Ok(Webhook::find_by_user_id(postgre_pool, user_id)
+        .await?
+        .into_iter()
+        .map(|webhook| to_webhook_element(webhook, false))
+        .collect())
+}
+
+pub async fn get_webhook(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<Option<Webhook>, PostgresPoolError> {
+    Webhook::find_by_id(postgre_pool, id, user_id).await
+}
+
+pub async fn delete_webhook(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<bool, PostgresPoolError> {
+    Webhook::delete(postgre_pool, id, user_id).await
+}
+
+/// The latest deliveries of the user's webhook, or `None` if it isn't theirs.
+pub async fn get_webhook_deliveries(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+    limit: i64,
+) -> Result<Option<Vec<WebhookDeliveryElement>>, PostgresPoolError> {
+    if Webhook::find_by_id(postgre_pool, id, user_id)
+        .await?
+        .is_none()
+    {
+        return Ok(None);
+    }
+    Ok(Some(
+        WebhookDelivery::find_by_webhook(postgre_pool, id, limit)
+            .await?
+            .into_iter()
+            .map(to_delivery_element)
+            .collect(),
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn log_webhook_attempt(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+    webhook_id: &Uuid,
+    event: &str,
+    payload: &str,
+    status: DeliveryStatus,
+    response_status: Option<i32>,
+    error: Option<&str>,
+) -> Result<WebhookDeliveryElement, PostgresPoolError> {
+    WebhookDelivery::log_attempt(
+        postgre_pool,
+        id,
+        webhook_id,
+        event,
+        payload,
+        status,
+        response_status,
+        error,
+    )
+    .await
+    .map(to_delivery_element)
+}
+
+/// Queues a `task.finished` delivery to every matching webhook of newly finished tasks.
+pub async fn enqueue_finished_task_webhooks(
+    postgre_pool: &PostgresPool,
+    limit: i64,
+) -> Result<usize, PostgresPoolError> {
+    let mut client = postgre_pool.get().await?;
+    let transaction = client.transaction().await?;
+    let finished =
+        function_postgre::Task::take_finished(&transaction, NotifiedFlag::Webhooks, limit).await?;
+    let mut queued = 0;
+    for task in finished {
+        let webhooks = Webhook::find_for_task(&transaction, &task.user_id, &task.id).await?;
+        if webhooks.is_empty() {
+            continue;
+        }
+        let payload = WebhookPayload {
+            event: TASK_FINISHED_EVENT,
+            task_id: Some(task.id),
+            name: Some(task.name),
+            status: Some(task.status.as_str()),
+            failure_reason: task.failure_reason,
+            finished_at: Some(task.finished_at),
+        };
+        let payload = serde_json::to_string(&payload).expect("webhook payload is serializable");
+        for webhook in webhooks {
+            WebhookDelivery::enqueue(
+                &transaction,
+                &webhook.id,
+                &task.id,
+                TASK_FINISHED_EVENT,
+                &payload,
+            )
+            .await?;
+            queued += 1;
+        }
+    }
+    transaction.commit().await?;
+    Ok(queued)
+}
+
+pub async fn claim_webhook_deliveries(
+    postgre_pool: &PostgresPool,
+    limit: i64,
+    lease: chrono::Duration,
+) -> Result<Vec<DueDelivery>, PostgresPoolError> {
+    WebhookDelivery::claim_due(postgre_pool, limit, Utc::now() + lease).await
+}
+
+pub async fn record_webhook_attempt(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+    status: DeliveryStatus,
+    response_status: Option<i32>,
+    error: Option<&str>,
+    next_attempt_at: chrono::DateTime<Utc>,
+) -> Result<(), PostgresPoolError> {
+    WebhookDelivery::record_attempt(
+        postgre_pool,
+        id,
+        status,
+        response_status,
+        error,
+        next_attempt_at,
+    )
+    .await
+}
+
+pub async fn get_notification_preferences(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+) -> Result<NotificationPreferences, PostgresPoolError> {
+    NotificationPreferences::find(postgre_pool, user_id).await
+}
+
+pub async fn update_notification_preferences(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    preferences: &NotificationPreferences,
+) -> Result<(), PostgresPoolError> {
+    preferences.save(postgre_pool, user_id).await
+}
+
+async fn enqueue_mail(
+    transaction: &deadpool_postgres::Transaction<'_>,
+    user_id: &str,
+    kind: &str,
+    mail: Mail,
+) -> Result<(), PostgresPoolError> {
+    Notification::enqueue(
+        transaction,
+        user_id,
+        kind,
+        &mail.to,
+        &mail.subject,
+        &mail.body,
+    )
+    .await
+}
+
+/// Queues an "analysis is ready" email for newly completed tasks of users who want one.
+pub async fn enqueue_finished_task_emails(
+    postgre_pool: &PostgresPool,
+    limit: i64,
+) -> Result<usize, PostgresPoolError> {
+    let mut client = postgre_pool.get().await?;
+    let transaction = client.transaction().await?;
+    let finished =
+        function_postgre::Task::take_finished(&transaction, NotifiedFlag::Email, limit).await?;
+    let mut queued = 0;
+    for task in finished {
+        if task.status != TaskStatus::Completed {
+            continue;
+        }
+        let Some(recipient) = Recipient::find(&transaction, &task.user_id).await? else {
+            continue;
+        };
+        if !recipient.preferences.task_finished {
+            continue;
+        }
+        let mail = task_ready_mail(&recipient.email, &recipient.name, &task.name, &task.id);
+        enqueue_mail(&transaction, &task.user_id, "task_finished", mail).await?;
+        queued += 1;
+    }
+    transaction.commit().await?;
+    Ok(queued)
+}
+
+/// Queues a reminder for subscriptions ending within `within`, once per `valid_to`.
+pub async fn enqueue_subscription_reminders(
+    postgre_pool: &PostgresPool,
+    within: chrono::Duration,
+) -> Result<usize, PostgresPoolError> {
+    let mut client = postgre_pool.get().await?;
+    let transaction = client.transaction().await?;
+    let expiring = SubscribeUser::take_expiring(&transaction, Utc::now() + within).await?;
+    let mut queued = 0;
+    for (user_id, valid_to) in expiring {
+        let Some(recipient) = Recipient::find(&transaction, &user_id).await? else {
+            continue;
+        };
+        if !recipient.preferences.subscription_expiring {
+            continue;
+        }
+        let mail = subscription_expiring_mail(&recipient.email, &recipient.name, valid_to);
+        enqueue_mail(&transaction, &user_id, "subscription_expiring", mail).await?;
+        queued += 1;
+    }
+    transaction.commit().await?;
+    Ok(queued)
+}
+
+pub async fn claim_notifications(
+    postgre_pool: &PostgresPool,
+    max_attempts: i32,
+    limit: i64,
+    lease: chrono::Duration,
+) -> Result<Vec<Notification>, PostgresPoolError> {
+    Notification::claim_due(postgre_pool, max_attempts, limit, Utc::now() + lease).await
+}
+
+pub async fn mark_notification_sent(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+) -> Result<(), PostgresPoolError> {
+    Notification::mark_sent(postgre_pool, id).await
+}
+
+pub async fn reschedule_notification(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+    next_attempt_at: chrono::DateTime<Utc>,
+    error: &str,
+) -> Result<(), PostgresPoolError> {
+    Notification::reschedule(postgre_pool, id, next_attempt_at, error).await
+}
+
+fn to_schedule_element(schedule: Schedule) -> ScheduleElement {
+    ScheduleElement {
+        id: schedule.id,
+        task_id: schedule.task_id,
+        cron: schedule.cron,
+        interval_secs: schedule.interval_secs,
+        paused: schedule.paused,
+        next_run_at: schedule.next_run_at,
+        last_run_at: schedule.last_run_at,
+        last_error: schedule.last_error,
+        created_at: schedule.created_at,
+    }
+}
+
+/// How many scheduled tasks the user may have, see [`schedule_allowance`].
+pub async fn schedule_limit(pool: &PostgresPool, user_id: &str) -> Result<i64, PostgresPoolError> {
+    let is_admin = User::find_by_id(pool, user_id)
+        .await?
+        .is_some_and(|user| user.is_admin);
+    let plan = SubscribeUser::get_by_user_id(pool, user_id)
+        .await?
+        .filter(|sub| sub.valid_to > chrono::Utc::now())
+        .map(|sub| sub.plan);
+    Ok(schedule_allowance(is_admin, plan))
+}
+
+/// Creates the schedule, or returns `None` when the user's `limit` is reached.
+pub async fn create_schedule(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    task_id: &Uuid,
+    cron: Option<&str>,
+    interval_secs: Option<i64>,
+    next_run_at: chrono::DateTime<Utc>,
+    limit: i64,
+) -> Result<Option<ScheduleElement>, PostgresPoolError> {
+    Ok(Schedule::create(
+        postgre_pool,
+        user_id,
+        task_id,
+        cron,
+        interval_secs,
+        next_run_at,
+        limit,
+    )
+    .await?
+    .map(to_schedule_element))
+}
+
+pub async fn get_schedules(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+) -> Result<Vec<ScheduleElement>, PostgresPoolError> {
+    Ok(Schedule::find_by_user_id(postgre_pool, user_id)
+        .await?
+        .into_iter()
+        .map(to_schedule_element)
+        .collect())
+}
+
+pub async fn get_task_schedule(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    task_id: &Uuid,
+) -> Result<Option<ScheduleElement>, PostgresPoolError> {
+    Ok(Schedule::find_by_task(postgre_pool, task_id, user_id)
+        .await?
+        .map(to_schedule_element))
+}
+
+pub async fn pause_schedule(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<Option<ScheduleElement>, PostgresPoolError> {
+    Ok(
+        Schedule::set_paused(postgre_pool, id, user_id, true, Utc::now())
+            .await?
+            .map(to_schedule_element),
+    )
+}
+
+/// Resumes the schedule from now on, without catching up on runs missed while it was paused.
+pub async fn resume_schedule(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<Option<ScheduleElement>, MixPostgresAndCustomError> {
+    let Some(schedule) = Schedule::find_by_id(postgre_pool, id, user_id)
+        .await
+        .map_err(MixPostgresAndCustomError::Postgres)?
+    else {
+        return Ok(None);
+    };
+    let next_run_at = Cadence::parse(schedule.cron.as_deref(), schedule.interval_secs)
+        .map_err(MixPostgresAndCustomError::Custom)?
+        .next_after(Utc::now())
+        .ok_or(MixPostgresAndCustomError::Custom(
+            "schedule has no runs left".to_string(),
+        ))?;
+    Ok(
+        Schedule::set_paused(postgre_pool, id, user_id, false, next_run_at)
+            .await
+            .map_err(MixPostgresAndCustomError::Postgres)?
+            .map(to_schedule_element),
+    )
+}
+
+pub async fn delete_schedule(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<bool, PostgresPoolError> {
+    Schedule::delete(postgre_pool, id, user_id).await
+}
+
+/// Claims due schedules and moves each to its next run before it starts, so a schedule fires at
+/// most once per slot even when starting the run fails.
+pub async fn take_due_schedules(
+    postgre_pool: &PostgresPool,
+    limit: i64,
+) -> Result<Vec<Schedule>, PostgresPoolError> {
+    let mut client = postgre_pool.get().await?;
+    let transaction = client.transaction().await?;
+    let due = Schedule::lock_due(&transaction, limit).await?;
+    let now = Utc::now();
+    for schedule in &due {
+        let next_run_at = Cadence::parse(schedule.cron.as_deref(), schedule.interval_secs)
+            .ok()
+            .and_then(|cadence| cadence.next_after(now));
+        Schedule::advance(&transaction, &schedule.id, next_run_at).await?;
+    }
+    transaction.commit().await?;
+    Ok(due)
+}
+
+pub async fn record_schedule_run(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+    error: Option<&str>,
+) -> Result<(), PostgresPoolError> {
+    Schedule::record_run(postgre_pool, id, error).await
+}
+
+/// The marketplace id of the task's main product, which the parser refreshes keywords for.
+pub async fn get_task_product_id(
+    mongo_pool: &MongoPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<Option<u64>, MongoPoolError> {
+    Ok(function_mongo::get_task(mongo_pool, user_id, *id)
+        .await?
+        .map(|task| task.main_product.id))
+}
+
+/// Keeps how the user split known keywords, files new ones as unused and drops the ones the
+/// parser no longer returns. Without fresh terms the words stay as they were.
+fn refresh_words(
+    words: function_mongo::WordsAnalysis,
+    terms: Option<Vec<String>>,
+) -> (Vec<String>, Vec<String>) {
+    let Some(terms) = terms.filter(|terms| !terms.is_empty()) else {
+        return (words.used_words, words.unused_words);
+    };
+    let (used, unused) = terms
+        .into_iter()
+        .partition(|term| words.used_words.contains(term));
+    (used, unused)
+}
+
+/// Starts the next run of a scheduled task from its stored products, with only its keywords
+/// refreshed from `terms`, and queues its jobs in the outbox. Returns `false` when the task is
+/// gone or still running. The run is claimed in Postgres first; if recording it in MongoDB then
+/// fails, the run is failed.
+pub async fn start_scheduled_run(
+    post_pool: &PostgresPool,
+    mongo_pool: &MongoPool,
+    user_id: &str,
+    id: &Uuid,
+    terms: Option<Vec<String>>,
+    progress_start: u64,
+) -> Result<bool, MixPoolError> {
+    let Some(task) = function_mongo::get_task(mongo_pool, user_id, *id)
+        .await
+        .map_err(MixPoolError::Mongo)?
+    else {
+        return Ok(false);
+    };
+    let priority = job_priority(post_pool, user_id)
+        .await
+        .map_err(MixPoolError::Postgres)?;
+    let mut client = post_pool.get().await.map_err(MixPoolError::Postgres)?;
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|e| MixPoolError::Postgres(e.into()))?;
+    let run = RunStart {
+        progress_start,
+        kinds: &AnalysisKind::ALL,
+    };
+    if function_postgre::Task::start_next_run(
+        &transaction,
+        id,
+        user_id,
+        Utc::now() + analysis_deadline(),
+        &run,
+    )
+    .await
+    .map_err(MixPoolError::Postgres)?
+        != RunClaim::Started
+    {
+        return Ok(false);
+    }
+
+    let products = std::iter::once(&task.main_product).chain(&task.competitors);
+    let mut text = vec![];
+    let mut photo = vec![];
+    let mut reviews = vec![];
+    for product in products {
+        text.push(product.description.as_str());
+        photo.push(product.image_url.as_str());
+        reviews.push(
+            product
+                .reviews
+                .iter()
+                .map(|r| {
+                    crate::format_review(&Review {
+                        text: r.text.clone(),
+                        pros: r.pros.clone(),
+                        cons: r.cons.clone(),
+                    })
+                })
+                .collect(),
+        );
+    }
+    let content_type = ContentType::configured();
+    for job in [
+        text_analysis_job(id, task.run + 1, text),
+        photo_analysis_job(id, task.run + 1, photo),
+        reviews_analysis_job(id, task.run + 1, reviews),
+    ] {
+        OutboxMessage::enqueue(
+            &transaction,
+            id,
+            job.body.kind().as_str(),
+            content_type.as_str(),
+            &job.encode(content_type),
+            priority as i16,
+        )
+        .await
+        .map_err(MixPoolError::Postgres)?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .map_err(|e| MixPoolError::Postgres(e.into()))?;
+
+    // The run is claimed; both writes below are keyed on the run, so a repeat is harmless.
+    let (used_words, unused_words) = refresh_words(task.words_analysis.clone(), terms);
+    let recorded = async {
+        function_mongo::archive_task_run(mongo_pool, user_id, &task).await?;
+        function_mongo::update_task(
+            mongo_pool,
+            user_id,
+            *id,
+            task.run,
+            task.main_product,
+            task.competitors,
+            used_words,
+            unused_words,
+        )
+        .await
+    }
+    .await;
+    if let Err(e) = recorded {
+        if let Err(e) = function_postgre::Task::fail(post_pool, id, RUN_NOT_RECORDED).await {
+            log::error!("Failed to fail unrecorded run of task {}: {}", id, e);
+        }
+        return Err(MixPoolError::Mongo(e));
+    }
+    Ok(true)
+}
+
+pub async fn get_task_status(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<Option<TaskStatus>, PostgresPoolError> {
+    function_postgre::Task::find_status(postgre_pool, id, user_id).await
+}
+
+pub async fn get_task_statuses(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    ids: &[Uuid],
+) -> Result<HashMap<Uuid, TaskStatus>, PostgresPoolError> {
+    function_postgre::Task::find_statuses(postgre_pool, ids, user_id).await
+}
+
+pub async fn get_task_owner(
+    postgre_pool: &PostgresPool,
+    id: &Uuid,
+) -> Result<Option<String>, PostgresPoolError> {
+    function_postgre::Task::find_owner(postgre_pool, id).await
+}
+
+pub async fn get_task_progress(
+    postgre_pool: &PostgresPool,
+    user_id: &str,
+    id: &Uuid,
+) -> Result<Option<TaskProgress>, PostgresPoolError> {
+    function_postgre::Task::find_progress(postgre_pool, id, user_id).await
 }
 
 pub async fn update_task_name(
@@ -296,13 +1611,10 @@ pub async fn update_task_name(
 }
 
 pub async fn sub_is_exist(pool: &PostgresPool, user_id: &str) -> Result<bool, PostgresPoolError> {
-    match User::find_by_id(pool, user_id).await? {
-        Some(user) => {
-            if user.is_admin {
-                return Ok(true);
-            }
+    if let Some(user) = User::find_by_id(pool, user_id).await? {
+        if user.is_admin {
+            return Ok(true);
         }
-        None => (),
     }
     Ok(SubscribeUser::get_by_user_id(pool, user_id)
         .await?
@@ -310,6 +1622,28 @@ pub async fn sub_is_exist(pool: &PostgresPool, user_id: &str) -> Result<bool, Po
         .unwrap_or(false))
 }
 
+const ADMIN_JOB_PRIORITY: u8 = 9;
+
+/// RabbitMQ priority of a user's analysis jobs, see [`plan_job_priority`].
+pub async fn job_priority(pool: &PostgresPool, user_id: &str) -> Result<u8, PostgresPoolError> {
+    let is_admin = User::find_by_id(pool, user_id)
+        .await?
+        .is_some_and(|user| user.is_admin);
+    let plan = SubscribeUser::get_by_user_id(pool, user_id)
+        .await?
+        .filter(|sub| sub.valid_to > chrono::Utc::now())
+        .map(|sub| sub.plan);
+    Ok(plan_job_priority(is_admin, plan))
+}
+
+/// Admins first, then by active subscription plan; users without one last.
+fn plan_job_priority(is_admin: bool, plan: Option<SubscriptionPlan>) -> u8 {
+    if is_admin {
+        return ADMIN_JOB_PRIORITY;
+    }
+    plan.map_or(0, |plan| plan.priority())
+}
+
 pub async fn get_account_info(
     pool: &PostgresPool,
     user_id: &str,
@@ -321,7 +1655,7 @@ pub async fn get_account_info(
         .await
         .map_err(MixPostgresAndCustomError::Postgres)?;
     if let Some(user) = user {
-        return Ok(SendAccount {
+        Ok(SendAccount {
             name: user.name,
             email: user.email,
             sessions: sessions
@@ -332,11 +1666,11 @@ pub async fn get_account_info(
                     last_activity: s._last_activity,
                 })
                 .collect(),
-        });
+        })
     } else {
-        return Err(MixPostgresAndCustomError::Custom(
+        Err(MixPostgresAndCustomError::Custom(
             "User not found".to_string(),
-        ));
+        ))
     }
 }
 
@@ -349,7 +1683,7 @@ pub async fn is_admin(pool: &PostgresPool, user_id: &str) -> Result<bool, Postgr
 
 pub async fn get_all_users(pool: &PostgresPool) -> Result<Vec<FullUser>, PostgresPoolError> {
     let users = FullUser::find_all(pool).await?;
-    Ok(users.into_iter().map(FullUser::from).collect())
+    Ok(users.into_iter().collect())
 }
 
 pub async fn create_subscribe(
@@ -357,8 +1691,9 @@ pub async fn create_subscribe(
     user_id: &str,
     created_at: chrono::DateTime<chrono::Utc>,
     valid_to: chrono::DateTime<chrono::Utc>,
+    plan: SubscriptionPlan,
 ) -> Result<(), PostgresPoolError> {
-    SubscribeUser::create(user_id, created_at, valid_to, pool).await?;
+    SubscribeUser::create(user_id, created_at, valid_to, plan, pool).await?;
     Ok(())
 }
 
@@ -370,3 +1705,256 @@ pub async fn set_admin(
     User::set_to_admin(pool, user_id, &is_admin).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy_inputs() -> AnalysisInputs {
+        AnalysisInputs {
+            text: vec!["main".to_string(), "competitor".to_string()],
+            photo: vec![String::new(), String::new()],
+            reviews: vec![vec![], vec![]],
+        }
+    }
+
+    #[test]
+    fn legacy_tasks_only_have_text_inputs() {
+        let inputs = legacy_inputs();
+        assert!(inputs.has(AnalysisKind::Text));
+        assert!(!inputs.has(AnalysisKind::Photo));
+        assert!(!inputs.has(AnalysisKind::Reviews));
+    }
+
+    #[test]
+    fn one_product_with_inputs_is_enough() {
+        let mut inputs = legacy_inputs();
+        inputs.photo[1] = "https://example.com/photo.jpg".to_string();
+        inputs.reviews[0].push(Review {
+            text: "fine".to_string(),
+            pros: String::new(),
+            cons: String::new(),
+        });
+        assert!(inputs.has(AnalysisKind::Photo));
+        assert!(inputs.has(AnalysisKind::Reviews));
+    }
+
+    fn task(name: &str) -> function_postgre::Task {
+        function_postgre::Task {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            _user_id: "user".to_string(),
+            created_at: chrono::DateTime::parse_from_rfc3339("2026-03-02T10:00:15.123456Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            status: "running".to_string(),
+            tags: vec![],
+        }
+    }
+
+    fn hex(text: &str) -> String {
+        text.bytes().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn history_cursors_round_trip() {
+        for name in ["phone", "a|b|c", "телефон 📱", ""] {
+            let task = task(name);
+            let cursor = encode_history_cursor(HistorySort::Name, &task);
+            assert_eq!(
+                decode_history_cursor(HistorySort::Name, &cursor),
+                Some(HistoryCursor::Name(name.to_string(), task.id)),
+                "{}",
+                name
+            );
+            let cursor = encode_history_cursor(HistorySort::CreatedAt, &task);
+            assert_eq!(
+                decode_history_cursor(HistorySort::CreatedAt, &cursor),
+                Some(HistoryCursor::CreatedAt(task.created_at, task.id))
+            );
+        }
+    }
+
+    #[test]
+    fn history_cursors_of_another_sort_are_refused() {
+        let task = task("phone");
+        let cursor = encode_history_cursor(HistorySort::Name, &task);
+        assert_eq!(decode_history_cursor(HistorySort::CreatedAt, &cursor), None);
+        let cursor = encode_history_cursor(HistorySort::CreatedAt, &task);
+        assert_eq!(decode_history_cursor(HistorySort::Name, &cursor), None);
+    }
+
+    #[test]
+    fn malformed_history_cursors_are_refused() {
+        let id = Uuid::new_v4();
+        for cursor in [
+            String::new(),
+            "zz".to_string(),
+            "6e616d657".to_string(),
+            "ффff".to_string(),
+            hex("name"),
+            hex(&format!("name|phone{}", id)),
+            hex("name|phone|not-a-uuid"),
+            hex(&format!("price|10|{}", id)),
+            hex(&format!("createdAt|yesterday|{}", id)),
+            "ff".repeat(4),
+        ] {
+            assert_eq!(
+                decode_history_cursor(HistorySort::Name, &cursor),
+                None,
+                "{}",
+                cursor
+            );
+            assert_eq!(
+                decode_history_cursor(HistorySort::CreatedAt, &cursor),
+                None,
+                "{}",
+                cursor
+            );
+        }
+    }
+
+    #[test]
+    fn history_search_builds_prefix_queries() {
+        for (text, query) in [
+            ("phone", Some("phone:*")),
+            ("phone  case", Some("phone:* & case:*")),
+            ("a&b|c:*!(d)", Some("a:* & b:* & c:* & d:*")),
+            ("Смартфон x2", Some("Смартфон:* & x2:*")),
+            ("", None),
+            (" & | ! ", None),
+        ] {
+            assert_eq!(history_search(text).as_deref(), query, "{}", text);
+        }
+    }
+
+    #[test]
+    fn history_filter_defaults_to_newest_first() {
+        let filter = history_filter(&HistoryQuery::default()).unwrap();
+        assert_eq!(filter.sort, HistorySort::CreatedAt);
+        assert!(filter.descending);
+        assert!(filter.statuses.is_empty());
+        assert_eq!(filter.after, None);
+        assert_eq!(filter.search, None);
+
+        let by_name = HistoryQuery {
+            sort: Some("name".to_string()),
+            ..Default::default()
+        };
+        let filter = history_filter(&by_name).unwrap();
+        assert_eq!(filter.sort, HistorySort::Name);
+        assert!(!filter.descending);
+    }
+
+    #[test]
+    fn history_filter_reads_the_query() {
+        let task = task("phone");
+        let query = HistoryQuery {
+            cursor: Some(encode_history_cursor(HistorySort::Name, &task)),
+            from: Some("2026-03-01".to_string()),
+            to: Some("2026-03-02T12:00:00+02:00".to_string()),
+            status: Some("running,failed,".to_string()),
+            tag: Some(String::new()),
+            q: Some("red phone".to_string()),
+            sort: Some("name".to_string()),
+            order: Some("desc".to_string()),
+            ..Default::default()
+        };
+        let filter = history_filter(&query).unwrap();
+        assert!(filter.descending);
+        assert_eq!(filter.statuses, [TaskStatus::Running, TaskStatus::Failed]);
+        assert_eq!(filter.tag, None);
+        assert_eq!(filter.search.as_deref(), Some("red:* & phone:*"));
+        assert_eq!(
+            filter.created_from.map(|at| at.to_rfc3339()).as_deref(),
+            Some("2026-03-01T00:00:00+00:00")
+        );
+        assert_eq!(
+            filter.created_to.map(|at| at.to_rfc3339()).as_deref(),
+            Some("2026-03-02T10:00:00+00:00")
+        );
+        assert_eq!(
+            filter.after,
+            Some(HistoryCursor::Name("phone".to_string(), task.id))
+        );
+    }
+
+    #[test]
+    fn invalid_history_queries_are_refused() {
+        let cursor = encode_history_cursor(HistorySort::Name, &task("phone"));
+        for (query, error) in [
+            (
+                HistoryQuery {
+                    sort: Some("price".to_string()),
+                    ..Default::default()
+                },
+                "unknown sort price",
+            ),
+            (
+                HistoryQuery {
+                    order: Some("up".to_string()),
+                    ..Default::default()
+                },
+                "unknown order up",
+            ),
+            (
+                HistoryQuery {
+                    status: Some("running,paused".to_string()),
+                    ..Default::default()
+                },
+                "unknown status paused",
+            ),
+            (
+                HistoryQuery {
+                    from: Some("01.03.2026".to_string()),
+                    ..Default::default()
+                },
+                "invalid from date",
+            ),
+            (
+                HistoryQuery {
+                    to: Some("tomorrow".to_string()),
+                    ..Default::default()
+                },
+                "invalid to date",
+            ),
+            (
+                HistoryQuery {
+                    cursor: Some("not hex".to_string()),
+                    ..Default::default()
+                },
+                "invalid cursor",
+            ),
+            (
+                HistoryQuery {
+                    cursor: Some(cursor),
+                    ..Default::default()
+                },
+                "invalid cursor",
+            ),
+        ] {
+            assert_eq!(history_filter(&query).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn job_priority_follows_the_plan() {
+        for (is_admin, plan, priority) in [
+            (false, None, 0),
+            (false, Some(SubscriptionPlan::Trial), 0),
+            (false, Some(SubscriptionPlan::Standard), 3),
+            (false, Some(SubscriptionPlan::Premium), 6),
+            (true, None, ADMIN_JOB_PRIORITY),
+            (true, Some(SubscriptionPlan::Trial), ADMIN_JOB_PRIORITY),
+        ] {
+            assert_eq!(
+                plan_job_priority(is_admin, plan),
+                priority,
+                "{} {:?}",
+                is_admin,
+                plan
+            );
+        }
+        assert!(ADMIN_JOB_PRIORITY > SubscriptionPlan::Premium.priority());
+    }
+}
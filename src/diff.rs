@@ -0,0 +1,538 @@
+use crate::structure::analysis_structures::{
+    PhotoAnalysis, ReviewAnalysis, Sentiment, Severity, TextAnalysis,
+};
+use crate::structure::send_structures::{
+    AnalysisChange, DiffProduct, KeywordChanges, Product, ProductChange, Task, TaskDiff,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::hash::Hash;
+
+pub fn diff_tasks(from: &Task, to: &Task) -> TaskDiff {
+    let before: FxHashMap<u64, _> = from.products.iter().map(|p| (p.id, p)).collect();
+    let after: FxHashMap<u64, _> = to.products.iter().map(|p| (p.id, p)).collect();
+
+    let added_competitors = to
+        .products
+        .iter()
+        .filter(|p| !before.contains_key(&p.id))
+        .map(|p| DiffProduct {
+            id: p.id,
+            name: p.name.clone(),
+        })
+        .collect();
+    let removed_competitors = from
+        .products
+        .iter()
+        .filter(|p| !after.contains_key(&p.id))
+        .map(|p| DiffProduct {
+            id: p.id,
+            name: p.name.clone(),
+        })
+        .collect();
+    let changed_competitors = to
+        .products
+        .iter()
+        .filter_map(|new| product_change(before.get(&new.id)?, new))
+        .collect();
+
+    let mut analyses = diff_text(from.text_analyses.as_ref(), to.text_analyses.as_ref());
+    analyses.extend(diff_photo(
+        from.photo_analyses.as_ref(),
+        to.photo_analyses.as_ref(),
+    ));
+    analyses.extend(diff_reviews(
+        from.review_analyses.as_ref(),
+        to.review_analyses.as_ref(),
+    ));
+    analyses.extend(diff_keyword_scores(
+        from.text_analyses.as_ref(),
+        to.text_analyses.as_ref(),
+    ));
+    analyses.extend(diff_findings(
+        from.photo_analyses.as_ref(),
+        to.photo_analyses.as_ref(),
+    ));
+    analyses.extend(diff_themes(
+        from.review_analyses.as_ref(),
+        to.review_analyses.as_ref(),
+    ));
+
+    TaskDiff {
+        from_run: from.run,
+        to_run: to.run,
+        main_product: product_change(&from.main, &to.main),
+        added_competitors,
+        removed_competitors,
+        changed_competitors,
+        keywords: diff_keywords(from, to),
+        analyses,
+    }
+}
+
+fn product_change(old: &Product, new: &Product) -> Option<ProductChange> {
+    if old.price == new.price && old.review_rating == new.review_rating {
+        return None;
+    }
+    Some(ProductChange {
+        id: new.id,
+        name: new.name.clone(),
+        price_before: old.price,
+        price_after: new.price,
+        price_delta: new.price - old.price,
+        rating_before: old.review_rating,
+        rating_after: new.review_rating,
+        rating_delta: new.review_rating - old.review_rating,
+    })
+}
+
+fn diff_keywords(from: &Task, to: &Task) -> KeywordChanges {
+    let old_used: FxHashSet<&str> = from.used_words.iter().map(String::as_str).collect();
+    let old_unused: FxHashSet<&str> = from.unused_words.iter().map(String::as_str).collect();
+    let new_used: FxHashSet<&str> = to.used_words.iter().map(String::as_str).collect();
+    let new_unused: FxHashSet<&str> = to.unused_words.iter().map(String::as_str).collect();
+
+    let pick = |words: &mut dyn Iterator<Item = &String>, keep: &dyn Fn(&str) -> bool| {
+        words
+            .filter(|w| keep(w.as_str()))
+            .cloned()
+            .collect::<Vec<String>>()
+    };
+
+    KeywordChanges {
+        moved_to_used: pick(&mut to.used_words.iter(), &|w| old_unused.contains(w)),
+        moved_to_unused: pick(&mut to.unused_words.iter(), &|w| old_used.contains(w)),
+        added: pick(&mut to.used_words.iter().chain(&to.unused_words), &|w| {
+            !old_used.contains(w) && !old_unused.contains(w)
+        }),
+        removed: pick(
+            &mut from.used_words.iter().chain(&from.unused_words),
+            &|w| !new_used.contains(w) && !new_unused.contains(w),
+        ),
+    }
+}
+
+/// Pairs up per-product values of two runs, keeping only products whose value changed.
+fn changed<K: Copy + Ord + Hash, V: PartialEq + Copy>(
+    old: FxHashMap<K, V>,
+    new: FxHashMap<K, V>,
+) -> Vec<(K, Option<V>, Option<V>)> {
+    let mut ids: Vec<K> = old.keys().chain(new.keys()).copied().collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids.into_iter()
+        .map(|id| (id, old.get(&id).copied(), new.get(&id).copied()))
+        .filter(|(_, before, after)| before != after)
+        .collect()
+}
+
+fn diff_text(from: Option<&TextAnalysis>, to: Option<&TextAnalysis>) -> Vec<AnalysisChange> {
+    fn summaries(analysis: Option<&TextAnalysis>) -> FxHashMap<u64, &str> {
+        analysis
+            .into_iter()
+            .flat_map(|a| a.products.iter())
+            .map(|p| (p.product_id, p.summary.as_str()))
+            .collect()
+    }
+    changed(summaries(from), summaries(to))
+        .into_iter()
+        .map(|(product_id, before, after)| AnalysisChange::Text {
+            product_id,
+            before: before.map(str::to_string),
+            after: after.map(str::to_string),
+        })
+        .collect()
+}
+
+fn diff_photo(from: Option<&PhotoAnalysis>, to: Option<&PhotoAnalysis>) -> Vec<AnalysisChange> {
+    let scores = |analysis: Option<&PhotoAnalysis>| -> FxHashMap<u64, f32> {
+        analysis
+            .into_iter()
+            .flat_map(|a| a.products.iter())
+            .map(|p| (p.product_id, p.quality_score))
+            .collect()
+    };
+    changed(scores(from), scores(to))
+        .into_iter()
+        .map(|(product_id, before, after)| AnalysisChange::Photo {
+            product_id,
+            before,
+            after,
+        })
+        .collect()
+}
+
+fn diff_reviews(from: Option<&ReviewAnalysis>, to: Option<&ReviewAnalysis>) -> Vec<AnalysisChange> {
+    let sentiments = |analysis: Option<&ReviewAnalysis>| -> FxHashMap<u64, (Sentiment, f32)> {
+        analysis
+            .into_iter()
+            .flat_map(|a| a.products.iter())
+            .map(|p| (p.product_id, (p.sentiment, p.sentiment_score)))
+            .collect()
+    };
+    changed(sentiments(from), sentiments(to))
+        .into_iter()
+        .map(|(product_id, before, after)| AnalysisChange::Reviews {
+            product_id,
+            before: before.map(|(sentiment, _)| sentiment),
+            after: after.map(|(sentiment, _)| sentiment),
+            score_delta: before
+                .zip(after)
+                .map(|((_, old_score), (_, new_score))| new_score - old_score),
+        })
+        .collect()
+}
+
+fn diff_keyword_scores(
+    from: Option<&TextAnalysis>,
+    to: Option<&TextAnalysis>,
+) -> Vec<AnalysisChange> {
+    fn scores(analysis: Option<&TextAnalysis>) -> FxHashMap<(u64, &str), f32> {
+        analysis
+            .into_iter()
+            .flat_map(|a| a.products.iter())
+            .flat_map(|p| {
+                p.keywords
+                    .iter()
+                    .map(move |k| ((p.product_id, k.word.as_str()), k.score))
+            })
+            .collect()
+    }
+    changed(scores(from), scores(to))
+        .into_iter()
+        .map(
+            |((product_id, word), before, after)| AnalysisChange::Keyword {
+                product_id,
+                word: word.to_string(),
+                before,
+                after,
+            },
+        )
+        .collect()
+}
+
+fn diff_findings(from: Option<&PhotoAnalysis>, to: Option<&PhotoAnalysis>) -> Vec<AnalysisChange> {
+    fn severities(analysis: Option<&PhotoAnalysis>) -> FxHashMap<(u64, &str), Severity> {
+        analysis
+            .into_iter()
+            .flat_map(|a| a.products.iter())
+            .flat_map(|p| {
+                p.findings
+                    .iter()
+                    .map(move |f| ((p.product_id, f.issue.as_str()), f.severity))
+            })
+            .collect()
+    }
+    changed(severities(from), severities(to))
+        .into_iter()
+        .map(
+            |((product_id, issue), before, after)| AnalysisChange::Finding {
+                product_id,
+                issue: issue.to_string(),
+                before,
+                after,
+            },
+        )
+        .collect()
+}
+
+fn diff_themes(from: Option<&ReviewAnalysis>, to: Option<&ReviewAnalysis>) -> Vec<AnalysisChange> {
+    fn themes(analysis: Option<&ReviewAnalysis>) -> FxHashMap<(u64, &str), (Sentiment, u32)> {
+        analysis
+            .into_iter()
+            .flat_map(|a| a.products.iter())
+            .flat_map(|p| {
+                p.themes
+                    .iter()
+                    .map(move |t| ((p.product_id, t.theme.as_str()), (t.sentiment, t.mentions)))
+            })
+            .collect()
+    }
+    let mentions = |theme: Option<(Sentiment, u32)>| theme.map_or(0, |(_, m)| i64::from(m));
+    changed(themes(from), themes(to))
+        .into_iter()
+        .map(
+            |((product_id, theme), before, after)| AnalysisChange::Theme {
+                product_id,
+                theme: theme.to_string(),
+                before: before.map(|(sentiment, _)| sentiment),
+                after: after.map(|(sentiment, _)| sentiment),
+                mentions_delta: mentions(after) - mentions(before),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::analysis_structures::{
+        ImageFinding, KeywordScore, ProductPhotoAnalysis, ProductReviewAnalysis,
+        ProductTextAnalysis, ReviewTheme, ANALYSIS_SCHEMA_VERSION,
+    };
+    use serde_json::{json, Value};
+
+    fn product(id: u64, price: f64, review_rating: f64) -> Product {
+        Product {
+            id,
+            root: id,
+            name: format!("product {}", id),
+            brand: String::new(),
+            price,
+            review_rating,
+            description: String::new(),
+        }
+    }
+
+    fn task(run: u32) -> Task {
+        Task {
+            run,
+            main: product(1, 0.0, 0.0),
+            products: vec![],
+            used_words: vec![],
+            unused_words: vec![],
+            text_analyses: None,
+            photo_analyses: None,
+            review_analyses: None,
+        }
+    }
+
+    fn text(keywords: &[(&str, f32)], summary: &str) -> Option<TextAnalysis> {
+        Some(TextAnalysis {
+            version: ANALYSIS_SCHEMA_VERSION,
+            products: vec![ProductTextAnalysis {
+                product_id: 1,
+                keywords: keywords
+                    .iter()
+                    .map(|&(word, score)| KeywordScore {
+                        word: word.to_string(),
+                        score,
+                    })
+                    .collect(),
+                summary: summary.to_string(),
+            }],
+        })
+    }
+
+    fn photo(quality_score: f32, findings: &[(&str, Severity)]) -> Option<PhotoAnalysis> {
+        Some(PhotoAnalysis {
+            version: ANALYSIS_SCHEMA_VERSION,
+            products: vec![ProductPhotoAnalysis {
+                product_id: 1,
+                quality_score,
+                findings: findings
+                    .iter()
+                    .map(|&(issue, severity)| ImageFinding {
+                        issue: issue.to_string(),
+                        severity,
+                    })
+                    .collect(),
+            }],
+        })
+    }
+
+    fn reviews(sentiment_score: f32, themes: &[(&str, Sentiment, u32)]) -> Option<ReviewAnalysis> {
+        Some(ReviewAnalysis {
+            version: ANALYSIS_SCHEMA_VERSION,
+            products: vec![ProductReviewAnalysis {
+                product_id: 1,
+                sentiment: Sentiment::Positive,
+                sentiment_score,
+                themes: themes
+                    .iter()
+                    .map(|&(theme, sentiment, mentions)| ReviewTheme {
+                        theme: theme.to_string(),
+                        mentions,
+                        sentiment,
+                    })
+                    .collect(),
+            }],
+        })
+    }
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn products_are_compared_by_id() {
+        let mut from = task(1);
+        from.products = vec![product(2, 100.0, 4.0), product(3, 50.0, 3.0)];
+        let mut to = task(2);
+        to.main = product(1, 10.0, 4.5);
+        to.products = vec![product(2, 90.0, 4.0), product(4, 70.0, 5.0)];
+
+        let diff = serde_json::to_value(diff_tasks(&from, &to)).unwrap();
+        assert_eq!(diff["fromRun"], 1);
+        assert_eq!(diff["toRun"], 2);
+        for (field, expected) in [
+            (
+                "addedCompetitors",
+                json!([{ "id": 4, "name": "product 4" }]),
+            ),
+            (
+                "removedCompetitors",
+                json!([{ "id": 3, "name": "product 3" }]),
+            ),
+            ("changedCompetitors.0.priceDelta", json!(-10.0)),
+            ("changedCompetitors.0.ratingDelta", json!(0.0)),
+            ("mainProduct.priceBefore", json!(0.0)),
+            ("mainProduct.priceAfter", json!(10.0)),
+            ("mainProduct.ratingDelta", json!(4.5)),
+        ] {
+            let value = field
+                .split('.')
+                .fold(&diff, |value, key| match key.parse::<usize>() {
+                    Ok(index) => &value[index],
+                    Err(_) => &value[key],
+                });
+            assert_eq!(value, &expected, "{}", field);
+        }
+    }
+
+    #[test]
+    fn unchanged_main_product_is_left_out() {
+        let diff = serde_json::to_value(diff_tasks(&task(1), &task(2))).unwrap();
+        assert_eq!(diff["mainProduct"], Value::Null);
+        assert_eq!(diff["analyses"], json!([]));
+    }
+
+    #[test]
+    fn keywords_are_compared_across_lists() {
+        let mut from = task(1);
+        from.used_words = words(&["phone", "case"]);
+        from.unused_words = words(&["cheap", "old"]);
+        let mut to = task(2);
+        to.used_words = words(&["phone", "cheap", "new"]);
+        to.unused_words = words(&["case"]);
+
+        let keywords = diff_tasks(&from, &to).keywords;
+        assert_eq!(keywords.moved_to_used, ["cheap"]);
+        assert_eq!(keywords.moved_to_unused, ["case"]);
+        assert_eq!(keywords.added, ["new"]);
+        assert_eq!(keywords.removed, ["old"]);
+    }
+
+    #[test]
+    fn analyses_report_only_changed_values() {
+        let cases: Vec<(&str, Task, Task, Value)> = vec![
+            (
+                "text summary",
+                Task {
+                    text_analyses: text(&[], "old"),
+                    ..task(1)
+                },
+                Task {
+                    text_analyses: text(&[], "new"),
+                    ..task(2)
+                },
+                json!([{ "kind": "text", "productId": 1, "before": "old", "after": "new" }]),
+            ),
+            (
+                "keyword scores",
+                Task {
+                    text_analyses: text(&[("phone", 0.5), ("case", 0.2), ("old", 0.1)], "s"),
+                    ..task(1)
+                },
+                Task {
+                    text_analyses: text(&[("phone", 0.5), ("case", 0.4), ("new", 0.3)], "s"),
+                    ..task(2)
+                },
+                json!([
+                    { "kind": "keyword", "productId": 1, "word": "case", "before": 0.2f32, "after": 0.4f32 },
+                    { "kind": "keyword", "productId": 1, "word": "new", "before": null, "after": 0.3f32 },
+                    { "kind": "keyword", "productId": 1, "word": "old", "before": 0.1f32, "after": null },
+                ]),
+            ),
+            (
+                "photo findings",
+                Task {
+                    photo_analyses: photo(
+                        0.5,
+                        &[("blurry", Severity::High), ("dark", Severity::Low)],
+                    ),
+                    ..task(1)
+                },
+                Task {
+                    photo_analyses: photo(
+                        0.5,
+                        &[("blurry", Severity::Medium), ("dark", Severity::Low)],
+                    ),
+                    ..task(2)
+                },
+                json!([
+                    { "kind": "finding", "productId": 1, "issue": "blurry", "before": "high", "after": "medium" },
+                ]),
+            ),
+            (
+                "photo score and resolved finding",
+                Task {
+                    photo_analyses: photo(0.5, &[("cropped", Severity::Low)]),
+                    ..task(1)
+                },
+                Task {
+                    photo_analyses: photo(0.75, &[]),
+                    ..task(2)
+                },
+                json!([
+                    { "kind": "photo", "productId": 1, "before": 0.5, "after": 0.75 },
+                    { "kind": "finding", "productId": 1, "issue": "cropped", "before": "low", "after": null },
+                ]),
+            ),
+            (
+                "review themes",
+                Task {
+                    review_analyses: reviews(
+                        0.5,
+                        &[
+                            ("battery", Sentiment::Negative, 4),
+                            ("price", Sentiment::Positive, 2),
+                        ],
+                    ),
+                    ..task(1)
+                },
+                Task {
+                    review_analyses: reviews(
+                        0.5,
+                        &[
+                            ("battery", Sentiment::Neutral, 6),
+                            ("price", Sentiment::Positive, 2),
+                            ("delivery", Sentiment::Negative, 1),
+                        ],
+                    ),
+                    ..task(2)
+                },
+                json!([
+                    { "kind": "theme", "productId": 1, "theme": "battery", "before": "negative", "after": "neutral", "mentionsDelta": 2 },
+                    { "kind": "theme", "productId": 1, "theme": "delivery", "before": null, "after": "negative", "mentionsDelta": 1 },
+                ]),
+            ),
+            (
+                "review score",
+                Task {
+                    review_analyses: reviews(0.25, &[]),
+                    ..task(1)
+                },
+                Task {
+                    review_analyses: reviews(0.75, &[]),
+                    ..task(2)
+                },
+                json!([
+                    { "kind": "reviews", "productId": 1, "before": "positive", "after": "positive", "scoreDelta": 0.5 },
+                ]),
+            ),
+            (
+                "analysis missing from the earlier run",
+                task(1),
+                Task {
+                    photo_analyses: photo(0.5, &[]),
+                    ..task(2)
+                },
+                json!([{ "kind": "photo", "productId": 1, "before": null, "after": 0.5 }]),
+            ),
+        ];
+        for (name, from, to, expected) in cases {
+            let analyses = serde_json::to_value(diff_tasks(&from, &to).analyses).unwrap();
+            assert_eq!(analyses, expected, "{}", name);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use uuid::Uuid;
 
+pub const STREAM_TICKET_TYPE: &str = "stream";
+
 #[derive(serde::Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct RefreshTokenPayload {
@@ -20,6 +22,20 @@ pub struct AccessTokenPayload {
     _exp: usize,
 }
 
+#[derive(serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StreamTicketPayload {
+    /// Always [`STREAM_TICKET_TYPE`]; keeps other tokens from passing as tickets.
+    pub typ: String,
+    /// Task the ticket is limited to; tickets without one open the live WebSocket.
+    pub task_id: Option<Uuid>,
+    pub user_id: String,
+    /// Session the ticket was issued in.
+    pub session_id: Uuid,
+    #[serde(rename = "exp")]
+    _exp: usize,
+}
+
 #[derive(serde::Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct RefreshToken<'r> {
@@ -38,6 +54,16 @@ pub struct AccessToken<'r> {
     exp: usize,
 }
 
+#[derive(serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StreamTicket<'r> {
+    typ: &'static str,
+    task_id: Option<&'r Uuid>,
+    user_id: &'r str,
+    session_id: &'r Uuid,
+    exp: usize,
+}
+
 impl RefreshToken<'_> {
     pub fn new<'a>(
         id: &'a Uuid,
@@ -61,3 +87,20 @@ impl AccessToken<'_> {
         AccessToken { id, user_id, exp }
     }
 }
+
+impl StreamTicket<'_> {
+    pub fn new<'a>(
+        task_id: Option<&'a Uuid>,
+        user_id: &'a str,
+        session_id: &'a Uuid,
+        exp: usize,
+    ) -> StreamTicket<'a> {
+        StreamTicket {
+            typ: STREAM_TICKET_TYPE,
+            task_id,
+            user_id,
+            session_id,
+            exp,
+        }
+    }
+}
@@ -1,6 +1,7 @@
 mod jwt_structure;
 
 use chrono::{Duration, Utc};
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use jwt_structure::RefreshTokenPayload;
 use std::env;
@@ -82,6 +83,62 @@ pub fn validate_access_jwt(
     Ok(token_data.claims)
 }
 
+/// Short-lived token for subscribing to progress where headers can't be set, e.g. from
+/// `EventSource` or a browser WebSocket.
+pub fn create_stream_ticket(
+    task_id: Option<&uuid::Uuid>,
+    user_id: &str,
+    session_id: &uuid::Uuid,
+    time: Duration,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let secret_key = match env::var("SECRET_KEY") {
+        Ok(s) => s,
+        Err(_) => {
+            log::warn!("Use in create 'default' as key SECRET_KEY not found in env");
+            "default".to_string()
+        }
+    };
+    let expiration = Utc::now()
+        .checked_add_signed(time)
+        .expect("Invalid timestamp")
+        .timestamp();
+
+    let claims =
+        jwt_structure::StreamTicket::new(task_id, user_id, session_id, expiration as usize);
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret_key.as_bytes()),
+    )
+}
+
+pub fn validate_stream_ticket(
+    token: &str,
+) -> Result<jwt_structure::StreamTicketPayload, jsonwebtoken::errors::Error> {
+    let secret_key = match env::var("SECRET_KEY") {
+        Ok(s) => s,
+        Err(_) => {
+            log::warn!("Use in validate 'default' as key SECRET_KEY not found in env");
+            "default".to_string()
+        }
+    };
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.validate_exp = true;
+    validation.leeway = 0;
+
+    let token_data = decode::<jwt_structure::StreamTicketPayload>(
+        token,
+        &DecodingKey::from_secret(secret_key.as_bytes()),
+        &validation,
+    )?;
+    if token_data.claims.typ != jwt_structure::STREAM_TICKET_TYPE {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+
+    Ok(token_data.claims)
+}
+
 pub fn validate_refresh_jwt(
     token: &str,
 ) -> Result<jwt_structure::RefreshTokenPayload, jsonwebtoken::errors::Error> {
@@ -121,3 +178,45 @@ pub fn validate_data_token_refresh(
 
     refresh_token.browser == browser && refresh_token.os == os && refresh_token.device == device
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_tickets_are_accepted() {
+        let (task_id, session_id) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        let ticket =
+            create_stream_ticket(Some(&task_id), "user", &session_id, Duration::minutes(1))
+                .unwrap();
+        let payload = validate_stream_ticket(&ticket).unwrap();
+        assert_eq!(payload.task_id, Some(task_id));
+        assert_eq!(payload.user_id, "user");
+        assert_eq!(payload.session_id, session_id);
+    }
+
+    #[test]
+    fn access_tokens_are_not_stream_tickets() {
+        let token = create_access_jwt(&uuid::Uuid::new_v4(), "user", Duration::minutes(1)).unwrap();
+        assert!(validate_stream_ticket(&token).is_err());
+    }
+
+    #[test]
+    fn tickets_of_another_type_are_refused() {
+        let claims = serde_json::json!({
+            "typ": "access",
+            "task_id": null,
+            "user_id": "user",
+            "session_id": uuid::Uuid::new_v4(),
+            "exp": (Utc::now() + Duration::minutes(1)).timestamp(),
+        });
+        let secret_key = env::var("SECRET_KEY").unwrap_or_else(|_| "default".to_string());
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret_key.as_bytes()),
+        )
+        .unwrap();
+        assert!(validate_stream_ticket(&token).is_err());
+    }
+}
@@ -0,0 +1,469 @@
+use crate::broker::{
+    BoxError, JobPublisher, ProgressMessage, ProgressStream, ProgressSubscriber, PublishError,
+};
+use crate::contracts::{decode_progress, ContentType, ControlMessage, Envelope, ProgressEvent};
+use crate::memory_broker::{ProgressItem, ProgressLog};
+use crate::metrics::{record_dead_letter, DeadLetterOutcome};
+use crate::structure::analysis_structures::AnalysisKind;
+use crate::utils::{env_or, reconnect_delay};
+use chrono::Utc;
+use log::{error, warn};
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    error::{KafkaError, RDKafkaErrorCode},
+    message::{BorrowedMessage, Header, Headers, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig, Message,
+};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use uuid::Uuid;
+
+const CONTENT_TYPE_HEADER: &str = "Content-Type";
+const ATTEMPT_HEADER: &str = "x-attempt";
+const PRIORITY_HEADER: &str = "x-priority";
+const REASON_HEADER: &str = "x-reason";
+const OFFSET_HEADER: &str = "x-offset";
+const CATCH_UP_POLL: Duration = Duration::from_millis(20);
+
+/// Next offset to read in each partition of the progress topic, as far as this instance got.
+type ReadOffsets = Arc<Mutex<HashMap<i32, i64>>>;
+
+/// Kafka transport: jobs and control commands keyed by task ID, progress read from one topic.
+///
+/// Progress sequences are Kafka offsets plus one, so they are the same on every instance.
+#[derive(Clone)]
+pub struct KafkaBroker {
+    producer: FutureProducer,
+    consumer: Arc<StreamConsumer>,
+    read: ReadOffsets,
+    progress: ProgressLog,
+}
+
+/// Topic consumed by the workers of one analysis kind, e.g. `KAFKA_TEXT_TOPIC`.
+fn job_topic(kind: AnalysisKind) -> String {
+    env_or(
+        &format!("KAFKA_{}_TOPIC", kind.as_str().to_uppercase()),
+        format!("analysis.{}", kind.as_str()),
+    )
+}
+
+fn control_topic() -> String {
+    env_or("KAFKA_CONTROL_TOPIC", "analysis.control".to_string())
+}
+
+fn progress_topic() -> String {
+    env_or("KAFKA_PROGRESS_TOPIC", "analysis.progress".to_string())
+}
+
+/// Topic receiving progress messages that could not be decoded.
+fn progress_dead_letter_topic() -> String {
+    env_or(
+        "KAFKA_PROGRESS_DEAD_LETTER_TOPIC",
+        "analysis.progress.dead_letter".to_string(),
+    )
+}
+
+fn delivery_timeout() -> Duration {
+    Duration::from_secs(env_or("KAFKA_DELIVERY_TIMEOUT_SECS", 5))
+}
+
+/// How long starting a run waits for this instance to read the progress published before it.
+fn catch_up_timeout() -> Duration {
+    Duration::from_millis(env_or("KAFKA_CATCH_UP_TIMEOUT_MILLIS", 5000))
+}
+
+pub fn connect_kafka() -> KafkaBroker {
+    let brokers = env::var("CONNECTION_STRING_KAFKA").expect("Failed to load Kafka config");
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .set("enable.idempotence", "true")
+        .set(
+            "message.timeout.ms",
+            delivery_timeout().as_millis().to_string(),
+        )
+        .create()
+        .expect("Invalid Kafka producer config");
+
+    // A group of its own lets every instance see every progress event, whichever one the
+    // client is connected to. It starts from the oldest retained event, so a restarted instance
+    // rebuilds the log of events younger than `PROGRESS_RETENTION_SECS`.
+    let group = env_or(
+        "KAFKA_PROGRESS_GROUP",
+        format!("external-api-{}", Uuid::new_v4()),
+    );
+    let consumer = Arc::new(progress_consumer(&brokers, &group));
+    // Malformed messages are dead-lettered by one shared group, so each is copied once however
+    // many instances run.
+    let dead_letter_group = env_or(
+        "KAFKA_DEAD_LETTER_GROUP",
+        "external-api-progress-dead-letters".to_string(),
+    );
+    let dead_letter_consumer = progress_consumer(&brokers, &dead_letter_group);
+
+    let progress = ProgressLog::default();
+    let read = ReadOffsets::default();
+    tokio::spawn(consume_progress(
+        consumer.clone(),
+        progress.clone(),
+        read.clone(),
+    ));
+    tokio::spawn(dead_letter_progress(dead_letter_consumer, producer.clone()));
+    KafkaBroker {
+        producer,
+        consumer,
+        read,
+        progress,
+    }
+}
+
+fn progress_consumer(brokers: &str, group: &str) -> StreamConsumer {
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", group)
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .expect("Invalid Kafka consumer config");
+    consumer
+        .subscribe(&[&progress_topic()])
+        .expect("Failed to subscribe to Kafka progress topic");
+    consumer
+}
+
+/// Waits for the next progress message, retrying with backoff while Kafka is unavailable.
+async fn next_message(consumer: &StreamConsumer) -> BorrowedMessage<'_> {
+    let mut attempt = 0;
+    loop {
+        match consumer.recv().await {
+            Ok(message) => return message,
+            Err(e) => {
+                let delay = reconnect_delay(attempt);
+                warn!(
+                    "Failed to receive Kafka progress, retrying in {:?}: {}",
+                    delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Decodes a progress message of the task named by its key.
+fn decode_message(message: &BorrowedMessage<'_>) -> Option<(Uuid, ProgressItem)> {
+    let task_id = message
+        .key()
+        .and_then(|key| std::str::from_utf8(key).ok())
+        .and_then(|key| Uuid::parse_str(key).ok());
+    let Some(task_id) = task_id else {
+        warn!(
+            "Skipping progress message without a task key at offset {}",
+            message.offset()
+        );
+        return None;
+    };
+    let content_type = header_content_type(message.headers());
+    let payload = message.payload().unwrap_or_default();
+    Some((task_id, decode_progress(task_id, payload, content_type)))
+}
+
+/// Whether the message was published longer ago than the log keeps events.
+fn is_expired(message: &BorrowedMessage<'_>, retention: Duration) -> bool {
+    let Some(published_at) = message.timestamp().to_millis() else {
+        return false;
+    };
+    let retention = i64::try_from(retention.as_millis()).unwrap_or(i64::MAX);
+    Utc::now().timestamp_millis().saturating_sub(published_at) > retention
+}
+
+/// Dispatches progress events to the queue of the task named by the message key.
+async fn consume_progress(consumer: Arc<StreamConsumer>, progress: ProgressLog, read: ReadOffsets) {
+    loop {
+        let message = next_message(&consumer).await;
+        if !is_expired(&message, progress.retention()) {
+            if let Some((task_id, event)) = decode_message(&message) {
+                let sequence = u64::try_from(message.offset() + 1).unwrap_or_default();
+                progress.push_at(task_id, sequence, event);
+            }
+        }
+        read.lock()
+            .expect("read offsets lock poisoned")
+            .insert(message.partition(), message.offset() + 1);
+    }
+}
+
+/// The offset after the last message of each non-empty partition of the progress topic.
+fn end_offsets(consumer: &StreamConsumer) -> Result<Vec<(i32, i64)>, KafkaError> {
+    let topic = progress_topic();
+    let timeout = delivery_timeout();
+    let metadata = consumer.fetch_metadata(Some(&topic), timeout)?;
+    let mut ends = Vec::new();
+    for partition in metadata
+        .topics()
+        .iter()
+        .flat_map(|topic| topic.partitions())
+    {
+        let (low, high) = consumer.fetch_watermarks(&topic, partition.id(), timeout)?;
+        if high > low {
+            ends.push((partition.id(), high));
+        }
+    }
+    Ok(ends)
+}
+
+/// Copies progress messages that can't be decoded to the dead-letter topic.
+async fn dead_letter_progress(consumer: StreamConsumer, producer: FutureProducer) {
+    loop {
+        let message = next_message(&consumer).await;
+        if let Some((task_id, Err(reason))) = decode_message(&message) {
+            dead_letter(&producer, &message, &task_id, &reason).await;
+        }
+    }
+}
+
+async fn dead_letter(
+    producer: &FutureProducer,
+    message: &BorrowedMessage<'_>,
+    task_id: &Uuid,
+    reason: &str,
+) {
+    let offset = message.offset().to_string();
+    let headers = message
+        .headers()
+        .map(|headers| headers.detach())
+        .unwrap_or_default()
+        .insert(Header {
+            key: REASON_HEADER,
+            value: Some(reason),
+        })
+        .insert(Header {
+            key: OFFSET_HEADER,
+            value: Some(&offset),
+        });
+    let key = task_id.to_string();
+    let topic = progress_dead_letter_topic();
+    let record = FutureRecord::to(&topic)
+        .key(&key)
+        .payload(message.payload().unwrap_or_default())
+        .headers(headers);
+    match producer.send(record, delivery_timeout()).await {
+        Ok(_) => {
+            record_dead_letter(DeadLetterOutcome::Published);
+            warn!(
+                "Dead-lettered progress message at offset {} of task {}: {}",
+                offset, task_id, reason
+            );
+        }
+        Err((e, _)) => {
+            record_dead_letter(DeadLetterOutcome::Failed);
+            error!(
+                "Failed to dead-letter progress message at offset {} of task {}: {}",
+                offset, task_id, e
+            );
+        }
+    }
+}
+
+fn content_type_headers(content_type: ContentType) -> OwnedHeaders {
+    OwnedHeaders::new().insert(Header {
+        key: CONTENT_TYPE_HEADER,
+        value: Some(content_type.as_str()),
+    })
+}
+
+/// Content type named by the `Content-Type` header, JSON when it is missing.
+fn header_content_type<H: Headers>(headers: Option<&H>) -> ContentType {
+    ContentType::from_header(
+        headers
+            .and_then(|headers| {
+                headers
+                    .iter()
+                    .find(|header| header.key == CONTENT_TYPE_HEADER)
+            })
+            .and_then(|header| header.value)
+            .and_then(|value| std::str::from_utf8(value).ok()),
+    )
+}
+
+impl KafkaBroker {
+    async fn send(
+        &self,
+        topic: &str,
+        task_id: &Uuid,
+        payload: &[u8],
+        headers: OwnedHeaders,
+    ) -> Result<(), PublishError> {
+        let key = task_id.to_string();
+        let record = FutureRecord::to(topic)
+            .key(&key)
+            .payload(payload)
+            .headers(headers);
+        match self.producer.send(record, delivery_timeout()).await {
+            Ok(_) => Ok(()),
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut), _)) => {
+                Err(PublishError::Timeout)
+            }
+            Err((e, _)) => Err(PublishError::Broker(Box::new(e))),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl JobPublisher for KafkaBroker {
+    /// Kafka has no message priorities, so the priority is only passed on as a header.
+    async fn publish_job(
+        &self,
+        task_id: &Uuid,
+        kind: AnalysisKind,
+        content_type: ContentType,
+        payload: &[u8],
+        priority: u8,
+        attempt: u32,
+    ) -> Result<(), PublishError> {
+        let attempt = attempt.to_string();
+        let priority = priority.to_string();
+        let headers = content_type_headers(content_type)
+            .insert(Header {
+                key: ATTEMPT_HEADER,
+                value: Some(&attempt),
+            })
+            .insert(Header {
+                key: PRIORITY_HEADER,
+                value: Some(&priority),
+            });
+        self.send(&job_topic(kind), task_id, payload, headers).await
+    }
+
+    async fn publish_cancel(&self, task_id: &Uuid) -> Result<(), PublishError> {
+        let content_type = ContentType::configured();
+        let payload = Envelope::new(*task_id, ControlMessage::Cancel).encode(content_type);
+        self.send(
+            &control_topic(),
+            task_id,
+            &payload,
+            content_type_headers(content_type),
+        )
+        .await
+    }
+}
+
+#[rocket::async_trait]
+impl ProgressSubscriber for KafkaBroker {
+    async fn subscribe(
+        &self,
+        task_id: Uuid,
+        after: Option<u64>,
+    ) -> Result<ProgressStream, BoxError> {
+        Ok(self.progress.subscribe(task_id, after))
+    }
+
+    /// Only events younger than `PROGRESS_RETENTION_SECS` are known; older ones are in the
+    /// archive.
+    async fn history(&self, task_id: Uuid, after: u64) -> Result<Vec<ProgressMessage>, BoxError> {
+        Ok(self.progress.history(task_id, after))
+    }
+
+    /// Waits until this instance has read everything published so far, so the task's last
+    /// offset it knows is the last one there is.
+    async fn position(&self, task_id: Uuid) -> Result<u64, BoxError> {
+        let consumer = self.consumer.clone();
+        let ends = tokio::task::spawn_blocking(move || end_offsets(&consumer)).await??;
+        let caught_up = || {
+            let read = self.read.lock().expect("read offsets lock poisoned");
+            ends.iter()
+                .all(|(partition, end)| read.get(partition).is_some_and(|next| next >= end))
+        };
+        tokio::time::timeout(catch_up_timeout(), async {
+            while !caught_up() {
+                tokio::time::sleep(CATCH_UP_POLL).await;
+            }
+        })
+        .await
+        .map_err(|_| "timed out reading the progress topic")?;
+        Ok(self.progress.last_sequence(task_id))
+    }
+
+    async fn publish(&self, task_id: Uuid, event: ProgressEvent) -> Result<(), PublishError> {
+        let content_type = ContentType::configured();
+        let payload = Envelope::new(task_id, event).encode(content_type);
+        self.send(
+            &progress_topic(),
+            &task_id,
+            &payload,
+            content_type_headers(content_type),
+        )
+        .await
+    }
+}
+
+#[cfg(all(test, feature = "kafka"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_survives_the_header() {
+        for content_type in [ContentType::Json, ContentType::Protobuf] {
+            let headers = content_type_headers(content_type);
+            assert_eq!(header_content_type(Some(&headers)), content_type);
+        }
+    }
+
+    #[test]
+    fn missing_or_unknown_content_type_is_json() {
+        let unknown = OwnedHeaders::new().insert(Header {
+            key: CONTENT_TYPE_HEADER,
+            value: Some("text/plain"),
+        });
+        let other = OwnedHeaders::new().insert(Header {
+            key: ATTEMPT_HEADER,
+            value: Some("1"),
+        });
+        let empty = OwnedHeaders::new().insert(Header::<&str> {
+            key: CONTENT_TYPE_HEADER,
+            value: None,
+        });
+        for headers in [Some(&unknown), Some(&other), Some(&empty), None] {
+            assert_eq!(header_content_type(headers), ContentType::Json);
+        }
+    }
+
+    /// The only test touching `KAFKA_*_TOPIC`, so no other test sees the overrides.
+    #[test]
+    fn topics_default_per_kind_and_can_be_overridden() {
+        for (kind, variable, default) in [
+            (AnalysisKind::Text, "KAFKA_TEXT_TOPIC", "analysis.text"),
+            (AnalysisKind::Photo, "KAFKA_PHOTO_TOPIC", "analysis.photo"),
+            (
+                AnalysisKind::Reviews,
+                "KAFKA_REVIEWS_TOPIC",
+                "analysis.reviews",
+            ),
+        ] {
+            env::remove_var(variable);
+            assert_eq!(job_topic(kind), default);
+            env::set_var(variable, format!("jobs.{}", kind.as_str()));
+            assert_eq!(job_topic(kind), format!("jobs.{}", kind.as_str()));
+            env::remove_var(variable);
+        }
+        for (variable, topic, default) in [
+            (
+                "KAFKA_CONTROL_TOPIC",
+                control_topic as fn() -> String,
+                "analysis.control",
+            ),
+            ("KAFKA_PROGRESS_TOPIC", progress_topic, "analysis.progress"),
+            (
+                "KAFKA_PROGRESS_DEAD_LETTER_TOPIC",
+                progress_dead_letter_topic,
+                "analysis.progress.dead_letter",
+            ),
+        ] {
+            env::remove_var(variable);
+            assert_eq!(topic(), default);
+        }
+    }
+}
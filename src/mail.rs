@@ -0,0 +1,207 @@
+use crate::broker::BoxError;
+use crate::utils::env_or;
+use chrono::{DateTime, Utc};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use log::info;
+use rocket::{Build, Rocket};
+use std::{env, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use uuid::Uuid;
+
+/// A plain-text email to one recipient.
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers notification emails.
+#[rocket::async_trait]
+pub trait MailSender: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<(), BoxError>;
+}
+
+/// Sends through an SMTP relay, authenticating when `SMTP_USERNAME` is set.
+pub struct SmtpSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpSender {
+    fn from_env() -> Self {
+        let host = env::var("SMTP_HOST").expect("Failed to load SMTP config");
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+            .expect("Invalid SMTP host")
+            .port(env_or("SMTP_PORT", 587));
+        if let Some(username) = env::var("SMTP_USERNAME").ok().filter(|u| !u.is_empty()) {
+            let password = env::var("SMTP_PASSWORD").unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        SmtpSender {
+            transport: builder.build(),
+            from: mail_from(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl MailSender for SmtpSender {
+    async fn send(&self, mail: &Mail) -> Result<(), BoxError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.parse()?)
+            .subject(&mail.subject)
+            .body(mail.body.clone())?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes emails to `MAIL_FILE`, or to the log when it is unset, for development.
+pub struct FileSender {
+    path: Option<PathBuf>,
+}
+
+#[rocket::async_trait]
+impl MailSender for FileSender {
+    async fn send(&self, mail: &Mail) -> Result<(), BoxError> {
+        let Some(path) = &self.path else {
+            info!("Mail to {}: {}\n{}", mail.to, mail.subject, mail.body);
+            return Ok(());
+        };
+        let entry = format!(
+            "To: {}\nSubject: {}\n\n{}\n\n",
+            mail.to, mail.subject, mail.body
+        );
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(entry.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// Tells the owner of a completed task that its analysis can be viewed, linking to it through
+/// `TASK_URL_TEMPLATE`.
+pub fn task_ready_mail(to: &str, name: &str, task_name: &str, task_id: &Uuid) -> Mail {
+    let link = env_or(
+        "TASK_URL_TEMPLATE",
+        "http://localhost:3000/task/{id}".to_string(),
+    )
+    .replace("{id}", &task_id.to_string());
+    Mail {
+        to: to.to_string(),
+        subject: format!("Analysis \"{}\" is ready", task_name),
+        body: format!(
+            "Hello, {}!\n\nThe analysis \"{}\" has finished. See the results at {}",
+            name, task_name, link
+        ),
+    }
+}
+
+pub fn subscription_expiring_mail(to: &str, name: &str, valid_to: DateTime<Utc>) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: "Your subscription is about to end".to_string(),
+        body: format!(
+            "Hello, {}!\n\nYour subscription ends on {}. Renew it to keep running analyses.",
+            name,
+            valid_to.format("%Y-%m-%d %H:%M UTC")
+        ),
+    }
+}
+
+/// Delay before retrying an email that failed `attempt` times, from `MAIL_RETRY_BASE_SECS`.
+pub fn retry_delay(attempt: u32) -> Duration {
+    let base: u64 = env_or("MAIL_RETRY_BASE_SECS", 60);
+    Duration::from_secs(base.saturating_mul(1 << attempt.min(10)))
+}
+
+fn mail_from() -> Mailbox {
+    env_or("MAIL_FROM", "External API <noreply@localhost>".to_string())
+        .parse()
+        .expect("Invalid MAIL_FROM")
+}
+
+/// Reads `MAIL_BACKEND`: `smtp`, `file` (`MAIL_FILE`) or `log` (default).
+pub async fn init_mail(rocket: Rocket<Build>) -> Rocket<Build> {
+    let sender: Arc<dyn MailSender> = match env_or("MAIL_BACKEND", "log".to_string()).as_str() {
+        "smtp" => Arc::new(SmtpSender::from_env()),
+        "file" => Arc::new(FileSender {
+            path: Some(env_or("MAIL_FILE", PathBuf::from("mail.log"))),
+        }),
+        "log" => Arc::new(FileSender { path: None }),
+        other => panic!("Unknown MAIL_BACKEND {}", other),
+    };
+    rocket.manage(sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn task_ready_mail_links_to_the_task() {
+        let task_id = Uuid::new_v4();
+        let mail = task_ready_mail("ann@example.com", "Ann", "Phones", &task_id);
+        assert_eq!(mail.to, "ann@example.com");
+        assert_eq!(mail.subject, "Analysis \"Phones\" is ready");
+        assert!(mail.body.starts_with("Hello, Ann!\n\n"), "{}", mail.body);
+        assert!(
+            mail.body.ends_with(&format!(
+                "See the results at http://localhost:3000/task/{}",
+                task_id
+            )),
+            "{}",
+            mail.body
+        );
+    }
+
+    #[test]
+    fn subscription_expiring_mail_names_the_end_date() {
+        let valid_to = Utc.with_ymd_and_hms(2026, 11, 3, 14, 5, 59).unwrap();
+        let mail = subscription_expiring_mail("ann@example.com", "Ann", valid_to);
+        assert_eq!(mail.to, "ann@example.com");
+        assert_eq!(mail.subject, "Your subscription is about to end");
+        assert_eq!(
+            mail.body,
+            "Hello, Ann!\n\nYour subscription ends on 2026-11-03 14:05 UTC. \
+             Renew it to keep running analyses."
+        );
+    }
+
+    #[test]
+    fn default_sender_address_parses() {
+        let from = mail_from();
+        assert_eq!(from.email.to_string(), "noreply@localhost");
+    }
+
+    #[tokio::test]
+    async fn file_sender_appends_mails() {
+        let path = env::temp_dir().join(format!("mail-{}.log", Uuid::new_v4()));
+        let sender = FileSender {
+            path: Some(path.clone()),
+        };
+        for subject in ["first", "second"] {
+            let mail = Mail {
+                to: "ann@example.com".to_string(),
+                subject: subject.to_string(),
+                body: "body".to_string(),
+            };
+            sender.send(&mail).await.unwrap();
+        }
+        let written = tokio::fs::read_to_string(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(
+            written,
+            "To: ann@example.com\nSubject: first\n\nbody\n\n\
+             To: ann@example.com\nSubject: second\n\nbody\n\n"
+        );
+    }
+}
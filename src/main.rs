@@ -1,27 +1,70 @@
+mod broker;
+mod contracts;
 mod database_function;
+mod diff;
 mod jwt;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod mail;
+mod memory_broker;
+mod metrics;
 mod nats;
+mod parser;
+mod progress;
 mod rabbit;
+mod schedule;
 mod structure;
+mod supervisor;
 mod utils;
+mod webhooks;
+mod websocket;
 
 #[macro_use]
 extern crate rocket;
 
+use broker::{
+    init_broker, photo_analysis_job, reviews_analysis_job, send_task_to_photo_analysis_queue,
+    send_task_to_reviews_analysis_queue, send_task_to_text_analysis_queue, text_analysis_job,
+    JobPublisher, ProgressSubscriber, PublishError,
+};
 use chrono::Duration;
+use contracts::{decode_job, ContentType, ProgressEvent};
+use diff::diff_tasks;
 use dotenvy::dotenv;
+use progress::{ClientEvent, ProgressFeed};
 
 use crate::jwt::{
-    create_access_jwt, create_refresh_jwt, validate_data_token_refresh, validate_refresh_jwt,
+    create_access_jwt, create_refresh_jwt, create_stream_ticket, validate_data_token_refresh,
+    validate_refresh_jwt, validate_stream_ticket,
+};
+use database_function::{
+    cancel_task as cancel_task_db, check_client_session_id, complete_task_if_finished,
+    create_subscribe, create_task as create_task_db, delete_client_session,
+    delete_task as delete_task_db, fail_task, function_mongo::DeadLetter,
+    function_mongo::FIRST_RUN, function_postgre::RunClaim, function_postgre::User,
+    get_account_info, get_all_tasks, get_all_users, get_dead_letters as get_dead_letters_db,
+    get_task_by_id, get_task_product_ids, get_task_progress, get_task_run_by_number,
+    get_task_runs as get_task_runs_db, get_task_status, get_user_session_by_id, init_mongo_pools,
+    init_postgre_pools, is_admin, job_priority, park_dead_letter,
+    rerun_analysis as rerun_analysis_db, restart_task, set_admin, set_photo_analysis,
+    set_review_analysis, set_text_analysis, sub_is_exist, take_dead_letter,
+    update_check_session_time, update_task_name, update_user_session_id, MixMongoAndCustomError,
+    MixPoolError, MixPostgresAndCustomError, Regenerate, Rerun,
+};
+use database_function::{
+    create_schedule as create_schedule_db, delete_schedule as delete_schedule_db,
+    get_schedules as get_schedules_db, get_task_schedule, pause_schedule as pause_schedule_db,
+    resume_schedule as resume_schedule_db, schedule_limit, set_task_tags,
+};
+use database_function::{
+    create_webhook as create_webhook_db, delete_webhook as delete_webhook_db,
+    function_postgre::DeliveryStatus, get_webhook,
+    get_webhook_deliveries as get_webhook_deliveries_db, get_webhooks as get_webhooks_db,
+    log_webhook_attempt,
 };
-use api::parser_integration_service_client::ParserIntegrationServiceClient;
 use database_function::{
-    check_client_session_id, create_subscribe, create_task as create_task_db,
-    delete_client_session, delete_task as delete_task_db, function_postgre::User, get_account_info,
-    get_all_tasks, get_all_users, get_task_by_id, get_user_session_by_id, init_mongo_pools,
-    init_postgre_pools, is_admin, set_admin, set_photo_analysis, set_review_analysis,
-    set_text_analysis, sub_is_exist, update_check_session_time, update_task_name,
-    update_user_session_id, MixMongoAndCustomError, MixPoolError, MixPostgresAndCustomError,
+    function_postgre::NotificationPreferences, get_notification_preferences,
+    update_notification_preferences,
 };
 use futures::StreamExt;
 use rocket::{config::SecretKey, http::CookieJar};
@@ -29,31 +72,37 @@ use rocket::{
     fairing::AdHoc,
     http::{Method, Status},
     request::{FromRequest, Outcome, Request},
-    response::stream::TextStream,
+    response::stream::{Event, EventStream},
     serde::json::Json,
     State,
 };
+use structure::analysis_structures::{
+    parse_task_analysis, AnalysisKind, PhotoAnalysis, ReviewAnalysis, TextAnalysis,
+};
 use structure::receive_structures::{
-    ChangeToAdminData, CreateSubscribe, CreateTask, DeleteTask, EditTask, EditTaskName, GetTask,
-    InformationTask, RefreshTokenStructure, TaskMessage,
+    CancelTask, ChangeToAdminData, CreateSchedule, CreateSubscribe, CreateTask, CreateWebhook,
+    DeleteSchedule, DeleteTask, DeleteWebhook, EditTask, EditTaskName, EditTaskTags, GetTask,
+    HistoryQuery, InformationTask, RefreshTokenStructure, RequeueDeadLetter, RerunAnalysis, Review,
 };
 use structure::send_structures::{
-    ErrorMessage, History, SendAccount, SendMessage, SendUser, Subscribtion, Task, TaskId, Token,
-    Tokens,
+    DeadLetterElement, ErrorMessage, History, ScheduleElement, SendAccount, SendMessage, SendUser,
+    Subscribtion, Task, TaskDiff, TaskId, TaskRuns, Token, Tokens, WebhookDeliveryElement,
+    WebhookElement, WebhookPayload,
 };
 
 use database_function::connection_mongo::Pool as MongoPool;
 use deadpool_postgres::Pool as PostgresPool;
 use log::error;
-use nats::{get_messages_stream, init_connection_to_stream as init_nats_stream, NatsStream};
-use rabbit::{
-    init_rabbit_queues, send_task_to_photo_analysis_queue, send_task_to_reviews_analysis_queue,
-    send_task_to_text_analysis_queue, RabbitChannel,
-};
+use mail::init_mail;
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
+use schedule::Cadence;
 use std::net::{IpAddr, Ipv4Addr};
-use std::str::{from_utf8, FromStr};
+use std::str::FromStr;
+use std::sync::Arc;
+use supervisor::spawn_supervisor;
 use utils::hash_str;
+use webhooks::{WebhookSender, TEST_EVENT};
+use websocket::{LiveConnection, WebSocketKey};
 mod api {
     tonic::include_proto!("api");
 }
@@ -87,6 +136,13 @@ impl<'r> FromRequest<'r> for AuthUser {
     }
 }
 
+fn format_review(review: &Review) -> String {
+    format!(
+        "текст:{};понравилось:{};не понравилось:{};",
+        review.text, review.pros, review.cons
+    )
+}
+
 #[post("/authorization", data = "<data>")]
 async fn authorization(
     pool: &State<PostgresPool>,
@@ -388,13 +444,85 @@ async fn delete_task(
     Ok(Status::Ok)
 }
 
+#[post("/task", data = "<data>")]
+async fn cancel_task(
+    pool: &State<PostgresPool>,
+    data: Json<CancelTask>,
+    user: AuthUser,
+    jobs: &State<Arc<dyn JobPublisher>>,
+    progress: &State<Arc<dyn ProgressSubscriber>>,
+) -> Result<Status, (Status, Json<ErrorMessage>)> {
+    let cancelled = cancel_task_db(pool, &user.user_id, &data.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to cancel task: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't cancel task".to_string(),
+                }),
+            )
+        })?;
+    if !cancelled {
+        // Completed, failed and already cancelled tasks have nothing left to stop.
+        let status = get_task_status(pool, &user.user_id, &data.id)
+            .await
+            .map_err(|e| {
+                error!("Failed to get task status: {}", e);
+                (
+                    Status::InternalServerError,
+                    Json(ErrorMessage {
+                        message: "can't get task status".to_string(),
+                    }),
+                )
+            })?;
+        return Err(match status {
+            Some(status) => (
+                Status::Conflict,
+                Json(ErrorMessage {
+                    message: format!("task is already {}", status.as_str()),
+                }),
+            ),
+            None => (
+                Status::NotFound,
+                Json(ErrorMessage {
+                    message: "task is not exist".to_string(),
+                }),
+            ),
+        });
+    }
+
+    let (workers_result, stream_result) = futures::join!(
+        jobs.publish_cancel(&data.id),
+        progress.publish(data.id, ProgressEvent::Cancelled)
+    );
+    workers_result.map_err(|e| {
+        error!("Failed to send cancel to analysis workers: {}", e);
+        (
+            publish_status(&e),
+            Json(ErrorMessage {
+                message: "can't send cancel to analysis workers".to_string(),
+            }),
+        )
+    })?;
+    stream_result.map_err(|e| {
+        error!("Failed to publish cancel to stream: {}", e);
+        (
+            publish_status(&e),
+            Json(ErrorMessage {
+                message: "can't publish cancel to stream".to_string(),
+            }),
+        )
+    })?;
+    Ok(Status::Ok)
+}
+
 #[post("/task", data = "<data>")]
 async fn create_task(
     pool: &State<PostgresPool>,
     mongo_pool: &State<MongoPool>,
     data: Json<CreateTask>,
     user: AuthUser,
-    channel: &State<RabbitChannel>,
 ) -> Result<(Status, Json<TaskId>), (Status, Json<ErrorMessage>)> {
     if !update_check_session_time(pool, &user.id)
         .await
@@ -431,6 +559,30 @@ async fn create_task(
             }),
         ));
     }
+    let mut text_vec = vec![];
+    let mut photo_vec = vec![];
+    let mut reviews_vec = Vec::new();
+
+    let mut main_reviews = Vec::new();
+    for review in &data.main.reviews {
+        main_reviews.push(format_review(review));
+    }
+    reviews_vec.push(main_reviews);
+
+    text_vec.push(data.main.description.as_str());
+    photo_vec.push(data.main.image_url.as_str());
+
+    for product in &data.products {
+        text_vec.push(product.description.as_str());
+        photo_vec.push(product.image_url.as_str());
+
+        let mut product_reviews = Vec::new();
+        for review in &product.reviews {
+            product_reviews.push(format_review(review));
+        }
+        reviews_vec.push(product_reviews);
+    }
+
     let task_id = create_task_db(
         pool,
         mongo_pool,
@@ -446,6 +598,13 @@ async fn create_task(
             .iter()
             .map(String::as_str)
             .collect::<Vec<&str>>(),
+        |task_id| {
+            vec![
+                text_analysis_job(task_id, FIRST_RUN, text_vec),
+                photo_analysis_job(task_id, FIRST_RUN, photo_vec),
+                reviews_analysis_job(task_id, FIRST_RUN, reviews_vec),
+            ]
+        },
     )
     .await
     .map_err(|e| match e {
@@ -468,74 +627,6 @@ async fn create_task(
             )
         }
     })?;
-    let mut text_vec = vec![];
-    let mut photo_vec = vec![];
-    let mut reviews_vec = Vec::new();
-
-    let mut main_reviews = Vec::new();
-    for review in &data.main.reviews {
-        let review_str = format!(
-            "текст:{};понравилось:{};не понравилось:{};",
-            review.text, review.pros, review.cons
-        );
-        main_reviews.push(review_str);
-    }
-    reviews_vec.push(main_reviews);
-
-    text_vec.push(data.main.description.as_str());
-    photo_vec.push(data.main.image_url.as_str());
-
-    for product in &data.products {
-        text_vec.push(product.description.as_str());
-        photo_vec.push(product.image_url.as_str());
-
-        let mut product_reviews = Vec::new();
-        for review in &product.reviews {
-            let review_str = format!(
-                "текст:{};понравилось:{};не понравилось:{};",
-                review.text, review.pros, review.cons
-            );
-            product_reviews.push(review_str);
-        }
-        reviews_vec.push(product_reviews);
-    }
-
-    let text_future = send_task_to_text_analysis_queue(channel, &task_id, text_vec);
-    let photo_future = send_task_to_photo_analysis_queue(channel, &task_id, photo_vec);
-    let reviews_future = send_task_to_reviews_analysis_queue(channel, &task_id, reviews_vec);
-
-    let (text_result, photo_result, reviews_result) =
-        futures::join!(text_future, photo_future, reviews_future);
-
-    text_result.map_err(|e| {
-        error!("Failed to send task to text analysis queue: {}", e);
-        (
-            Status::InternalServerError,
-            Json(ErrorMessage {
-                message: "can't send task to text analysis queue".to_string(),
-            }),
-        )
-    })?;
-
-    photo_result.map_err(|e| {
-        error!("Failed to send task to photo analysis queue: {}", e);
-        (
-            Status::InternalServerError,
-            Json(ErrorMessage {
-                message: "can't send task to photo analysis queue".to_string(),
-            }),
-        )
-    })?;
-
-    reviews_result.map_err(|e| {
-        error!("Failed to send task to reviews analysis queue: {}", e);
-        (
-            Status::InternalServerError,
-            Json(ErrorMessage {
-                message: "can't send task to reviews analysis queue".to_string(),
-            }),
-        )
-    })?;
     Ok((Status::Created, Json(TaskId { id: task_id })))
 }
 
@@ -545,7 +636,8 @@ async fn edit_task(
     mongo_pool: &State<MongoPool>,
     data: Json<EditTask>,
     user: AuthUser,
-    channel: &State<RabbitChannel>,
+    jobs: &State<Arc<dyn JobPublisher>>,
+    progress: &State<Arc<dyn ProgressSubscriber>>,
 ) -> Result<Status, (Status, Json<ErrorMessage>)> {
     if !update_check_session_time(pool, &user.id)
         .await
@@ -577,7 +669,8 @@ async fn edit_task(
     })? {
         return Ok(Status::PaymentRequired);
     }
-    database_function::regenerate_task(
+    let progress_start = run_position(progress.as_ref(), &data.id).await?;
+    let run = match database_function::regenerate_task(
         pool,
         mongo_pool,
         &data.id,
@@ -592,6 +685,7 @@ async fn edit_task(
             .iter()
             .map(String::as_str)
             .collect::<Vec<&str>>(),
+        progress_start,
     )
     .await
     .map_err(|e| match e {
@@ -613,18 +707,32 @@ async fn edit_task(
                 }),
             )
         }
-    })?;
+    })? {
+        Regenerate::Started(run) => run,
+        Regenerate::NotFound => {
+            return Err((
+                Status::NotFound,
+                Json(ErrorMessage {
+                    message: "task is not exist now".to_string(),
+                }),
+            ))
+        }
+        Regenerate::Running => {
+            return Err((
+                Status::Conflict,
+                Json(ErrorMessage {
+                    message: "task is running".to_string(),
+                }),
+            ))
+        }
+    };
     let mut text_vec = vec![];
     let mut photo_vec = vec![];
     let mut reviews_vec = Vec::new();
 
     let mut main_reviews = Vec::new();
     for review in &data.main.reviews {
-        let review_str = format!(
-            "текст:{};понравилось:{};не понравилось:{};",
-            review.text, review.pros, review.cons
-        );
-        main_reviews.push(review_str);
+        main_reviews.push(format_review(review));
     }
     reviews_vec.push(main_reviews);
 
@@ -637,18 +745,26 @@ async fn edit_task(
 
         let mut product_reviews = Vec::new();
         for review in &product.reviews {
-            let review_str = format!(
-                "текст:{};понравилось:{};не понравилось:{};",
-                review.text, review.pros, review.cons
-            );
-            product_reviews.push(review_str);
+            product_reviews.push(format_review(review));
         }
         reviews_vec.push(product_reviews);
     }
 
-    let text_future = send_task_to_text_analysis_queue(channel, &data.id, text_vec);
-    let photo_future = send_task_to_photo_analysis_queue(channel, &data.id, photo_vec);
-    let reviews_future = send_task_to_reviews_analysis_queue(channel, &data.id, reviews_vec);
+    let priority = job_priority(pool, &user.user_id).await.map_err(|e| {
+        error!("Failed to get job priority: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't get job priority".to_string(),
+            }),
+        )
+    })?;
+    let text_future =
+        send_task_to_text_analysis_queue(jobs.as_ref(), &data.id, run, text_vec, priority);
+    let photo_future =
+        send_task_to_photo_analysis_queue(jobs.as_ref(), &data.id, run, photo_vec, priority);
+    let reviews_future =
+        send_task_to_reviews_analysis_queue(jobs.as_ref(), &data.id, run, reviews_vec, priority);
 
     let (text_result, photo_result, reviews_result) =
         futures::join!(text_future, photo_future, reviews_future);
@@ -656,7 +772,7 @@ async fn edit_task(
     text_result.map_err(|e| {
         error!("Failed to send task to text analysis queue: {}", e);
         (
-            Status::InternalServerError,
+            publish_status(&e),
             Json(ErrorMessage {
                 message: "can't send task to text analysis queue".to_string(),
             }),
@@ -666,7 +782,7 @@ async fn edit_task(
     photo_result.map_err(|e| {
         error!("Failed to send task to photo analysis queue: {}", e);
         (
-            Status::InternalServerError,
+            publish_status(&e),
             Json(ErrorMessage {
                 message: "can't send task to photo analysis queue".to_string(),
             }),
@@ -676,7 +792,7 @@ async fn edit_task(
     reviews_result.map_err(|e| {
         error!("Failed to send task to reviews analysis queue: {}", e);
         (
-            Status::InternalServerError,
+            publish_status(&e),
             Json(ErrorMessage {
                 message: "can't send task to reviews analysis queue".to_string(),
             }),
@@ -685,64 +801,212 @@ async fn edit_task(
     Ok(Status::Created)
 }
 
-#[get("/words/<product_id>")]
-async fn get_words_from_url(
-    product_id: i32,
-    user: AuthUser,
+#[post("/analysis", data = "<data>")]
+async fn rerun_analysis(
     pool: &State<PostgresPool>,
-) -> Result<(Status, Json<Vec<String>>), (Status, Json<ErrorMessage>)> {
+    mongo_pool: &State<MongoPool>,
+    data: Json<RerunAnalysis>,
+    user: AuthUser,
+    jobs: &State<Arc<dyn JobPublisher>>,
+    progress: &State<Arc<dyn ProgressSubscriber>>,
+) -> Result<Status, (Status, Json<ErrorMessage>)> {
+    if !update_check_session_time(pool, &user.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to update session time: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't update session time".to_string(),
+                }),
+            )
+        })?
+    {
+        return Err((
+            Status::Unauthorized,
+            Json(ErrorMessage {
+                message: "invalid refresh token. Your session is not avalable.".to_string(),
+            }),
+        ));
+    }
     if !sub_is_exist(pool, &user.user_id).await.map_err(|e| {
         error!("Failed to check subscription existence: {}", e);
         (
             Status::InternalServerError,
             Json(ErrorMessage {
-                message: "can't create new user".to_string(),
+                message: "can't check subscription".to_string(),
             }),
         )
     })? {
-        return Ok((Status::PaymentRequired, Json(vec![])));
+        return Err((
+            Status::PaymentRequired,
+            Json(ErrorMessage {
+                message: "user is not paid".to_string(),
+            }),
+        ));
     }
-    let mut client =
-        ParserIntegrationServiceClient::connect(std::env::var("URL_INTEGRATION_SERVICE")
-            .unwrap_or("internal_api:50051".to_string()))
-            .await
-            .map_err(|e| {
-                error!("Failed to create parser client: {}", e);
-                (
-                    Status::InternalServerError,
-                    Json(ErrorMessage {
-                        message: "Failed to create parser client".to_string(),
-                    }),
-                )
-            })?;
-    let request = tonic::Request::new(api::ParserQueryRequest {
-        query_id: product_id,
-    });
-
-    let response = client.get_parsed_content(request).await.map_err(|e| {
-        error!("Failed to parse content: {}", e);
+    let kind = AnalysisKind::parse(&data.task_type).ok_or((
+        Status::BadRequest,
+        Json(ErrorMessage {
+            message: "your type is not exist".to_string(),
+        }),
+    ))?;
+    let progress_start = run_position(progress.as_ref(), &data.id).await?;
+    let rerun = rerun_analysis_db(
+        pool,
+        mongo_pool,
+        &user.user_id,
+        &data.id,
+        kind,
+        progress_start,
+    )
+    .await
+    .map_err(|e| match e {
+        MixPoolError::Postgres(e) => {
+            error!("Failed to rerun analysis side postgres: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't rerun analysis side postgres".to_string(),
+                }),
+            )
+        }
+        MixPoolError::Mongo(e) => {
+            error!("Failed to rerun analysis side mongo: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't rerun analysis side mongo".to_string(),
+                }),
+            )
+        }
+    })?;
+    let (inputs, run) = match rerun {
+        Rerun::Started { inputs, run } => (inputs, run),
+        Rerun::NotFound => {
+            return Err((
+                Status::NotFound,
+                Json(ErrorMessage {
+                    message: "task is not exist now".to_string(),
+                }),
+            ))
+        }
+        Rerun::Running => {
+            return Err((
+                Status::Conflict,
+                Json(ErrorMessage {
+                    message: "task is running".to_string(),
+                }),
+            ))
+        }
+        Rerun::NoInputs => {
+            return Err((
+                Status::UnprocessableEntity,
+                Json(ErrorMessage {
+                    message: format!("task has no inputs for {} analysis", kind.as_str()),
+                }),
+            ))
+        }
+    };
+    let priority = job_priority(pool, &user.user_id).await.map_err(|e| {
+        error!("Failed to get job priority: {}", e);
         (
             Status::InternalServerError,
             Json(ErrorMessage {
-                message: "Failed to parse content".to_string(),
+                message: "can't get job priority".to_string(),
             }),
         )
     })?;
 
-    let parsed_terms = response.into_inner().parsed_terms;
-    Ok((Status::Ok, Json(parsed_terms)))
-}
-
-#[post("/task", data = "<task_id>")]
-async fn get_task(
-    task_id: Json<GetTask>,
-    user: AuthUser,
-    pool: &State<MongoPool>,
-) -> Result<(Status, Json<Task>), (Status, Json<ErrorMessage>)> {
-    get_task_by_id(&pool, &user.user_id, task_id.id)
-        .await
-        .map(|task| (Status::Ok, Json(task)))
-        .map_err(|e| match e {
+    let result = match kind {
+        AnalysisKind::Text => {
+            let payload = inputs.text.iter().map(String::as_str).collect();
+            send_task_to_text_analysis_queue(jobs.as_ref(), &data.id, run, payload, priority).await
+        }
+        AnalysisKind::Photo => {
+            let payload = inputs.photo.iter().map(String::as_str).collect();
+            send_task_to_photo_analysis_queue(jobs.as_ref(), &data.id, run, payload, priority).await
+        }
+        AnalysisKind::Reviews => {
+            let payload = inputs
+                .reviews
+                .iter()
+                .map(|reviews| reviews.iter().map(format_review).collect())
+                .collect();
+            send_task_to_reviews_analysis_queue(jobs.as_ref(), &data.id, run, payload, priority)
+                .await
+        }
+    };
+    result.map_err(|e| {
+        error!(
+            "Failed to send task to {} analysis queue: {}",
+            kind.as_str(),
+            e
+        );
+        (
+            publish_status(&e),
+            Json(ErrorMessage {
+                message: format!("can't send task to {} analysis queue", kind.as_str()),
+            }),
+        )
+    })?;
+    Ok(Status::Created)
+}
+
+#[get("/words/<product_id>")]
+async fn get_words_from_url(
+    product_id: i32,
+    user: AuthUser,
+    pool: &State<PostgresPool>,
+) -> Result<(Status, Json<Vec<String>>), (Status, Json<ErrorMessage>)> {
+    if !sub_is_exist(pool, &user.user_id).await.map_err(|e| {
+        error!("Failed to check subscription existence: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't create new user".to_string(),
+            }),
+        )
+    })? {
+        return Ok((Status::PaymentRequired, Json(vec![])));
+    }
+    let mut client = parser::connect().await.map_err(|e| {
+        error!("Failed to create parser client: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "Failed to create parser client".to_string(),
+            }),
+        )
+    })?;
+    let request = tonic::Request::new(api::ParserQueryRequest {
+        query_id: product_id,
+    });
+
+    let response = client.get_parsed_content(request).await.map_err(|e| {
+        error!("Failed to parse content: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "Failed to parse content".to_string(),
+            }),
+        )
+    })?;
+
+    let parsed_terms = response.into_inner().parsed_terms;
+    Ok((Status::Ok, Json(parsed_terms)))
+}
+
+#[post("/task", data = "<task_id>")]
+async fn get_task(
+    task_id: Json<GetTask>,
+    user: AuthUser,
+    pool: &State<MongoPool>,
+) -> Result<(Status, Json<Task>), (Status, Json<ErrorMessage>)> {
+    get_task_by_id(pool, &user.user_id, task_id.id)
+        .await
+        .map(|task| (Status::Ok, Json(task)))
+        .map_err(|e| match e {
             MixMongoAndCustomError::Mongo(e) => {
                 error!("Failed to get task side mongo: {}", e);
                 (
@@ -764,6 +1028,123 @@ async fn get_task(
         })
 }
 
+fn publish_status(e: &PublishError) -> Status {
+    match e {
+        PublishError::Broker(_) => Status::InternalServerError,
+        PublishError::Nack | PublishError::Timeout | PublishError::Unavailable => {
+            Status::ServiceUnavailable
+        }
+    }
+}
+
+/// Refreshes the session a token or ticket was issued in, failing if it has ended.
+async fn check_session(
+    pool: &PostgresPool,
+    session_id: &uuid::Uuid,
+) -> Result<(), (Status, Json<ErrorMessage>)> {
+    let live = update_check_session_time(pool, session_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to update session time: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't update session time".to_string(),
+                }),
+            )
+        })?;
+    if !live {
+        return Err((
+            Status::Unauthorized,
+            Json(ErrorMessage {
+                message: "invalid refresh token. Your session is not avalable.".to_string(),
+            }),
+        ));
+    }
+    Ok(())
+}
+
+/// The progress sequence a new run of the task starts after.
+async fn run_position(
+    progress: &dyn ProgressSubscriber,
+    id: &uuid::Uuid,
+) -> Result<u64, (Status, Json<ErrorMessage>)> {
+    progress.position(*id).await.map_err(|e| {
+        error!("Failed to read progress position: {}", e);
+        (
+            Status::ServiceUnavailable,
+            Json(ErrorMessage {
+                message: "can't read task progress".to_string(),
+            }),
+        )
+    })
+}
+
+fn task_lookup_error(e: MixMongoAndCustomError) -> (Status, Json<ErrorMessage>) {
+    match e {
+        MixMongoAndCustomError::Mongo(e) => {
+            error!("Failed to get task runs side mongo: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get task side mongo".to_string(),
+                }),
+            )
+        }
+        MixMongoAndCustomError::Custom(e) => {
+            error!("Task run does not exist: {}", e);
+            (
+                Status::NotFound,
+                Json(ErrorMessage {
+                    message: "task run is not exist".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+#[get("/task/<id>/runs")]
+async fn get_task_runs(
+    id: uuid::Uuid,
+    user: AuthUser,
+    pool: &State<MongoPool>,
+) -> Result<(Status, Json<TaskRuns>), (Status, Json<ErrorMessage>)> {
+    get_task_runs_db(pool, &user.user_id, id)
+        .await
+        .map(|runs| (Status::Ok, Json(runs)))
+        .map_err(task_lookup_error)
+}
+
+#[get("/task/<id>/runs/<run>")]
+async fn get_task_run(
+    id: uuid::Uuid,
+    run: u32,
+    user: AuthUser,
+    pool: &State<MongoPool>,
+) -> Result<(Status, Json<Task>), (Status, Json<ErrorMessage>)> {
+    get_task_run_by_number(pool, &user.user_id, id, run)
+        .await
+        .map(|task| (Status::Ok, Json(task)))
+        .map_err(task_lookup_error)
+}
+
+#[get("/task/<id>/diff?<from>&<to>")]
+async fn get_task_diff(
+    id: uuid::Uuid,
+    from: u32,
+    to: u32,
+    user: AuthUser,
+    pool: &State<MongoPool>,
+) -> Result<(Status, Json<TaskDiff>), (Status, Json<ErrorMessage>)> {
+    let (from_task, to_task) = futures::join!(
+        get_task_run_by_number(pool, &user.user_id, id, from),
+        get_task_run_by_number(pool, &user.user_id, id, to)
+    );
+    let from_task = from_task.map_err(task_lookup_error)?;
+    let to_task = to_task.map_err(task_lookup_error)?;
+    Ok((Status::Ok, Json(diff_tasks(&from_task, &to_task))))
+}
+
 #[put("/task", data = "<data>")]
 async fn edit_task_name(
     pool: &State<PostgresPool>,
@@ -783,289 +1164,1182 @@ async fn edit_task_name(
     Ok(Status::Accepted)
 }
 
-#[get("/history")]
+#[get("/history?<query..>")]
 async fn get_history(
     user: AuthUser,
     pool: &State<PostgresPool>,
+    query: HistoryQuery,
 ) -> Result<(Status, Json<History>), (Status, Json<ErrorMessage>)> {
-    get_all_tasks(pool, &user.user_id)
+    get_all_tasks(pool, &user.user_id, &query)
         .await
         .map(|history| (Status::Ok, Json(history)))
+        .map_err(|e| match e {
+            MixPostgresAndCustomError::Postgres(e) => {
+                error!("Failed to get history: {}", e);
+                (
+                    Status::InternalServerError,
+                    Json(ErrorMessage {
+                        message: "can't get history".to_string(),
+                    }),
+                )
+            }
+            MixPostgresAndCustomError::Custom(message) => {
+                (Status::BadRequest, Json(ErrorMessage { message }))
+            }
+        })
+}
+
+#[put("/task/tags", data = "<data>")]
+async fn edit_task_tags(
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+    data: Json<EditTaskTags>,
+) -> Result<Status, (Status, Json<ErrorMessage>)> {
+    let max_tags: usize = utils::env_or("TASK_MAX_TAGS", 20);
+    if data.tags.len() > max_tags {
+        return Err((
+            Status::BadRequest,
+            Json(ErrorMessage {
+                message: format!("at most {} tags", max_tags),
+            }),
+        ));
+    }
+    let updated = set_task_tags(pool, &user.user_id, &data.id, &data.tags)
+        .await
         .map_err(|e| {
-            error!("Failed to get history: {}", e);
+            error!("Failed to update task tags: {}", e);
             (
                 Status::InternalServerError,
                 Json(ErrorMessage {
-                    message: "can't get history".to_string(),
+                    message: "can't update task tags".to_string(),
                 }),
             )
-        })
+        })?;
+    if !updated {
+        return Err((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "task is not exist".to_string(),
+            }),
+        ));
+    }
+    Ok(Status::Accepted)
+}
+
+#[get("/account")]
+async fn get_account(
+    user: AuthUser,
+    pool: &State<PostgresPool>,
+) -> Result<Json<SendAccount>, (Status, Json<ErrorMessage>)> {
+    if !update_check_session_time(pool, &user.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to update session time: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't update session time".to_string(),
+                }),
+            )
+        })?
+    {
+        return Err((
+            Status::Unauthorized,
+            Json(ErrorMessage {
+                message: "invalid refresh token. Your session is not avalable.".to_string(),
+            }),
+        ));
+    }
+    Ok(Json(get_account_info(pool, &user.user_id).await.map_err(
+        |e| match e {
+            MixPostgresAndCustomError::Postgres(e) => {
+                error!("Failed to get account side postgres: {}", e);
+                (
+                    Status::InternalServerError,
+                    Json(ErrorMessage {
+                        message: "can't get account side postgres".to_string(),
+                    }),
+                )
+            }
+            MixPostgresAndCustomError::Custom(e) => {
+                error!("Failed to get account side mongo: {}", e);
+                (
+                    Status::InternalServerError,
+                    Json(ErrorMessage {
+                        message: "can't get account side mongo".to_string(),
+                    }),
+                )
+            }
+        },
+    )?))
+}
+
+#[delete("/session", data = "<session_id>")]
+async fn delete_session(
+    session_id: Json<GetTask>,
+    user: AuthUser,
+    pool: &State<PostgresPool>,
+) -> Result<(), (Status, Json<ErrorMessage>)> {
+    if !update_check_session_time(pool, &user.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to update session time: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't update session time".to_string(),
+                }),
+            )
+        })?
+    {
+        return Err((
+            Status::Unauthorized,
+            Json(ErrorMessage {
+                message: "invalid refresh token. Your session is not avalable.".to_string(),
+            }),
+        ));
+    }
+    if user.id == session_id.id {
+        return Err((
+            Status::BadRequest,
+            Json(ErrorMessage {
+                message: "you can't delete your own session".to_string(),
+            }),
+        ));
+    }
+    delete_client_session(pool, session_id.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to delete client session: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get sessions".to_string(),
+                }),
+            )
+        })?;
+    Ok(())
+}
+
+#[post("/exit")]
+async fn exit(
+    user: AuthUser,
+    pool: &State<PostgresPool>,
+    cookies: &CookieJar<'_>,
+) -> Result<(), (Status, Json<ErrorMessage>)> {
+    if !check_client_session_id(pool, &user.id).await.map_err(|e| {
+        error!("Failed to check client session ID: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't update session time".to_string(),
+            }),
+        )
+    })? {
+        return Err((
+            Status::Unauthorized,
+            Json(ErrorMessage {
+                message: "invalid refresh token. Your session is not avalable.".to_string(),
+            }),
+        ));
+    }
+    delete_client_session(pool, user.id).await.map_err(|e| {
+        error!("Failed to delete client session: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't get sessions".to_string(),
+            }),
+        )
+    })?;
+    cookies.remove_private("refresh_token");
+    Ok(())
+}
+
+/// Sequence number of the last progress event a reconnecting `EventSource` received.
+pub struct LastEventId(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = request
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|id| id.trim().parse().ok());
+        Outcome::Success(LastEventId(id))
+    }
+}
+
+fn progress_event(name: &'static str, message: SendMessage, sequence: Option<u64>) -> Event {
+    let event = Event::json(&message).event(name);
+    match sequence {
+        Some(sequence) => event.id(sequence.to_string()),
+        None => event,
+    }
+}
+
+#[get("/task/<id>/stream-ticket")]
+async fn get_stream_ticket(
+    id: uuid::Uuid,
+    user: AuthUser,
+    pool: &State<PostgresPool>,
+) -> Result<Json<Token>, (Status, Json<ErrorMessage>)> {
+    check_session(pool, &user.id).await?;
+    let status = get_task_status(pool, &user.user_id, &id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get task status: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get task status".to_string(),
+                }),
+            )
+        })?;
+    if status.is_none() {
+        return Err((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "task is not exist".to_string(),
+            }),
+        ));
+    }
+    let life_time = Duration::seconds(utils::env_or("STREAM_TICKET_SECS", 60));
+    let token =
+        create_stream_ticket(Some(&id), &user.user_id, &user.id, life_time).map_err(|e| {
+            error!("Failed to create stream ticket: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't create stream ticket".to_string(),
+                }),
+            )
+        })?;
+    Ok(Json(Token { token, life_time }))
+}
+
+#[get("/live-ticket")]
+async fn get_live_ticket(
+    user: AuthUser,
+    pool: &State<PostgresPool>,
+) -> Result<Json<Token>, (Status, Json<ErrorMessage>)> {
+    check_session(pool, &user.id).await?;
+    let life_time = Duration::seconds(utils::env_or("STREAM_TICKET_SECS", 60));
+    let token = create_stream_ticket(None, &user.user_id, &user.id, life_time).map_err(|e| {
+        error!("Failed to create live ticket: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't create live ticket".to_string(),
+            }),
+        )
+    })?;
+    Ok(Json(Token { token, life_time }))
+}
+
+/// Opens a WebSocket for following several of the caller's tasks, authenticated like
+/// `information` but with a ticket from `get_live_ticket`.
+#[get("/live?<ticket>")]
+pub async fn live(
+    key: WebSocketKey,
+    ticket: Option<String>,
+    user: Option<AuthUser>,
+    pool: &State<PostgresPool>,
+    mongo_pool: &State<MongoPool>,
+    progress: &State<Arc<dyn ProgressSubscriber>>,
+) -> Result<LiveConnection, (Status, Json<ErrorMessage>)> {
+    let (user_id, session_id) = match (user, ticket) {
+        (Some(user), _) => (user.user_id, user.id),
+        (None, Some(ticket)) => match validate_stream_ticket(&ticket) {
+            Ok(ticket) if ticket.task_id.is_none() => (ticket.user_id, ticket.session_id),
+            _ => {
+                return Err((
+                    Status::Unauthorized,
+                    Json(ErrorMessage {
+                        message: "invalid live ticket".to_string(),
+                    }),
+                ))
+            }
+        },
+        (None, None) => {
+            return Err((
+                Status::Unauthorized,
+                Json(ErrorMessage {
+                    message: "missing token".to_string(),
+                }),
+            ))
+        }
+    };
+    check_session(pool, &session_id).await?;
+    Ok(LiveConnection::new(
+        key,
+        user_id,
+        pool.inner().clone(),
+        mongo_pool.inner().clone(),
+        progress.inner().clone(),
+    ))
+}
+
+/// A Prometheus scrape carrying `METRICS_TOKEN` as its bearer token.
+pub struct MetricsScrape;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsScrape {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token: String = utils::env_or("METRICS_TOKEN", String::new());
+        if token.is_empty() {
+            return Outcome::Error((Status::NotFound, "Metrics are disabled".to_string()));
+        }
+        if metrics::scrape_allowed(&token, request.headers().get_one("Authorization")) {
+            Outcome::Success(MetricsScrape)
+        } else {
+            Outcome::Error((Status::Unauthorized, "Invalid metrics token".to_string()))
+        }
+    }
+}
+
+/// Counters in the Prometheus text format.
+#[get("/metrics")]
+pub fn get_metrics(_scrape: MetricsScrape) -> String {
+    metrics::render()
+}
+
+/// Subscribes to a task of the caller, authenticated by the `Authorization` header or, for
+/// `EventSource` which can't send headers, by a ticket from `get_stream_ticket`.
+#[get("/information?<id>&<ticket>")]
+pub async fn information(
+    id: String,
+    ticket: Option<String>,
+    user: Option<AuthUser>,
+    last_event_id: LastEventId,
+    pool: &State<PostgresPool>,
+    mongo_pool: &State<MongoPool>,
+    progress: &State<Arc<dyn ProgressSubscriber>>,
+) -> Result<EventStream![], (Status, Json<ErrorMessage>)> {
+    let norm_id = match uuid::Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(e) => {
+            log::error!("Failed to parse UUID: {}", e);
+            return Err((
+                Status::BadRequest,
+                Json(ErrorMessage {
+                    message: "invalid id".to_string(),
+                }),
+            ));
+        }
+    };
+    let (user_id, session_id) = match (user, ticket) {
+        (Some(user), _) => (user.user_id, user.id),
+        (None, Some(ticket)) => match validate_stream_ticket(&ticket) {
+            Ok(ticket) if ticket.task_id == Some(norm_id) => (ticket.user_id, ticket.session_id),
+            _ => {
+                return Err((
+                    Status::Unauthorized,
+                    Json(ErrorMessage {
+                        message: "invalid stream ticket".to_string(),
+                    }),
+                ))
+            }
+        },
+        (None, None) => {
+            return Err((
+                Status::Unauthorized,
+                Json(ErrorMessage {
+                    message: "missing token".to_string(),
+                }),
+            ))
+        }
+    };
+    check_session(pool, &session_id).await?;
+    let task = get_task_progress(pool, &user_id, &norm_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get task status: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get task status".to_string(),
+                }),
+            )
+        })?;
+    let Some(task) = task else {
+        return Err((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "task is not exist".to_string(),
+            }),
+        ));
+    };
+    let feed = ProgressFeed::open(
+        mongo_pool,
+        progress.as_ref(),
+        norm_id,
+        &task,
+        last_event_id.0,
+    )
+    .await
+    .map_err(|e| {
+        error!("Failed to create consumer: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can`t create consumer".to_string(),
+            }),
+        )
+    })?;
+    let heartbeat = std::time::Duration::from_secs(utils::env_or("SSE_HEARTBEAT_SECS", 15));
+
+    Ok(EventStream! {
+        let start = ClientEvent::start();
+        yield progress_event(start.name, start.message, None);
+        let ProgressFeed { mut messages, closing, mut view } = feed;
+        while let Some(message) = messages.next().await {
+            for event in view.events(message.event) {
+                yield progress_event(event.name, event.message, Some(message.sequence));
+                if event.terminal {
+                    return;
+                }
+            }
+        }
+        if let Some(closing) = closing {
+            yield progress_event(closing.name, closing.message, None);
+        }
+    }
+    .heartbeat(heartbeat))
+}
+
+#[post("/task", data = "<data>")]
+pub async fn add_information_by_task(
+    pool: &State<MongoPool>,
+    postgre_pool: &State<PostgresPool>,
+    user: AuthUser,
+    data: Json<InformationTask>,
+) -> Result<Status, (Status, Json<ErrorMessage>)> {
+    let data = data.into_inner();
+    let status = get_task_status(postgre_pool, &user.user_id, &data.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get task status: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get task status".to_string(),
+                }),
+            )
+        })?
+        .ok_or((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "task is not exist now".to_string(),
+            }),
+        ))?;
+    if !status.accepts_results() {
+        log::info!("Ignoring late result for cancelled task {}", data.id);
+        return Err((
+            Status::Conflict,
+            Json(ErrorMessage {
+                message: "task was cancelled".to_string(),
+            }),
+        ));
+    }
+    let kind = AnalysisKind::parse(&data.task_type).ok_or((
+        Status::BadRequest,
+        Json(ErrorMessage {
+            message: "your type is not exist".to_string(),
+        }),
+    ))?;
+    let invalid_result = |e: String| {
+        error!(
+            "Invalid {} analysis for task {}: {}",
+            kind.as_str(),
+            data.id,
+            e
+        );
+        (
+            Status::UnprocessableEntity,
+            Json(ErrorMessage {
+                message: format!("invalid {} analysis: {}", kind.as_str(), e),
+            }),
+        )
+    };
+    let product_ids = get_task_product_ids(pool, &user.user_id, data.id)
+        .await
+        .map_err(|e| {
+            error!("Mongo is not connected. Error {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "internal service is not online please wait".to_string(),
+                }),
+            )
+        })?
+        .ok_or((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "task is not exist now".to_string(),
+            }),
+        ))?;
+    let run = data.run;
+    let result = match kind {
+        AnalysisKind::Photo => {
+            let analysis: PhotoAnalysis =
+                parse_task_analysis(data.message, &product_ids).map_err(invalid_result)?;
+            set_photo_analysis(pool, &user.user_id, data.id, run, &analysis).await
+        }
+        AnalysisKind::Reviews => {
+            let analysis: ReviewAnalysis =
+                parse_task_analysis(data.message, &product_ids).map_err(invalid_result)?;
+            set_review_analysis(pool, &user.user_id, data.id, run, &analysis).await
+        }
+        AnalysisKind::Text => {
+            let analysis: TextAnalysis =
+                parse_task_analysis(data.message, &product_ids).map_err(invalid_result)?;
+            set_text_analysis(pool, &user.user_id, data.id, run, &analysis).await
+        }
+    };
+    let stored = result.map_err(|e| {
+        error!("Mongo is not connected. Error {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "internal service is not online please wait".to_string(),
+            }),
+        )
+    })?;
+    if !stored {
+        log::info!("Ignoring late result of an earlier run of task {}", data.id);
+        return Err((
+            Status::Conflict,
+            Json(ErrorMessage {
+                message: "result is for an earlier run".to_string(),
+            }),
+        ));
+    }
+    complete_task_if_finished(postgre_pool, pool, &user.user_id, data.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to update task status: {:?}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't update task status".to_string(),
+                }),
+            )
+        })?;
+    Ok(Status::Ok)
+}
+
+#[post("/webhook", data = "<data>")]
+async fn create_webhook(
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+    data: Json<CreateWebhook>,
+) -> Result<(Status, Json<WebhookElement>), (Status, Json<ErrorMessage>)> {
+    if let Err(reason) = webhooks::validate_url(&data.url).await {
+        return Err((
+            Status::BadRequest,
+            Json(ErrorMessage {
+                message: format!("invalid url: {}", reason),
+            }),
+        ));
+    }
+    if let Some(task_id) = &data.task_id {
+        let status = get_task_status(pool, &user.user_id, task_id)
+            .await
+            .map_err(|e| {
+                error!("Failed to get task status: {}", e);
+                (
+                    Status::InternalServerError,
+                    Json(ErrorMessage {
+                        message: "can't get task status".to_string(),
+                    }),
+                )
+            })?;
+        if status.is_none() {
+            return Err((
+                Status::NotFound,
+                Json(ErrorMessage {
+                    message: "task is not exist".to_string(),
+                }),
+            ));
+        }
+    }
+    let webhook = create_webhook_db(pool, &user.user_id, data.task_id.as_ref(), &data.url)
+        .await
+        .map_err(|e| {
+            error!("Failed to create webhook: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't create webhook".to_string(),
+                }),
+            )
+        })?;
+    Ok((Status::Created, Json(webhook)))
+}
+
+#[get("/notifications")]
+async fn get_notifications(
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+) -> Result<Json<NotificationPreferences>, (Status, Json<ErrorMessage>)> {
+    let preferences = get_notification_preferences(pool, &user.user_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get notification preferences: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get notification preferences".to_string(),
+                }),
+            )
+        })?;
+    Ok(Json(preferences))
+}
+
+#[put("/notifications", data = "<data>")]
+async fn edit_notifications(
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+    data: Json<NotificationPreferences>,
+) -> Result<Status, (Status, Json<ErrorMessage>)> {
+    update_notification_preferences(pool, &user.user_id, &data)
+        .await
+        .map_err(|e| {
+            error!("Failed to update notification preferences: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't update notification preferences".to_string(),
+                }),
+            )
+        })?;
+    Ok(Status::Accepted)
+}
+
+#[get("/webhooks")]
+async fn get_webhooks(
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+) -> Result<Json<Vec<WebhookElement>>, (Status, Json<ErrorMessage>)> {
+    let webhooks = get_webhooks_db(pool, &user.user_id).await.map_err(|e| {
+        error!("Failed to get webhooks: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't get webhooks".to_string(),
+            }),
+        )
+    })?;
+    Ok(Json(webhooks))
+}
+
+#[get("/webhook/<id>/deliveries")]
+async fn get_webhook_deliveries(
+    id: uuid::Uuid,
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+) -> Result<Json<Vec<WebhookDeliveryElement>>, (Status, Json<ErrorMessage>)> {
+    let limit: i64 = utils::env_or("WEBHOOK_LOG_LIMIT", 100);
+    get_webhook_deliveries_db(pool, &user.user_id, &id, limit)
+        .await
+        .map_err(|e| {
+            error!("Failed to get webhook deliveries: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get webhook deliveries".to_string(),
+                }),
+            )
+        })?
+        .map(Json)
+        .ok_or((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "webhook is not exist".to_string(),
+            }),
+        ))
+}
+
+/// Sends a `webhook.test` payload right away, without retries, and returns the logged outcome.
+#[post("/webhook/<id>/test")]
+async fn test_webhook(
+    id: uuid::Uuid,
+    pool: &State<PostgresPool>,
+    sender: &State<WebhookSender>,
+    user: AuthUser,
+) -> Result<Json<WebhookDeliveryElement>, (Status, Json<ErrorMessage>)> {
+    let webhook = get_webhook(pool, &user.user_id, &id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get webhook: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get webhook".to_string(),
+                }),
+            )
+        })?
+        .ok_or((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "webhook is not exist".to_string(),
+            }),
+        ))?;
+    let payload = WebhookPayload {
+        event: TEST_EVENT,
+        task_id: webhook.task_id,
+        name: None,
+        status: None,
+        failure_reason: None,
+        finished_at: None,
+    };
+    let payload = serde_json::to_string(&payload).map_err(|e| {
+        error!("Failed to serialize webhook payload: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't build test payload".to_string(),
+            }),
+        )
+    })?;
+    let delivery_id = uuid::Uuid::new_v4();
+    let (status, response_status, error) = match sender
+        .send(&delivery_id, &webhook.url, &webhook.secret, &payload)
+        .await
+    {
+        Ok(response_status) => (DeliveryStatus::Delivered, Some(response_status), None),
+        Err(e) => (
+            DeliveryStatus::Failed,
+            e.response_status(),
+            Some(e.to_string()),
+        ),
+    };
+    let delivery = log_webhook_attempt(
+        pool,
+        &delivery_id,
+        &webhook.id,
+        TEST_EVENT,
+        &payload,
+        status,
+        response_status.map(i32::from),
+        error.as_deref(),
+    )
+    .await
+    .map_err(|e| {
+        error!("Failed to log webhook delivery: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't log webhook delivery".to_string(),
+            }),
+        )
+    })?;
+    Ok(Json(delivery))
+}
+
+#[post("/webhook", data = "<data>")]
+async fn delete_webhook(
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+    data: Json<DeleteWebhook>,
+) -> Result<Status, (Status, Json<ErrorMessage>)> {
+    let deleted = delete_webhook_db(pool, &user.user_id, &data.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to delete webhook: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't delete webhook".to_string(),
+                }),
+            )
+        })?;
+    if !deleted {
+        return Err((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "webhook is not exist".to_string(),
+            }),
+        ));
+    }
+    Ok(Status::Ok)
+}
+
+/// Schedules a task to rerun. Runs reuse the products stored with the task; only its keywords
+/// are refreshed, so descriptions, photos, reviews and prices stay as they were.
+#[post("/schedule", data = "<data>")]
+async fn create_schedule(
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+    data: Json<CreateSchedule>,
+) -> Result<(Status, Json<ScheduleElement>), (Status, Json<ErrorMessage>)> {
+    let now = chrono::Utc::now();
+    let next_run_at = Cadence::parse(data.cron.as_deref(), data.interval_secs)
+        .and_then(|cadence| {
+            cadence.validate(now)?;
+            cadence
+                .next_after(now)
+                .ok_or("cron expression never fires".to_string())
+        })
+        .map_err(|message| (Status::BadRequest, Json(ErrorMessage { message })))?;
+    let status = get_task_status(pool, &user.user_id, &data.task_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get task status: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get task status".to_string(),
+                }),
+            )
+        })?;
+    if status.is_none() {
+        return Err((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "task is not exist".to_string(),
+            }),
+        ));
+    }
+    let limit = schedule_limit(pool, &user.user_id).await.map_err(|e| {
+        error!("Failed to get schedule limit: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't get schedule limit".to_string(),
+            }),
+        )
+    })?;
+    if limit == 0 {
+        return Err((
+            Status::PaymentRequired,
+            Json(ErrorMessage {
+                message: "buy subscribtion".to_string(),
+            }),
+        ));
+    }
+    let existing = get_task_schedule(pool, &user.user_id, &data.task_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get task schedule: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't get task schedule".to_string(),
+                }),
+            )
+        })?;
+    if existing.is_some() {
+        return Err((
+            Status::Conflict,
+            Json(ErrorMessage {
+                message: "task is already scheduled".to_string(),
+            }),
+        ));
+    }
+    let schedule = create_schedule_db(
+        pool,
+        &user.user_id,
+        &data.task_id,
+        data.cron.as_deref(),
+        data.interval_secs,
+        next_run_at,
+        limit,
+    )
+    .await
+    .map_err(|e| {
+        error!("Failed to create schedule: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't create schedule".to_string(),
+            }),
+        )
+    })?
+    .ok_or((
+        Status::Forbidden,
+        Json(ErrorMessage {
+            message: format!("your plan allows at most {} scheduled tasks", limit),
+        }),
+    ))?;
+    Ok((Status::Created, Json(schedule)))
+}
+
+#[get("/schedules")]
+async fn get_schedules(
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+) -> Result<Json<Vec<ScheduleElement>>, (Status, Json<ErrorMessage>)> {
+    let schedules = get_schedules_db(pool, &user.user_id).await.map_err(|e| {
+        error!("Failed to get schedules: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't get schedules".to_string(),
+            }),
+        )
+    })?;
+    Ok(Json(schedules))
 }
 
-#[get("/account")]
-async fn get_account(
-    user: AuthUser,
+#[post("/schedule/<id>/pause")]
+async fn pause_schedule(
+    id: uuid::Uuid,
     pool: &State<PostgresPool>,
-) -> Result<Json<SendAccount>, (Status, Json<ErrorMessage>)> {
-    if !update_check_session_time(pool, &user.id)
+    user: AuthUser,
+) -> Result<Json<ScheduleElement>, (Status, Json<ErrorMessage>)> {
+    pause_schedule_db(pool, &user.user_id, &id)
         .await
         .map_err(|e| {
-            error!("Failed to update session time: {}", e);
+            error!("Failed to pause schedule: {}", e);
             (
                 Status::InternalServerError,
                 Json(ErrorMessage {
-                    message: "can't update session time".to_string(),
+                    message: "can't pause schedule".to_string(),
                 }),
             )
         })?
-    {
-        return Err((
-            Status::Unauthorized,
+        .map(Json)
+        .ok_or((
+            Status::NotFound,
             Json(ErrorMessage {
-                message: "invalid refresh token. Your session is not avalable.".to_string(),
+                message: "schedule is not exist".to_string(),
             }),
-        ));
-    }
-    Ok(Json(get_account_info(pool, &user.user_id).await.map_err(
-        |e| match e {
+        ))
+}
+
+#[post("/schedule/<id>/resume")]
+async fn resume_schedule(
+    id: uuid::Uuid,
+    pool: &State<PostgresPool>,
+    user: AuthUser,
+) -> Result<Json<ScheduleElement>, (Status, Json<ErrorMessage>)> {
+    resume_schedule_db(pool, &user.user_id, &id)
+        .await
+        .map_err(|e| match e {
             MixPostgresAndCustomError::Postgres(e) => {
-                error!("Failed to get account side postgres: {}", e);
+                error!("Failed to resume schedule: {}", e);
                 (
                     Status::InternalServerError,
                     Json(ErrorMessage {
-                        message: "can't get account side postgres".to_string(),
+                        message: "can't resume schedule".to_string(),
                     }),
                 )
             }
-            MixPostgresAndCustomError::Custom(e) => {
-                error!("Failed to get account side mongo: {}", e);
-                (
-                    Status::InternalServerError,
-                    Json(ErrorMessage {
-                        message: "can't get account side mongo".to_string(),
-                    }),
-                )
+            MixPostgresAndCustomError::Custom(message) => {
+                (Status::Conflict, Json(ErrorMessage { message }))
             }
-        },
-    )?))
+        })?
+        .map(Json)
+        .ok_or((
+            Status::NotFound,
+            Json(ErrorMessage {
+                message: "schedule is not exist".to_string(),
+            }),
+        ))
 }
 
-#[delete("/session", data = "<session_id>")]
-async fn delete_session(
-    session_id: Json<GetTask>,
-    user: AuthUser,
+#[post("/schedule", data = "<data>")]
+async fn delete_schedule(
     pool: &State<PostgresPool>,
-) -> Result<(), (Status, Json<ErrorMessage>)> {
-    if !update_check_session_time(pool, &user.id)
+    user: AuthUser,
+    data: Json<DeleteSchedule>,
+) -> Result<Status, (Status, Json<ErrorMessage>)> {
+    let deleted = delete_schedule_db(pool, &user.user_id, &data.id)
         .await
         .map_err(|e| {
-            error!("Failed to update session time: {}", e);
+            error!("Failed to delete schedule: {}", e);
             (
                 Status::InternalServerError,
                 Json(ErrorMessage {
-                    message: "can't update session time".to_string(),
+                    message: "can't delete schedule".to_string(),
                 }),
             )
-        })?
-    {
-        return Err((
-            Status::Unauthorized,
-            Json(ErrorMessage {
-                message: "invalid refresh token. Your session is not avalable.".to_string(),
-            }),
-        ));
-    }
-    if user.id == session_id.id {
+        })?;
+    if !deleted {
         return Err((
-            Status::BadRequest,
+            Status::NotFound,
             Json(ErrorMessage {
-                message: "you can't delete your own session".to_string(),
+                message: "schedule is not exist".to_string(),
             }),
         ));
     }
-    delete_client_session(pool, session_id.id)
-        .await
-        .map_err(|e| {
-            error!("Failed to delete client session: {}", e);
-            (
-                Status::InternalServerError,
-                Json(ErrorMessage {
-                    message: "can't get sessions".to_string(),
-                }),
-            )
-        })?;
-    Ok(())
+    Ok(Status::Ok)
 }
 
-#[post("/exit")]
-async fn exit(
-    user: AuthUser,
+#[get("/dead-letters")]
+pub async fn get_dead_letters(
     pool: &State<PostgresPool>,
-    cookies: &CookieJar<'_>,
-) -> Result<(), (Status, Json<ErrorMessage>)> {
-    if !check_client_session_id(pool, &user.id).await.map_err(|e| {
-        error!("Failed to check client session ID: {}", e);
+    mongo_pool: &State<MongoPool>,
+    user: AuthUser,
+) -> Result<Json<Vec<DeadLetterElement>>, (Status, Json<ErrorMessage>)> {
+    let is_admin_result = is_admin(pool, &user.user_id).await.map_err(|e| {
+        error!("Failed to check admin status: {}", e);
         (
             Status::InternalServerError,
             Json(ErrorMessage {
-                message: "can't update session time".to_string(),
+                message: "can't check admin status".to_string(),
             }),
         )
-    })? {
+    })?;
+    if !is_admin_result {
         return Err((
-            Status::Unauthorized,
+            Status::Forbidden,
             Json(ErrorMessage {
-                message: "invalid refresh token. Your session is not avalable.".to_string(),
+                message: "you are not admin".to_string(),
             }),
         ));
     }
-    delete_client_session(pool, user.id).await.map_err(|e| {
-        error!("Failed to delete client session: {}", e);
+    let dead_letters = get_dead_letters_db(mongo_pool).await.map_err(|e| {
+        error!("Failed to get dead letters: {}", e);
         (
             Status::InternalServerError,
             Json(ErrorMessage {
-                message: "can't get sessions".to_string(),
+                message: "can't get dead letters".to_string(),
             }),
         )
     })?;
-    cookies.remove_private("refresh_token");
-    Ok(())
+    Ok(Json(dead_letters))
 }
 
-#[get("/information?<id>")]
-pub async fn information<'a>(
-    id: String,
-    stream: &'a State<NatsStream>,
-) -> Result<TextStream![String], (Status, Json<ErrorMessage>)> {
-    let norm_id = match uuid::Uuid::parse_str(&id) {
-        Ok(id) => id,
-        Err(e) => {
-            log::error!("Failed to parse UUID: {}", e);
-            return Err((
-                Status::BadRequest,
-                Json(ErrorMessage {
-                    message: "invalid id".to_string(),
-                }),
-            ));
-        }
-    };
-    let mut messages = get_messages_stream(stream, norm_id).await.map_err(|e| {
-        error!("Failed to create consumer: {}", e);
+#[post("/dead-letter", data = "<data>")]
+pub async fn requeue_dead_letter(
+    pool: &State<PostgresPool>,
+    mongo_pool: &State<MongoPool>,
+    jobs: &State<Arc<dyn JobPublisher>>,
+    progress: &State<Arc<dyn ProgressSubscriber>>,
+    user: AuthUser,
+    data: Json<RequeueDeadLetter>,
+) -> Result<Status, (Status, Json<ErrorMessage>)> {
+    let is_admin_result = is_admin(pool, &user.user_id).await.map_err(|e| {
+        error!("Failed to check admin status: {}", e);
         (
             Status::InternalServerError,
             Json(ErrorMessage {
-                message: "can`t create consumer".to_string(),
+                message: "can't check admin status".to_string(),
             }),
         )
     })?;
-
-    Ok(TextStream! {
-        yield serde_json::to_string(&SendMessage{ message: "start".to_string(), task_type: "system".to_string() }).unwrap()+"\n\n";
-        let mut text_is_end = false;
-        let mut reviews_is_end = false;
-        let mut photo_is_end = false;
-        while let Some(message) = messages.next().await{
-            let message = match message{
-                Ok(message) => message,
-                Err(e) => {
-                    log::error!("Failed to receive message: {}", e);
-                    continue;
-                }
-            };
-            message.ack().await.unwrap();
-
-            let text = match from_utf8(&message.payload){
-                Ok(s) => s,
-                Err(_) => todo!(),
-            };
-            let nats_message: TaskMessage = match serde_json::from_str(text) {
-                Ok(msg) => msg,
-                Err(e) => {
-                    log::error!("Ошибка парсинга JSON: {}", e);
-                    yield format!("Ошибка: невалидный JSON в сообщении");
-                    continue;
-                }
-            };
-            if nats_message.message == "__end__" {
-                if nats_message.task_type == "text"{
-                    text_is_end = true;
-                }else if nats_message.task_type == "reviews"{
-                    reviews_is_end = true;
-                }else if nats_message.task_type == "photo"{
-                    photo_is_end = true;
-                }
-            }
-            yield serde_json::to_string(&SendMessage{ message: nats_message.message, task_type: nats_message.task_type }).unwrap()+"\n\n";
-            if text_is_end && reviews_is_end && photo_is_end {
-                yield serde_json::to_string(&SendMessage{ message: "done".to_string(), task_type: "system".to_string() }).unwrap()+"\n\n";
-                break;
-            }
-
-        }
-    })
-}
-
-#[post("/task", data = "<data>")]
-pub async fn add_information_by_task<'a>(
-    pool: &'a State<MongoPool>,
-    user: AuthUser,
-    data: Json<InformationTask>,
-) -> Result<Status, (Status, Json<ErrorMessage>)> {
-    if !(data.task_type == "photo" || data.task_type == "reviews" || data.task_type == "text") {
+    if !is_admin_result {
         return Err((
-            Status::BadRequest,
+            Status::Forbidden,
             Json(ErrorMessage {
-                message: "your type is not exist".to_string(),
+                message: "you are not admin".to_string(),
             }),
         ));
     }
-    if data.task_type == "photo" {
-        set_photo_analysis(pool, &user.user_id, data.id, &data.message)
-            .await
-            .map_err(|e| {
-                error!("Mongo is not connected. Error {}", e);
-                (
-                    Status::InternalServerError,
-                    Json(ErrorMessage {
-                        message: "internal service is not online please wait".to_string(),
-                    }),
-                )
-            })?;
-        Ok(Status::Ok)
-    } else if data.task_type == "reviews" {
-        set_review_analysis(pool, &user.user_id, data.id, &data.message)
-            .await
-            .map_err(|e| {
-                error!("Mongo is not connected. Error {}", e);
-                (
-                    Status::InternalServerError,
-                    Json(ErrorMessage {
-                        message: "internal service is not online please wait".to_string(),
-                    }),
-                )
-            })?;
-        Ok(Status::Ok)
-    } else if data.task_type == "text" {
-        set_text_analysis(pool, &user.user_id, data.id, &data.message)
-            .await
-            .map_err(|e| {
-                error!("Mongo is not connected. Error {}", e);
-                (
-                    Status::InternalServerError,
-                    Json(ErrorMessage {
-                        message: "internal service is not online please wait".to_string(),
-                    }),
-                )
-            })?;
-        Ok(Status::Ok)
-    } else {
-        Err((
-            Status::BadRequest,
+    let dead_letter = take_dead_letter(mongo_pool, data.id)
+        .await
+        .map_err(|e| {
+            error!("Failed to take dead letter: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't take dead letter".to_string(),
+                }),
+            )
+        })?
+        .ok_or((
+            Status::NotFound,
             Json(ErrorMessage {
-                message: "your type is not exist".to_string(),
+                message: "dead letter is not exist".to_string(),
             }),
-        ))
+        ))?;
+    let job = match decode_job(dead_letter.payload.as_bytes(), ContentType::Json) {
+        Ok(job) => job,
+        Err(e) => {
+            repark_dead_letter(mongo_pool, dead_letter).await?;
+            return Err((
+                Status::UnprocessableEntity,
+                Json(ErrorMessage {
+                    message: format!("dead letter is not a valid job: {}", e),
+                }),
+            ));
+        }
+    };
+    let task_id = job.correlation_id;
+    let kind = job.body.kind();
+    let progress_start = match run_position(progress.as_ref(), &task_id).await {
+        Ok(progress_start) => progress_start,
+        Err(e) => {
+            repark_dead_letter(mongo_pool, dead_letter).await?;
+            return Err(e);
+        }
+    };
+    let claim = match restart_task(pool, &task_id, kind, progress_start).await {
+        Ok(claim) => claim,
+        Err(e) => {
+            error!("Failed to restart task: {}", e);
+            repark_dead_letter(mongo_pool, dead_letter).await?;
+            return Err((
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't restart task".to_string(),
+                }),
+            ));
+        }
+    };
+    let refused = match claim {
+        RunClaim::Started => None,
+        RunClaim::Refused(status) => {
+            Some((Status::Conflict, format!("task is {}", status.as_str())))
+        }
+        RunClaim::NotFound => Some((Status::NotFound, "task is not exist now".to_string())),
+    };
+    if let Some((status, message)) = refused {
+        repark_dead_letter(mongo_pool, dead_letter).await?;
+        return Err((status, Json(ErrorMessage { message })));
+    }
+    let content_type = ContentType::configured();
+    let published = jobs
+        .publish_job(
+            &task_id,
+            kind,
+            content_type,
+            &job.encode(content_type),
+            dead_letter.priority,
+            0,
+        )
+        .await;
+    if let Err(e) = published {
+        error!("Failed to requeue dead letter: {}", e);
+        if let Err(e) = fail_task(pool, &task_id, JOB_NOT_REQUEUED).await {
+            error!("Failed to fail task {} of unrequeued job: {}", task_id, e);
+        }
+        repark_dead_letter(mongo_pool, dead_letter).await?;
+        return Err((
+            publish_status(&e),
+            Json(ErrorMessage {
+                message: "can't requeue dead letter".to_string(),
+            }),
+        ));
     }
+    Ok(Status::Ok)
+}
+
+const JOB_NOT_REQUEUED: &str = "job could not be requeued";
+
+/// Puts a dead letter taken for requeueing back, so a failed requeue can be retried.
+async fn repark_dead_letter(
+    mongo_pool: &MongoPool,
+    dead_letter: DeadLetter,
+) -> Result<(), (Status, Json<ErrorMessage>)> {
+    park_dead_letter(mongo_pool, dead_letter)
+        .await
+        .map_err(|e| {
+            error!("Failed to park dead letter again: {}", e);
+            (
+                Status::InternalServerError,
+                Json(ErrorMessage {
+                    message: "can't park dead letter".to_string(),
+                }),
+            )
+        })
 }
 
 #[get("/users")]
@@ -1212,17 +2486,23 @@ pub async fn add_subscribe(
     if !is_admin {
         return Ok(Status::Forbidden);
     }
-    create_subscribe(pool, &data.user_id, data.created_at, data.valid_to)
-        .await
-        .map_err(|e| {
-            error!("Failed to create subscription: {}", e);
-            (
-                Status::InternalServerError,
-                Json(ErrorMessage {
-                    message: "can't create new user".to_string(),
-                }),
-            )
-        })?;
+    create_subscribe(
+        pool,
+        &data.user_id,
+        data.created_at,
+        data.valid_to,
+        data.plan,
+    )
+    .await
+    .map_err(|e| {
+        error!("Failed to create subscription: {}", e);
+        (
+            Status::InternalServerError,
+            Json(ErrorMessage {
+                message: "can't create new user".to_string(),
+            }),
+        )
+    })?;
     Ok(Status::Created)
 }
 
@@ -1237,7 +2517,7 @@ async fn rocket() -> _ {
     .unwrap_or(log::LevelFilter::Info);
     femme::with_level(log_level);
     let secret_key = SecretKey::from(
-        &dotenvy::var("SECRET_KEY_COOKIE")
+        dotenvy::var("SECRET_KEY_COOKIE")
             .expect("Failed to load secret key from environment variable")
             .as_bytes(),
     );
@@ -1270,13 +2550,16 @@ async fn rocket() -> _ {
         .attach(AdHoc::on_ignite("MongoDB", |rocket| async move {
             init_mongo_pools(rocket).await
         }))
-        .attach(AdHoc::on_ignite("RabbitMQ", |rocket| async move {
-            init_rabbit_queues(rocket).await
+        .attach(AdHoc::on_ignite("Broker", |rocket| async move {
+            init_broker(rocket).await
         }))
-        .attach(AdHoc::on_ignite("Nats", |rocket| async move {
-            init_nats_stream(rocket).await
+        .attach(AdHoc::on_ignite("Mail", init_mail))
+        .manage(WebhookSender::default())
+        .attach(AdHoc::on_liftoff("Supervisor", |rocket| {
+            Box::pin(async move { spawn_supervisor(rocket) })
         }))
-        .mount("/api/v1", routes![information,])
+        .mount("/", routes![get_metrics])
+        .mount("/api/v1", routes![information, live])
         .mount(
             "/api/v1/auth",
             routes![authorization, registration, exit, refresh],
@@ -1288,14 +2571,44 @@ async fn rocket() -> _ {
                 get_words_from_url,
                 get_history,
                 get_task,
+                get_task_runs,
+                get_task_run,
+                get_task_diff,
+                get_stream_ticket,
+                get_live_ticket,
                 get_account,
-                all_users
+                all_users,
+                get_dead_letters,
+                get_webhooks,
+                get_webhook_deliveries,
+                get_notifications,
+                get_schedules
+            ],
+        )
+        .mount(
+            "/api/v1/create",
+            routes![create_task, create_webhook, test_webhook, create_schedule],
+        )
+        .mount(
+            "/api/v1/edit",
+            routes![
+                edit_task_name,
+                edit_task_tags,
+                change_admin,
+                edit_notifications,
+                pause_schedule,
+                resume_schedule
             ],
         )
-        .mount("/api/v1/create", routes![create_task])
-        .mount("/api/v1/edit", routes![edit_task_name, change_admin])
-        .mount("/api/v1/regenerate", routes![edit_task])
-        .mount("/api/v1/delete", routes![delete_session, delete_task])
+        .mount(
+            "/api/v1/regenerate",
+            routes![edit_task, rerun_analysis, requeue_dead_letter],
+        )
+        .mount(
+            "/api/v1/delete",
+            routes![delete_session, delete_task, delete_webhook, delete_schedule],
+        )
+        .mount("/api/v1/cancel", routes![cancel_task])
         .mount(
             "/api/v1/add",
             routes![add_information_by_task, add_subscribe],
@@ -0,0 +1,716 @@
+use crate::broker::{
+    BoxError, JobPublisher, ProgressMessage, ProgressStream, ProgressSubscriber, PublishError,
+};
+use crate::contracts::{decode_job, AnalysisJob, ContentType, Envelope, ProgressEvent};
+use crate::database_function::{
+    complete_task_if_finished, connection_mongo::Pool as MongoPool, get_task_owner,
+    get_task_product_ids, get_task_status, set_photo_analysis, set_review_analysis,
+    set_text_analysis,
+};
+use crate::structure::analysis_structures::{
+    AnalysisKind, ImageFinding, KeywordScore, PhotoAnalysis, ProductPhotoAnalysis,
+    ProductReviewAnalysis, ProductTextAnalysis, ReviewAnalysis, Sentiment, Severity, TextAnalysis,
+    ANALYSIS_SCHEMA_VERSION,
+};
+use crate::utils::env_or;
+use deadpool_postgres::Pool as PostgresPool;
+use futures::StreamExt;
+use log::{error, info};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+pub type ProgressItem = Result<Envelope<ProgressEvent>, String>;
+
+#[derive(Default)]
+struct Retained {
+    messages: VecDeque<ProgressMessage>,
+    updated_at: Option<Instant>,
+}
+
+/// Retained events of one task; every subscriber reads all of them.
+struct TaskLog {
+    retained: Mutex<Retained>,
+    last_sequence: watch::Sender<u64>,
+}
+
+impl Default for TaskLog {
+    fn default() -> Self {
+        Self {
+            retained: Mutex::default(),
+            last_sequence: watch::Sender::new(0),
+        }
+    }
+}
+
+impl TaskLog {
+    /// Appends an event at the sequence `next` picks, unless that is not past the last one.
+    fn push(&self, event: ProgressItem, capacity: usize, next: impl FnOnce() -> u64) {
+        let mut retained = self.retained.lock().expect("progress log lock poisoned");
+        let sequence = next();
+        if sequence <= *self.last_sequence.borrow() {
+            return;
+        }
+        retained
+            .messages
+            .push_back(ProgressMessage { sequence, event });
+        while retained.messages.len() > capacity {
+            retained.messages.pop_front();
+        }
+        retained.updated_at = Some(Instant::now());
+        self.last_sequence.send_replace(sequence);
+    }
+
+    fn next_after(&self, sequence: u64) -> Option<ProgressMessage> {
+        self.retained
+            .lock()
+            .expect("progress log lock poisoned")
+            .messages
+            .iter()
+            .find(|message| message.sequence > sequence)
+            .cloned()
+    }
+
+    fn snapshot(&self) -> Vec<ProgressMessage> {
+        self.retained
+            .lock()
+            .expect("progress log lock poisoned")
+            .messages
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn updated_at(&self) -> Option<Instant> {
+        self.retained
+            .lock()
+            .expect("progress log lock poisoned")
+            .updated_at
+    }
+
+    fn is_expired(&self, retention: Duration) -> bool {
+        self.updated_at()
+            .is_some_and(|updated_at| updated_at.elapsed() > retention)
+    }
+}
+
+#[derive(Default)]
+struct Tasks {
+    logs: HashMap<Uuid, Arc<TaskLog>>,
+    pruned_at: Option<Instant>,
+}
+
+/// Progress events kept in process memory per task, capped at `PROGRESS_MAX_EVENTS_PER_TASK`
+/// and dropped `PROGRESS_RETENTION_SECS` after the task's last event. At most
+/// `PROGRESS_MAX_TASKS` tasks are kept; the least recently updated one nobody follows makes
+/// room for a new one.
+///
+/// Sequences are shared by all tasks, so a task's sequences keep growing after its log is
+/// dropped and a run can start after [`ProgressLog::last_sequence`].
+#[derive(Clone)]
+pub struct ProgressLog {
+    tasks: Arc<Mutex<Tasks>>,
+    sequence: Arc<AtomicU64>,
+    capacity: usize,
+    max_tasks: usize,
+    retention: Duration,
+}
+
+impl Default for ProgressLog {
+    fn default() -> Self {
+        Self {
+            tasks: Arc::default(),
+            sequence: Arc::default(),
+            capacity: env_or("PROGRESS_MAX_EVENTS_PER_TASK", 10_000),
+            max_tasks: env_or("PROGRESS_MAX_TASKS", 100_000),
+            retention: Duration::from_secs(env_or("PROGRESS_RETENTION_SECS", 86_400)),
+        }
+    }
+}
+
+impl ProgressLog {
+    fn task(&self, task_id: Uuid) -> Arc<TaskLog> {
+        let mut tasks = self.tasks.lock().expect("progress log lock poisoned");
+        let prune_every = self.retention / 10;
+        if tasks
+            .pruned_at
+            .is_none_or(|pruned_at| pruned_at.elapsed() > prune_every)
+        {
+            let retention = self.retention;
+            tasks.logs.retain(|_, log| !log.is_expired(retention));
+            tasks.pruned_at = Some(Instant::now());
+        }
+        if !tasks.logs.contains_key(&task_id) && tasks.logs.len() >= self.max_tasks {
+            let unfollowed = tasks
+                .logs
+                .iter()
+                .filter(|(_, log)| Arc::strong_count(log) == 1)
+                .min_by_key(|(_, log)| log.updated_at())
+                .map(|(id, _)| *id);
+            if let Some(id) = unfollowed {
+                tasks.logs.remove(&id);
+            }
+        }
+        tasks.logs.entry(task_id).or_default().clone()
+    }
+
+    /// How long events are kept after the task's last one.
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    pub fn push(&self, task_id: Uuid, event: ProgressItem) {
+        let sequence = &self.sequence;
+        self.task(task_id).push(event, self.capacity, || {
+            sequence.fetch_add(1, Ordering::Relaxed) + 1
+        });
+    }
+
+    /// Appends an event at a sequence chosen by the broker, e.g. its offset.
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    pub fn push_at(&self, task_id: Uuid, sequence: u64, event: ProgressItem) {
+        self.task(task_id).push(event, self.capacity, || sequence);
+    }
+
+    pub fn history(&self, task_id: Uuid, after: u64) -> Vec<ProgressMessage> {
+        let tasks = self.tasks.lock().expect("progress log lock poisoned");
+        tasks
+            .logs
+            .get(&task_id)
+            .map(|log| log.snapshot())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|message| message.sequence > after)
+            .collect()
+    }
+
+    /// Sequence of the task's latest event, 0 when it has none.
+    pub fn last_sequence(&self, task_id: Uuid) -> u64 {
+        let tasks = self.tasks.lock().expect("progress log lock poisoned");
+        tasks
+            .logs
+            .get(&task_id)
+            .map_or(0, |log| *log.last_sequence.borrow())
+    }
+
+    pub fn subscribe(&self, task_id: Uuid, after: Option<u64>) -> ProgressStream {
+        let task = self.task(task_id);
+        let published = task.last_sequence.subscribe();
+        let cursor = after.unwrap_or(0);
+        futures::stream::unfold(
+            (task, published, cursor),
+            |(task, mut published, cursor)| async move {
+                loop {
+                    if let Some(message) = task.next_after(cursor) {
+                        let cursor = message.sequence;
+                        return Some((message, (task, published, cursor)));
+                    }
+                    published.changed().await.ok()?;
+                }
+            },
+        )
+        .boxed()
+    }
+}
+
+/// An analysis made by the echo worker.
+pub enum EchoAnalysis {
+    Text(TextAnalysis),
+    Photo(PhotoAnalysis),
+    Reviews(ReviewAnalysis),
+}
+
+/// Where the echo worker leaves its analyses, as a worker posting to `/api/v1/add/task` would.
+#[rocket::async_trait]
+pub trait EchoResults: Send + Sync {
+    /// Ids of the task's products, main product first, in the order its jobs list them.
+    async fn product_ids(&self, task_id: Uuid) -> Result<Vec<u64>, BoxError>;
+
+    /// Stores the analysis of the job of run `run` and completes the task once all of its
+    /// analyses are stored.
+    async fn store(
+        &self,
+        task_id: Uuid,
+        run: Option<u32>,
+        analysis: EchoAnalysis,
+    ) -> Result<(), BoxError>;
+}
+
+/// Keeps echoed analyses with the task in MongoDB and its status in Postgres.
+pub struct DatabaseResults {
+    postgre_pool: PostgresPool,
+    mongo_pool: MongoPool,
+}
+
+impl DatabaseResults {
+    pub fn new(postgre_pool: PostgresPool, mongo_pool: MongoPool) -> Self {
+        Self {
+            postgre_pool,
+            mongo_pool,
+        }
+    }
+
+    async fn owner(&self, task_id: Uuid) -> Result<String, BoxError> {
+        get_task_owner(&self.postgre_pool, &task_id)
+            .await?
+            .ok_or_else(|| format!("task {} is not exist", task_id).into())
+    }
+}
+
+#[rocket::async_trait]
+impl EchoResults for DatabaseResults {
+    async fn product_ids(&self, task_id: Uuid) -> Result<Vec<u64>, BoxError> {
+        let user_id = self.owner(task_id).await?;
+        let product_ids = get_task_product_ids(&self.mongo_pool, &user_id, task_id)
+            .await?
+            .ok_or_else(|| format!("task {} has no products", task_id))?;
+        Ok(product_ids)
+    }
+
+    async fn store(
+        &self,
+        task_id: Uuid,
+        run: Option<u32>,
+        analysis: EchoAnalysis,
+    ) -> Result<(), BoxError> {
+        let user_id = self.owner(task_id).await?;
+        let status = get_task_status(&self.postgre_pool, &user_id, &task_id).await?;
+        if !status.is_some_and(|status| status.accepts_results()) {
+            info!("Dropping echoed result of cancelled task {}", task_id);
+            return Ok(());
+        }
+        let mongo_pool = &self.mongo_pool;
+        let stored = match &analysis {
+            EchoAnalysis::Text(analysis) => {
+                set_text_analysis(mongo_pool, &user_id, task_id, run, analysis).await?
+            }
+            EchoAnalysis::Photo(analysis) => {
+                set_photo_analysis(mongo_pool, &user_id, task_id, run, analysis).await?
+            }
+            EchoAnalysis::Reviews(analysis) => {
+                set_review_analysis(mongo_pool, &user_id, task_id, run, analysis).await?
+            }
+        };
+        if !stored {
+            info!(
+                "Dropping echoed result of an earlier run of task {}",
+                task_id
+            );
+            return Ok(());
+        }
+        complete_task_if_finished(&self.postgre_pool, mongo_pool, &user_id, task_id)
+            .await
+            .map_err(|e| format!("can't complete task {}: {:?}", task_id, e))?;
+        Ok(())
+    }
+}
+
+/// A schema v1 analysis of the job's items, one per product.
+fn echo_analysis(kind: AnalysisKind, product_ids: &[u64], items: &[String]) -> EchoAnalysis {
+    let products = product_ids.iter().copied().zip(items);
+    match kind {
+        AnalysisKind::Text => EchoAnalysis::Text(TextAnalysis {
+            version: ANALYSIS_SCHEMA_VERSION,
+            products: products
+                .map(|(product_id, text)| ProductTextAnalysis {
+                    product_id,
+                    keywords: text
+                        .split_whitespace()
+                        .take(5)
+                        .map(|word| KeywordScore {
+                            word: word.to_string(),
+                            score: 0.5,
+                        })
+                        .collect(),
+                    summary: text.clone(),
+                })
+                .collect(),
+        }),
+        AnalysisKind::Photo => EchoAnalysis::Photo(PhotoAnalysis {
+            version: ANALYSIS_SCHEMA_VERSION,
+            products: products
+                .map(|(product_id, url)| ProductPhotoAnalysis {
+                    product_id,
+                    quality_score: if url.is_empty() { 0.0 } else { 1.0 },
+                    findings: if url.is_empty() {
+                        vec![ImageFinding {
+                            issue: "no photo".to_string(),
+                            severity: Severity::High,
+                        }]
+                    } else {
+                        vec![]
+                    },
+                })
+                .collect(),
+        }),
+        AnalysisKind::Reviews => EchoAnalysis::Reviews(ReviewAnalysis {
+            version: ANALYSIS_SCHEMA_VERSION,
+            products: products
+                .map(|(product_id, _)| ProductReviewAnalysis {
+                    product_id,
+                    sentiment: Sentiment::Neutral,
+                    sentiment_score: 0.0,
+                    themes: vec![],
+                })
+                .collect(),
+        }),
+    }
+}
+
+/// Broker kept in process memory, for running the server without RabbitMQ and NATS.
+///
+/// Published jobs are answered by an echo worker: every input item comes back as a progress
+/// chunk, then a v1 analysis of the items is stored and the job's kind ends.
+#[derive(Clone, Default)]
+pub struct MemoryBroker {
+    progress: ProgressLog,
+    results: Option<Arc<dyn EchoResults>>,
+}
+
+impl MemoryBroker {
+    pub fn with_results(results: Arc<dyn EchoResults>) -> Self {
+        Self {
+            results: Some(results),
+            ..Self::default()
+        }
+    }
+
+    fn emit(&self, task_id: Uuid, event: ProgressEvent) {
+        self.progress
+            .push(task_id, Ok(Envelope::new(task_id, event)));
+    }
+
+    async fn store(
+        &self,
+        results: &dyn EchoResults,
+        task_id: Uuid,
+        run: Option<u32>,
+        kind: AnalysisKind,
+        items: &[String],
+    ) -> Result<(), BoxError> {
+        let product_ids = results.product_ids(task_id).await?;
+        results
+            .store(task_id, run, echo_analysis(kind, &product_ids, items))
+            .await
+    }
+}
+
+#[rocket::async_trait]
+impl JobPublisher for MemoryBroker {
+    async fn publish_job(
+        &self,
+        task_id: &Uuid,
+        _kind: AnalysisKind,
+        content_type: ContentType,
+        payload: &[u8],
+        _priority: u8,
+        _attempt: u32,
+    ) -> Result<(), PublishError> {
+        let job = decode_job(payload, content_type).map_err(|e| PublishError::Broker(e.into()))?;
+        let task_id = *task_id;
+        let kind = job.body.kind();
+        let run = job.run;
+        let chunks: Vec<String> = match job.body {
+            AnalysisJob::Text(items) | AnalysisJob::Photo(items) => items,
+            AnalysisJob::Reviews(batches) => batches.into_iter().map(|b| b.join("\n")).collect(),
+        };
+        info!(
+            "Echoing {} {} items of task {}",
+            chunks.len(),
+            kind.as_str(),
+            task_id
+        );
+        for text in &chunks {
+            let text = text.clone();
+            self.emit(task_id, ProgressEvent::Chunk { kind, text });
+        }
+        if let Some(results) = &self.results {
+            if let Err(e) = self
+                .store(results.as_ref(), task_id, run, kind, &chunks)
+                .await
+            {
+                error!(
+                    "Failed to store echoed {} analysis of task {}: {}",
+                    kind.as_str(),
+                    task_id,
+                    e
+                );
+                let reason = format!("can't store {} analysis", kind.as_str());
+                self.emit(task_id, ProgressEvent::Failed { reason });
+                return Ok(());
+            }
+        }
+        self.emit(task_id, ProgressEvent::End { kind });
+        Ok(())
+    }
+
+    async fn publish_cancel(&self, task_id: &Uuid) -> Result<(), PublishError> {
+        info!("Task {} cancelled, nothing to stop in memory", task_id);
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl ProgressSubscriber for MemoryBroker {
+    async fn subscribe(
+        &self,
+        task_id: Uuid,
+        after: Option<u64>,
+    ) -> Result<ProgressStream, BoxError> {
+        Ok(self.progress.subscribe(task_id, after))
+    }
+
+    async fn history(&self, task_id: Uuid, after: u64) -> Result<Vec<ProgressMessage>, BoxError> {
+        Ok(self.progress.history(task_id, after))
+    }
+
+    async fn position(&self, task_id: Uuid) -> Result<u64, BoxError> {
+        Ok(self.progress.last_sequence(task_id))
+    }
+
+    async fn publish(&self, task_id: Uuid, event: ProgressEvent) -> Result<(), PublishError> {
+        self.emit(task_id, event);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::{photo_analysis_job, reviews_analysis_job, text_analysis_job};
+    use crate::progress::ProgressView;
+    use crate::structure::analysis_structures::ValidateAnalysis;
+
+    /// Stores analyses like `DatabaseResults`, completing the task once all three are in.
+    #[derive(Default)]
+    struct StoredResults {
+        analyses: Mutex<HashMap<&'static str, Result<(), String>>>,
+        completed: Mutex<bool>,
+    }
+
+    #[rocket::async_trait]
+    impl EchoResults for StoredResults {
+        async fn product_ids(&self, _task_id: Uuid) -> Result<Vec<u64>, BoxError> {
+            Ok(vec![11, 22])
+        }
+
+        async fn store(
+            &self,
+            _task_id: Uuid,
+            _run: Option<u32>,
+            analysis: EchoAnalysis,
+        ) -> Result<(), BoxError> {
+            let (kind, valid) = match &analysis {
+                EchoAnalysis::Text(analysis) => ("text", analysis.validate()),
+                EchoAnalysis::Photo(analysis) => ("photo", analysis.validate()),
+                EchoAnalysis::Reviews(analysis) => ("reviews", analysis.validate()),
+            };
+            let mut analyses = self.analyses.lock().unwrap();
+            analyses.insert(kind, valid);
+            *self.completed.lock().unwrap() = analyses.len() == AnalysisKind::ALL.len();
+            Ok(())
+        }
+    }
+
+    fn run(broker: &MemoryBroker, task_id: Uuid, kinds: &[AnalysisKind], text: &str) {
+        for &kind in kinds {
+            let text = text.to_string();
+            broker.emit(task_id, ProgressEvent::Chunk { kind, text });
+            broker.emit(task_id, ProgressEvent::End { kind });
+        }
+    }
+
+    /// Follows the feed until the view ends it, returning the chunks and the terminal event.
+    async fn follow(mut messages: ProgressStream, mut view: ProgressView) -> (Vec<String>, String) {
+        let mut chunks = Vec::new();
+        while let Some(message) = messages.next().await {
+            for event in view.events(message.event) {
+                if event.terminal {
+                    return (chunks, event.message.message);
+                }
+                if event.name == "progress" {
+                    chunks.push(event.message.message);
+                }
+            }
+        }
+        panic!("progress ended without a terminal event");
+    }
+
+    #[tokio::test]
+    async fn created_task_is_analysed_to_completion() {
+        let results = Arc::new(StoredResults::default());
+        let broker = MemoryBroker::with_results(results.clone());
+        let task_id = Uuid::new_v4();
+        let messages = broker
+            .subscribe(task_id, Some(broker.position(task_id).await.unwrap()))
+            .await
+            .unwrap();
+        let feed = tokio::spawn(follow(messages, ProgressView::default()));
+
+        let reviews = vec![vec!["good".to_string()], vec![]];
+        for job in [
+            text_analysis_job(&task_id, 1, vec!["main product", "competitor"]),
+            photo_analysis_job(&task_id, 1, vec!["https://example.com/main.jpg", ""]),
+            reviews_analysis_job(&task_id, 1, reviews),
+        ] {
+            let content_type = ContentType::Json;
+            let payload = job.encode(content_type);
+            broker
+                .publish_job(&task_id, job.body.kind(), content_type, &payload, 0, 0)
+                .await
+                .unwrap();
+        }
+
+        let (chunks, end) = tokio::time::timeout(Duration::from_secs(5), feed)
+            .await
+            .expect("task was not streamed to completion")
+            .unwrap();
+        assert_eq!(chunks.len(), 6);
+        assert_eq!(end, "done");
+        let analyses = results.analyses.lock().unwrap();
+        for kind in AnalysisKind::ALL {
+            assert_eq!(
+                analyses.get(kind.as_str()),
+                Some(&Ok(())),
+                "{}",
+                kind.as_str()
+            );
+        }
+        assert!(*results.completed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn cancellation_ends_the_stream() {
+        let broker = MemoryBroker::default();
+        let task_id = Uuid::new_v4();
+        let start = broker.position(task_id).await.unwrap();
+        let messages = broker.subscribe(task_id, Some(start)).await.unwrap();
+        let feed = tokio::spawn(follow(messages, ProgressView::default()));
+        let kind = AnalysisKind::Text;
+        broker.emit(
+            task_id,
+            ProgressEvent::Chunk {
+                kind,
+                text: "before".to_string(),
+            },
+        );
+        broker.emit(task_id, ProgressEvent::Cancelled);
+        broker.emit(
+            task_id,
+            ProgressEvent::Chunk {
+                kind,
+                text: "late".to_string(),
+            },
+        );
+
+        let (chunks, end) = tokio::time::timeout(Duration::from_secs(5), feed)
+            .await
+            .expect("cancelled run was not ended")
+            .unwrap();
+        assert_eq!(chunks, ["before"]);
+        assert_eq!(end, "cancelled");
+    }
+
+    #[tokio::test]
+    async fn second_run_is_streamed_to_completion() {
+        let broker = MemoryBroker::default();
+        let task_id = Uuid::new_v4();
+        run(&broker, task_id, &AnalysisKind::ALL, "first");
+        broker.emit(task_id, ProgressEvent::Cancelled);
+
+        let start = broker.position(task_id).await.unwrap();
+        let messages = broker.subscribe(task_id, Some(start)).await.unwrap();
+        let feed = tokio::spawn(follow(messages, ProgressView::default()));
+        run(&broker, task_id, &AnalysisKind::ALL, "second");
+
+        let (chunks, end) = tokio::time::timeout(Duration::from_secs(5), feed)
+            .await
+            .expect("second run was not streamed to completion")
+            .unwrap();
+        assert_eq!(chunks, ["second", "second", "second"]);
+        assert_eq!(end, "done");
+    }
+
+    #[tokio::test]
+    async fn rerun_of_one_analysis_is_streamed_to_completion() {
+        let broker = MemoryBroker::default();
+        let task_id = Uuid::new_v4();
+        run(&broker, task_id, &AnalysisKind::ALL, "first");
+
+        let start = broker.position(task_id).await.unwrap();
+        run(&broker, task_id, &[AnalysisKind::Photo], "rerun");
+        let messages = broker.subscribe(task_id, Some(start)).await.unwrap();
+        let view = ProgressView::expecting(&[AnalysisKind::Photo]);
+
+        let (chunks, end) = tokio::time::timeout(Duration::from_secs(5), follow(messages, view))
+            .await
+            .expect("rerun was not streamed to completion");
+        assert_eq!(chunks, ["rerun"]);
+        assert_eq!(end, "done");
+    }
+
+    #[tokio::test]
+    async fn subscription_resumes_after_the_last_event_id() {
+        let broker = MemoryBroker::default();
+        let task_id = Uuid::new_v4();
+        for text in ["one", "two", "three"] {
+            let kind = AnalysisKind::Text;
+            let text = text.to_string();
+            broker
+                .publish(task_id, ProgressEvent::Chunk { kind, text })
+                .await
+                .unwrap();
+        }
+        let history = broker.history(task_id, 0).await.unwrap();
+        assert_eq!(history.len(), 3);
+        let last_event_id = history[1].sequence;
+
+        let mut messages = broker
+            .subscribe(task_id, Some(last_event_id))
+            .await
+            .unwrap();
+        let kind = AnalysisKind::Text;
+        broker
+            .publish(task_id, ProgressEvent::End { kind })
+            .await
+            .unwrap();
+        let mut resumed = Vec::new();
+        for _ in 0..2 {
+            let message = tokio::time::timeout(Duration::from_secs(5), messages.next())
+                .await
+                .expect("resumed feed stalled")
+                .unwrap();
+            resumed.push((message.sequence, message.event.unwrap().body));
+        }
+        assert_eq!(resumed[0].0, history[2].sequence);
+        assert!(matches!(&resumed[0].1, ProgressEvent::Chunk { text, .. } if text == "three"));
+        assert!(resumed[1].0 > resumed[0].0);
+        assert!(matches!(resumed[1].1, ProgressEvent::End { .. }));
+    }
+
+    #[test]
+    fn sequences_keep_growing_after_a_task_log_is_dropped() {
+        let log = ProgressLog {
+            retention: Duration::ZERO,
+            ..ProgressLog::default()
+        };
+        let task_id = Uuid::new_v4();
+        log.push(task_id, Err("first".to_string()));
+        let start = log.last_sequence(task_id);
+        std::thread::sleep(Duration::from_millis(5));
+        log.push(Uuid::new_v4(), Err("other".to_string()));
+        log.push(task_id, Err("second".to_string()));
+
+        let history = log.history(task_id, start);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].event.as_ref().err().unwrap(), "second");
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A count that only goes up, exposed as a Prometheus counter on `/metrics`.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn increment(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub static PROGRESS_DECODE_FAILURES: Counter = Counter::new(
+    "progress_decode_failures_total",
+    "Progress messages from workers that could not be decoded.",
+);
+
+pub static PROGRESS_DEAD_LETTERS: Counter = Counter::new(
+    "progress_dead_letters_total",
+    "Malformed progress messages published to the dead-letter subject.",
+);
+
+pub static PROGRESS_DEAD_LETTER_ERRORS: Counter = Counter::new(
+    "progress_dead_letter_errors_total",
+    "Malformed progress messages that could not be dead-lettered.",
+);
+
+static COUNTERS: [&Counter; 3] = [
+    &PROGRESS_DECODE_FAILURES,
+    &PROGRESS_DEAD_LETTERS,
+    &PROGRESS_DEAD_LETTER_ERRORS,
+];
+
+/// What became of a progress message that could not be decoded.
+pub enum DeadLetterOutcome {
+    /// Copied to the dead letters.
+    Published,
+    /// Already copied when another viewer came across it.
+    Duplicate,
+    /// Could not be copied.
+    Failed,
+}
+
+/// Counts a malformed progress message once, however many viewers came across it.
+pub fn record_dead_letter(outcome: DeadLetterOutcome) {
+    match outcome {
+        DeadLetterOutcome::Duplicate => {}
+        DeadLetterOutcome::Published => {
+            PROGRESS_DECODE_FAILURES.increment();
+            PROGRESS_DEAD_LETTERS.increment();
+        }
+        DeadLetterOutcome::Failed => {
+            PROGRESS_DECODE_FAILURES.increment();
+            PROGRESS_DEAD_LETTER_ERRORS.increment();
+        }
+    }
+}
+
+/// Whether the `Authorization` header of a scrape carries `METRICS_TOKEN`. Without a token the
+/// metrics are not served at all.
+pub fn scrape_allowed(token: &str, authorization: Option<&str>) -> bool {
+    let Some(given) = authorization.and_then(|header| header.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    // Compares every byte so the time taken doesn't tell how much of the token matched.
+    !token.is_empty()
+        && given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Renders every counter in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut text = String::new();
+    for counter in COUNTERS {
+        let _ = writeln!(text, "# HELP {} {}", counter.name, counter.help);
+        let _ = writeln!(text, "# TYPE {} counter", counter.name);
+        let _ = writeln!(
+            text,
+            "{} {}",
+            counter.name,
+            counter.value.load(Ordering::Relaxed)
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(name: &str) -> u64 {
+        render()
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| panic!("{} is not rendered", name))
+    }
+
+    #[test]
+    fn dead_letters_are_counted_once() {
+        let failures = rendered("progress_decode_failures_total");
+        let dead_letters = rendered("progress_dead_letters_total");
+        let errors = rendered("progress_dead_letter_errors_total");
+
+        record_dead_letter(DeadLetterOutcome::Published);
+        record_dead_letter(DeadLetterOutcome::Duplicate);
+        record_dead_letter(DeadLetterOutcome::Duplicate);
+        record_dead_letter(DeadLetterOutcome::Failed);
+
+        assert_eq!(rendered("progress_decode_failures_total"), failures + 2);
+        assert_eq!(rendered("progress_dead_letters_total"), dead_letters + 1);
+        assert_eq!(rendered("progress_dead_letter_errors_total"), errors + 1);
+    }
+
+    #[test]
+    fn scrapes_need_the_token() {
+        for (token, authorization, allowed) in [
+            ("secret", Some("Bearer secret"), true),
+            ("secret", Some("Bearer secreT"), false),
+            ("secret", Some("Bearer secret2"), false),
+            ("secret", Some("secret"), false),
+            ("secret", None, false),
+            ("", Some("Bearer "), false),
+            ("", None, false),
+        ] {
+            assert_eq!(
+                scrape_allowed(token, authorization),
+                allowed,
+                "token {:?}, header {:?}",
+                token,
+                authorization
+            );
+        }
+    }
+
+    #[test]
+    fn counters_are_rendered_for_prometheus() {
+        let text = render();
+        assert!(text.contains("# HELP progress_dead_letters_total Malformed progress messages"));
+        assert!(text.contains("# TYPE progress_dead_letters_total counter\n"));
+        assert_eq!(text.lines().count(), COUNTERS.len() * 3);
+    }
+}
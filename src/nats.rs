@@ -1,62 +1,374 @@
+use crate::broker::{BoxError, ProgressMessage, ProgressStream, ProgressSubscriber, PublishError};
+use crate::contracts::{decode_progress, ContentType, Envelope, ProgressEvent};
+use crate::metrics::{record_dead_letter, DeadLetterOutcome};
+use crate::utils::{env_or, reconnect_delay};
 use async_nats::{
+    header::NATS_MESSAGE_ID,
     jetstream::{
         self,
         consumer::{
-            pull::{Config as pullConfig, Stream as pullStream},
-            PullConsumer,
+            pull::{Ordered, OrderedConfig},
+            DeliverPolicy,
         },
-        stream::{Config, Stream},
+        message::Message,
+        publish::PublishAck,
+        stream::{Config, RetentionPolicy, Stream},
     },
     Error,
 };
-use rocket::{Build, Rocket, State};
-use std::env;
+use futures::StreamExt;
+use log::{error, info, warn};
+use std::{
+    env,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+type NatsContext = jetstream::Context;
+
+const CONTENT_TYPE_HEADER: &str = "Content-Type";
+const REASON_HEADER: &str = "x-reason";
+const SEQUENCE_HEADER: &str = "x-stream-sequence";
+const HISTORY_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub type NatsStream = Stream;
+/// Shared handle to the JetStream stream, re-declared every time the client reconnects.
+#[derive(Clone, Default)]
+pub struct NatsStream(Arc<RwLock<Option<Stream>>>);
+
+impl NatsStream {
+    pub fn get(&self) -> Option<Stream> {
+        self.0.read().expect("NATS stream lock poisoned").clone()
+    }
+
+    fn set(&self, stream: Stream) {
+        *self.0.write().expect("NATS stream lock poisoned") = Some(stream);
+    }
+}
 
-#[derive(serde::Serialize)]
-pub struct Message {
-    pub task_type: String,
-    pub payload: String,
-    pub task_id: String,
+/// Progress events published to and consumed from the JetStream stream.
+#[derive(Clone)]
+pub struct NatsProgress {
+    context: NatsContext,
+    stream: NatsStream,
 }
 
-pub async fn init_connection_to_stream(rocket: Rocket<Build>) -> Rocket<Build> {
+pub async fn connect_nats() -> NatsProgress {
     let connection_string = env::var("CONNECTION_STRING_NATS").expect("Failed to load NATS config");
-    let stream_name = crate::STREAM_NAME;
-    let client = async_nats::connect(connection_string)
+    let reconnected = Arc::new(Notify::new());
+    let notify = reconnected.clone();
+    let client = async_nats::ConnectOptions::new()
+        .retry_on_initial_connect()
+        .event_callback(move |event| {
+            let notify = notify.clone();
+            async move {
+                match event {
+                    async_nats::Event::Connected => {
+                        info!("Connected to NATS");
+                        notify.notify_one();
+                    }
+                    async_nats::Event::Disconnected => warn!("Disconnected from NATS"),
+                    _ => {}
+                }
+            }
+        })
+        .connect(connection_string)
         .await
-        .expect("Can`t connect to NATS server");
+        .expect("Invalid NATS connection options");
 
     let jetstream = jetstream::new(client);
-    let stream = jetstream
-        .get_or_create_stream(Config {
-            name: stream_name.to_string().to_uppercase(),
-            subjects: vec![format!("{}.>", stream_name).into()],
-            retention: jetstream::stream::RetentionPolicy::WorkQueue,
+    let stream = NatsStream::default();
+    tokio::spawn(keep_stream(jetstream.clone(), stream.clone(), reconnected));
+    NatsProgress {
+        context: jetstream,
+        stream,
+    }
+}
+
+/// Progress is kept under limits retention so every viewer reads all events, capped per task
+/// by `PROGRESS_MAX_EVENTS_PER_TASK` and in age by `PROGRESS_RETENTION_SECS`.
+fn stream_config() -> Config {
+    let stream_name = crate::STREAM_NAME;
+    Config {
+        name: stream_name.to_string().to_uppercase(),
+        subjects: vec![format!("{}.>", stream_name)],
+        retention: RetentionPolicy::Limits,
+        max_messages_per_subject: env_or("PROGRESS_MAX_EVENTS_PER_TASK", 10_000),
+        max_age: Duration::from_secs(env_or("PROGRESS_RETENTION_SECS", 86_400)),
+        ..Default::default()
+    }
+}
+
+/// Subject prefix of progress messages that could not be decoded, `<prefix>.<task_id>`.
+fn dead_letter_subject() -> String {
+    format!("{}_dead_letters", crate::STREAM_NAME)
+}
+
+/// Malformed progress is kept for `PROGRESS_DEAD_LETTER_RETENTION_SECS`. Every viewer of a task
+/// reports the same bad message, so duplicates are dropped for as long as progress is retained.
+fn dead_letter_stream_config() -> Config {
+    let subject = dead_letter_subject();
+    let max_age = Duration::from_secs(env_or("PROGRESS_DEAD_LETTER_RETENTION_SECS", 604_800));
+    let progress_age = Duration::from_secs(env_or("PROGRESS_RETENTION_SECS", 86_400));
+    Config {
+        name: subject.to_uppercase(),
+        subjects: vec![format!("{}.>", subject)],
+        retention: RetentionPolicy::Limits,
+        max_age,
+        duplicate_window: progress_age.min(max_age),
+        ..Default::default()
+    }
+}
+
+async fn create_stream(context: &NatsContext) -> Result<Stream, Error> {
+    let dead_letters = dead_letter_stream_config();
+    context.get_or_create_stream(dead_letters.clone()).await?;
+    context.update_stream(&dead_letters).await?;
+
+    let config = stream_config();
+    let stream = context.get_or_create_stream(config.clone()).await?;
+    // The retention policy of an existing stream can't be changed in place.
+    if stream.cached_info().config.retention == RetentionPolicy::WorkQueue {
+        warn!(
+            "Recreating stream {} with limits retention, queued progress is dropped",
+            config.name
+        );
+        context.delete_stream(&config.name).await?;
+        return Ok(context.create_stream(config).await?);
+    }
+    context.update_stream(&config).await?;
+    Ok(stream)
+}
+
+/// Declares the stream with backoff, then again after every reconnect in case the server lost it.
+async fn keep_stream(context: NatsContext, handle: NatsStream, reconnected: Arc<Notify>) {
+    loop {
+        let mut attempt = 0;
+        loop {
+            match create_stream(&context).await {
+                Ok(stream) => {
+                    handle.set(stream);
+                    break;
+                }
+                Err(e) => {
+                    let delay = reconnect_delay(attempt);
+                    warn!("NATS stream is unavailable, retrying in {:?}: {}", delay, e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+        reconnected.notified().await;
+    }
+}
+
+/// Deletes the ephemeral consumer of a progress stream once its viewer goes away.
+struct ViewerStream {
+    messages: Ordered,
+    stream: Stream,
+    consumer: String,
+    /// Messages of the task already in the stream when the consumer was created.
+    pending: u64,
+}
+
+impl futures::Stream for ViewerStream {
+    type Item = <Ordered as futures::Stream>::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for ViewerStream {
+    fn drop(&mut self) {
+        let stream = self.stream.clone();
+        let consumer = std::mem::take(&mut self.consumer);
+        tokio::spawn(async move {
+            if let Err(e) = stream.delete_consumer(&consumer).await {
+                // Ordered consumers are also removed by the server after a short inactivity.
+                info!("Failed to delete progress consumer {}: {}", consumer, e);
+            }
+        });
+    }
+}
+
+/// Opens an ordered consumer of the task's events, starting after sequence `after` when resuming.
+async fn get_messages_stream(
+    stream: &NatsStream,
+    id: uuid::Uuid,
+    after: Option<u64>,
+) -> Result<ViewerStream, Error> {
+    let stream = stream.get().ok_or("NATS stream is not available")?;
+    let filter_subject = format!("{}.{}", crate::STREAM_NAME, id);
+    let mut consumer = stream
+        .create_consumer(OrderedConfig {
+            filter_subject,
+            deliver_policy: match after {
+                Some(sequence) => DeliverPolicy::ByStartSequence {
+                    start_sequence: sequence + 1,
+                },
+                None => DeliverPolicy::All,
+            },
             ..Default::default()
         })
-        .await
-        .expect("Failed to create or get stream");
-    rocket.manage(stream)
+        .await?;
+    let info = consumer.info().await?;
+    let (name, pending) = (info.name.clone(), info.num_pending);
+    Ok(ViewerStream {
+        messages: consumer.messages().await?,
+        stream,
+        consumer: name,
+        pending,
+    })
+}
+
+/// Decodes a progress message, along with the number of the task's messages still after it.
+fn decode_message(task_id: uuid::Uuid, message: &Message) -> Result<(ProgressMessage, u64), Error> {
+    let info = message.info()?;
+    let content_type = ContentType::from_header(
+        message
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get(CONTENT_TYPE_HEADER))
+            .map(|value| value.as_str()),
+    );
+    let message = ProgressMessage {
+        sequence: info.stream_sequence,
+        event: decode_progress(task_id, &message.payload, content_type),
+    };
+    Ok((message, info.pending))
 }
 
-pub async fn get_messages_stream(
-    stream: &State<NatsStream>,
+/// Copies a message that could not be decoded to the dead-letter subject, with the reason and its
+/// position in the progress stream.
+async fn dead_letter(
+    context: &NatsContext,
+    task_id: uuid::Uuid,
+    sequence: u64,
+    message: &Message,
+    reason: &str,
+) {
+    let published = async {
+        let subject = format!("{}.{}", dead_letter_subject(), task_id);
+        let mut headers = message.headers.clone().unwrap_or_default();
+        headers.insert(NATS_MESSAGE_ID, format!("{}.{}", task_id, sequence));
+        headers.insert(REASON_HEADER, reason);
+        headers.insert(SEQUENCE_HEADER, sequence.to_string());
+        let ack: PublishAck = context
+            .publish_with_headers(subject, headers, message.payload.clone())
+            .await?
+            .await?;
+        Ok::<_, Error>(ack)
+    }
+    .await;
+    match published {
+        Ok(ack) if ack.duplicate => record_dead_letter(DeadLetterOutcome::Duplicate),
+        Ok(_) => {
+            record_dead_letter(DeadLetterOutcome::Published);
+            warn!(
+                "Dead-lettered progress message {} of task {}: {}",
+                sequence, task_id, reason
+            );
+        }
+        Err(e) => {
+            record_dead_letter(DeadLetterOutcome::Failed);
+            error!(
+                "Failed to dead-letter progress message {} of task {}: {}",
+                sequence, task_id, e
+            );
+        }
+    }
+}
+
+async fn publish_event(
+    context: &NatsContext,
     id: uuid::Uuid,
-) -> Result<pullStream, Error> {
-    let stream_name = crate::STREAM_NAME;
-    let consumer_name = format!("pull-{}", id);
-    let filter_subject = format!("{}.{}", stream_name, id);
-    let consumer: PullConsumer = stream
-        .get_or_create_consumer(
-            &consumer_name,
-            pullConfig {
-                durable_name: Some(consumer_name.clone()),
-                filter_subject: filter_subject.into(),
-                ..Default::default()
-            },
-        )
+    event: ProgressEvent,
+) -> Result<(), Error> {
+    let subject = format!("{}.{}", crate::STREAM_NAME, id);
+    let content_type = ContentType::configured();
+    let payload = Envelope::new(id, event).encode(content_type);
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(CONTENT_TYPE_HEADER, content_type.as_str());
+    context
+        .publish_with_headers(subject, headers, payload.into())
+        .await?
         .await?;
-    Ok(consumer.messages().await?)
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl ProgressSubscriber for NatsProgress {
+    async fn subscribe(
+        &self,
+        task_id: uuid::Uuid,
+        after: Option<u64>,
+    ) -> Result<ProgressStream, BoxError> {
+        let messages = get_messages_stream(&self.stream, task_id, after).await?;
+        let context = self.context.clone();
+        Ok(messages
+            .filter_map(move |message| {
+                let context = context.clone();
+                async move {
+                    let message = message
+                        .map_err(Error::from)
+                        .and_then(|message| Ok((decode_message(task_id, &message)?, message)));
+                    match message {
+                        Ok(((progress, _), message)) => {
+                            if let Err(reason) = &progress.event {
+                                dead_letter(&context, task_id, progress.sequence, &message, reason)
+                                    .await;
+                            }
+                            Some(progress)
+                        }
+                        Err(e) => {
+                            error!("Failed to receive message: {}", e);
+                            None
+                        }
+                    }
+                }
+            })
+            .boxed())
+    }
+
+    async fn history(
+        &self,
+        task_id: uuid::Uuid,
+        after: u64,
+    ) -> Result<Vec<ProgressMessage>, BoxError> {
+        let mut messages = get_messages_stream(&self.stream, task_id, Some(after)).await?;
+        let mut history = Vec::new();
+        if messages.pending == 0 {
+            return Ok(history);
+        }
+        tokio::time::timeout(HISTORY_TIMEOUT, async {
+            while let Some(message) = messages.next().await {
+                let message = message?;
+                let (progress, pending) = decode_message(task_id, &message)?;
+                if let Err(reason) = &progress.event {
+                    dead_letter(&self.context, task_id, progress.sequence, &message, reason).await;
+                }
+                history.push(progress);
+                if pending == 0 {
+                    break;
+                }
+            }
+            Ok::<_, BoxError>(())
+        })
+        .await
+        .map_err(|_| "timed out reading progress history")??;
+        Ok(history)
+    }
+
+    /// Stream sequences are shared by all tasks, so the stream's last one will do.
+    async fn position(&self, _task_id: uuid::Uuid) -> Result<u64, BoxError> {
+        let stream = self.stream.get().ok_or("NATS stream is not available")?;
+        Ok(stream.get_info().await?.state.last_sequence)
+    }
+
+    async fn publish(&self, task_id: uuid::Uuid, event: ProgressEvent) -> Result<(), PublishError> {
+        Ok(publish_event(&self.context, task_id, event).await?)
+    }
 }
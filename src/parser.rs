@@ -0,0 +1,25 @@
+use crate::api::{
+    parser_integration_service_client::ParserIntegrationServiceClient, ParserQueryRequest,
+};
+use crate::broker::BoxError;
+use crate::utils::env_or;
+use tonic::transport::Channel;
+
+pub async fn connect() -> Result<ParserIntegrationServiceClient<Channel>, tonic::transport::Error> {
+    ParserIntegrationServiceClient::connect(env_or(
+        "URL_INTEGRATION_SERVICE",
+        "internal_api:50051".to_string(),
+    ))
+    .await
+}
+
+/// Keywords the parser currently extracts for a marketplace product.
+pub async fn parsed_terms(product_id: i32) -> Result<Vec<String>, BoxError> {
+    let mut client = connect().await?;
+    let response = client
+        .get_parsed_content(ParserQueryRequest {
+            query_id: product_id,
+        })
+        .await?;
+    Ok(response.into_inner().parsed_terms)
+}
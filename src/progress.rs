@@ -0,0 +1,267 @@
+use crate::broker::{BoxError, ProgressStream, ProgressSubscriber};
+use crate::contracts::{Envelope, ProgressEvent};
+use crate::database_function::{
+    connection_mongo::Pool as MongoPool,
+    function_postgre::{TaskProgress, TaskStatus},
+    get_archived_progress,
+};
+use crate::structure::analysis_structures::AnalysisKind;
+use crate::structure::send_structures::{ProgressErrorCode, SendMessage};
+use futures::StreamExt;
+use log::error;
+use uuid::Uuid;
+
+/// A progress message as shown to clients: `start`, `progress`, `end`, `done` or `error`.
+pub struct ClientEvent {
+    pub name: &'static str,
+    pub message: SendMessage,
+    /// Nothing follows this event for the task.
+    pub terminal: bool,
+}
+
+impl ClientEvent {
+    fn system(name: &'static str, message: &str, terminal: bool) -> Self {
+        ClientEvent {
+            name,
+            message: SendMessage {
+                message: message.to_string(),
+                task_type: "system".to_string(),
+                reason: None,
+                code: None,
+            },
+            terminal,
+        }
+    }
+
+    fn error(
+        code: ProgressErrorCode,
+        message: &str,
+        reason: Option<String>,
+        terminal: bool,
+    ) -> Self {
+        ClientEvent {
+            name: "error",
+            message: SendMessage {
+                message: message.to_string(),
+                task_type: "system".to_string(),
+                reason,
+                code: Some(code),
+            },
+            terminal,
+        }
+    }
+
+    pub fn start() -> Self {
+        Self::system("start", "start", false)
+    }
+
+    /// The terminal event of a task that finished with `status`.
+    fn finished(status: TaskStatus) -> Option<Self> {
+        match status {
+            TaskStatus::Running => None,
+            TaskStatus::Completed => Some(Self::system("done", "done", true)),
+            TaskStatus::Cancelled => Some(Self::system("done", "cancelled", true)),
+            TaskStatus::Failed => Some(Self::error(
+                ProgressErrorCode::AnalysisFailed,
+                "failed",
+                None,
+                true,
+            )),
+        }
+    }
+
+    fn analysis(name: &'static str, kind: AnalysisKind, message: String) -> Self {
+        ClientEvent {
+            name,
+            message: SendMessage {
+                message,
+                task_type: kind.as_str().to_string(),
+                reason: None,
+                code: None,
+            },
+            terminal: false,
+        }
+    }
+}
+
+/// Follows one run of a task's progress, noticing when every analysis of the run has ended.
+pub struct ProgressView {
+    pending: Vec<AnalysisKind>,
+}
+
+impl Default for ProgressView {
+    fn default() -> Self {
+        Self::expecting(&AnalysisKind::ALL)
+    }
+}
+
+impl ProgressView {
+    /// A view of a run of the analyses `kinds`.
+    pub fn expecting(kinds: &[AnalysisKind]) -> Self {
+        ProgressView {
+            pending: kinds.to_vec(),
+        }
+    }
+
+    /// Client events for one message, with `done` appended once the last analysis ends.
+    pub fn events(&mut self, event: Result<Envelope<ProgressEvent>, String>) -> Vec<ClientEvent> {
+        let event = match event {
+            Ok(envelope) => envelope.body,
+            Err(e) => {
+                error!("Failed to decode progress message: {}", e);
+                return vec![ClientEvent::error(
+                    ProgressErrorCode::MalformedMessage,
+                    "invalid message",
+                    Some(e),
+                    false,
+                )];
+            }
+        };
+        match event {
+            ProgressEvent::Cancelled => vec![ClientEvent::system("done", "cancelled", true)],
+            ProgressEvent::Failed { reason } => vec![ClientEvent::error(
+                ProgressErrorCode::AnalysisFailed,
+                "failed",
+                Some(reason),
+                true,
+            )],
+            ProgressEvent::Chunk { kind, text } => {
+                vec![ClientEvent::analysis("progress", kind, text)]
+            }
+            ProgressEvent::End { kind } => {
+                let was_pending = !self.pending.is_empty();
+                self.pending.retain(|pending| *pending != kind);
+                let mut events = vec![ClientEvent::analysis("end", kind, "end".to_string())];
+                if was_pending && self.pending.is_empty() {
+                    events.push(ClientEvent::system("done", "done", true));
+                }
+                events
+            }
+        }
+    }
+}
+
+/// The progress of a task's current run as sent to one viewer.
+pub struct ProgressFeed {
+    pub messages: ProgressStream,
+    /// Sent once `messages` ends without a terminal event, which only happens when replaying a
+    /// finished task whose recorded events stop short of it.
+    pub closing: Option<ClientEvent>,
+    pub view: ProgressView,
+}
+
+impl ProgressFeed {
+    /// Follows a running task live; replays a finished one from its archive, or from the broker
+    /// until it is archived, so the viewer is not left waiting for events that won't come.
+    /// Events of earlier runs are skipped.
+    pub async fn open(
+        mongo_pool: &MongoPool,
+        progress: &dyn ProgressSubscriber,
+        task_id: Uuid,
+        task: &TaskProgress,
+        after: Option<u64>,
+    ) -> Result<Self, BoxError> {
+        let after = after.unwrap_or(0).max(task.progress_start);
+        let view = ProgressView::expecting(&task.kinds);
+        let Some(closing) = ClientEvent::finished(task.status) else {
+            return Ok(ProgressFeed {
+                messages: progress.subscribe(task_id, Some(after)).await?,
+                closing: None,
+                view,
+            });
+        };
+        let history = match get_archived_progress(mongo_pool, task_id, task.progress_start).await? {
+            Some(history) => history,
+            None => progress.history(task_id, after).await?,
+        };
+        let history = history
+            .into_iter()
+            .filter(move |message| message.sequence > after);
+        Ok(ProgressFeed {
+            messages: futures::stream::iter(history).boxed(),
+            closing: Some(closing),
+            view,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(body: ProgressEvent) -> Result<Envelope<ProgressEvent>, String> {
+        Ok(Envelope::new(Uuid::new_v4(), body))
+    }
+
+    fn names(events: &[ClientEvent]) -> Vec<(&'static str, &str, bool)> {
+        events
+            .iter()
+            .map(|event| (event.name, event.message.message.as_str(), event.terminal))
+            .collect()
+    }
+
+    #[test]
+    fn malformed_messages_are_reported_without_ending_the_feed() {
+        let events = ProgressView::default().events(Err("bad json".to_string()));
+        assert_eq!(names(&events), [("error", "invalid message", false)]);
+        assert!(matches!(
+            events[0].message.code,
+            Some(ProgressErrorCode::MalformedMessage)
+        ));
+        assert_eq!(events[0].message.reason.as_deref(), Some("bad json"));
+    }
+
+    #[test]
+    fn failure_ends_the_feed_with_its_reason() {
+        let reason = "worker crashed".to_string();
+        let events = ProgressView::default().events(event(ProgressEvent::Failed { reason }));
+        assert_eq!(names(&events), [("error", "failed", true)]);
+        assert!(matches!(
+            events[0].message.code,
+            Some(ProgressErrorCode::AnalysisFailed)
+        ));
+        assert_eq!(events[0].message.reason.as_deref(), Some("worker crashed"));
+    }
+
+    #[test]
+    fn cancellation_ends_the_feed() {
+        let events = ProgressView::default().events(event(ProgressEvent::Cancelled));
+        assert_eq!(names(&events), [("done", "cancelled", true)]);
+    }
+
+    #[test]
+    fn done_follows_the_end_of_every_analysis() {
+        let mut view = ProgressView::default();
+        let chunk = ProgressEvent::Chunk {
+            kind: AnalysisKind::Text,
+            text: "keywords".to_string(),
+        };
+        assert_eq!(
+            names(&view.events(event(chunk))),
+            [("progress", "keywords", false)]
+        );
+        for kind in [AnalysisKind::Text, AnalysisKind::Photo] {
+            let events = view.events(event(ProgressEvent::End { kind }));
+            assert_eq!(names(&events), [("end", "end", false)]);
+            assert_eq!(events[0].message.task_type, kind.as_str());
+        }
+        let events = view.events(event(ProgressEvent::End {
+            kind: AnalysisKind::Reviews,
+        }));
+        assert_eq!(
+            names(&events),
+            [("end", "end", false), ("done", "done", true)]
+        );
+    }
+
+    #[test]
+    fn repeated_end_does_not_finish_the_run() {
+        let mut view = ProgressView::default();
+        for _ in 0..3 {
+            let events = view.events(event(ProgressEvent::End {
+                kind: AnalysisKind::Text,
+            }));
+            assert_eq!(names(&events), [("end", "end", false)]);
+        }
+    }
+}
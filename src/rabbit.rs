@@ -1,106 +1,526 @@
-use lapin::{publisher_confirm::PublisherConfirm, Connection, ConnectionProperties};
-use rocket::{Build, Rocket};
-use std::env;
+use crate::broker::{JobPublisher, PublishError};
+use crate::contracts::{ContentType, ControlMessage, Envelope};
+use crate::structure::analysis_structures::AnalysisKind;
+use crate::utils::{env_or, reconnect_delay};
+use lapin::{
+    message::Delivery,
+    publisher_confirm::Confirmation,
+    types::{AMQPValue, FieldTable},
+    Connection, ConnectionProperties, Consumer,
+};
+use log::{info, warn};
+use std::{
+    env,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use uuid::Uuid;
 
-pub type RabbitChannel = lapin::Channel;
+/// Shared handle to the current RabbitMQ channel, swapped out when the connection is restored.
+#[derive(Clone, Default)]
+pub struct RabbitChannel(Arc<RwLock<Option<lapin::Channel>>>);
 
-#[derive(serde::Serialize)]
-struct Message<'r> {
-    pub task_type: String,
-    pub payload: Vec<&'r str>,
-    pub task_id: String,
-}
+impl RabbitChannel {
+    /// Returns the channel if it is still connected.
+    pub fn get(&self) -> Option<lapin::Channel> {
+        let channel = self.0.read().expect("RabbitMQ channel lock poisoned");
+        channel
+            .as_ref()
+            .filter(|channel| channel.status().connected())
+            .cloned()
+    }
 
-#[derive(serde::Serialize)]
-struct MessageReview {
-    pub task_type: String,
-    pub payload: Vec<Vec<String>>,
-    pub task_id: String,
+    fn set(&self, channel: lapin::Channel) {
+        *self.0.write().expect("RabbitMQ channel lock poisoned") = Some(channel);
+    }
 }
 
-pub async fn init_rabbit_queues(rocket: Rocket<Build>) -> Rocket<Build> {
+const ANALYSIS_KINDS: [AnalysisKind; 3] = [
+    AnalysisKind::Text,
+    AnalysisKind::Photo,
+    AnalysisKind::Reviews,
+];
+const CONTROL_EXCHANGE: &str = "analysis_control";
+const DEAD_LETTER_EXCHANGE: &str = "analysis_dead_letter";
+const DEAD_LETTER_QUEUE: &str = "analysis_queue.dlq";
+const ATTEMPT_HEADER: &str = "x-attempt";
+const PERSISTENT_DELIVERY: u8 = 2;
+
+/// Connects to RabbitMQ, starting degraded if it is down, and keeps the channel connected.
+pub async fn connect_rabbit() -> RabbitChannel {
     let connection_string =
         env::var("CONNECTION_STRING_RabbitMQ").expect("Failed to load RabbitMQ config");
-    let conn = Connection::connect(&connection_string, ConnectionProperties::default())
-        .await
-        .expect("Failed to connect to RabbitMQ");
+    let handle = RabbitChannel::default();
+    match connect_and_declare(&connection_string).await {
+        Ok(channel) => handle.set(channel),
+        Err(e) => warn!("RabbitMQ is unavailable, starting in degraded mode: {}", e),
+    }
+    tokio::spawn(keep_connected(handle.clone(), connection_string));
+    handle
+}
 
-    let channel = conn
-        .create_channel()
-        .await
-        .expect("Failed to create channel RAbbitMQ");
+/// Watches the current channel and replaces it, re-declaring the topology, once it drops.
+async fn keep_connected(handle: RabbitChannel, connection_string: String) {
+    let check = Duration::from_secs(env_or("RABBIT_HEALTH_CHECK_SECS", 5));
+    loop {
+        tokio::time::sleep(check).await;
+        if handle.get().is_some() {
+            continue;
+        }
+        let mut attempt = 0;
+        loop {
+            match connect_and_declare(&connection_string).await {
+                Ok(channel) => {
+                    info!("Reconnected to RabbitMQ");
+                    handle.set(channel);
+                    break;
+                }
+                Err(e) => {
+                    let delay = reconnect_delay(attempt);
+                    warn!(
+                        "Failed to reconnect to RabbitMQ, retrying in {:?}: {}",
+                        delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Topic exchange the analysis jobs are published to, `RABBIT_ANALYSIS_EXCHANGE`.
+fn analysis_exchange() -> String {
+    env_or("RABBIT_ANALYSIS_EXCHANGE", "analysis_jobs".to_string())
+}
+
+fn analysis_routing_key(kind: AnalysisKind) -> String {
+    format!("analysis.{}", kind.as_str())
+}
+
+/// Highest priority the analysis queues accept, declared as their `x-max-priority`.
+fn max_priority() -> u8 {
+    env_or("RABBIT_MAX_PRIORITY", 10)
+}
+
+/// Queue consumed by the worker pool of one analysis kind, e.g. `RABBIT_TEXT_QUEUE`.
+///
+/// The default names are new to the priority queues: a durable queue can't be re-declared with
+/// other arguments, so the older `analysis_queue.<kind>` queues are left to drain.
+fn analysis_queue(kind: AnalysisKind) -> String {
+    env_or(
+        &format!("RABBIT_{}_QUEUE", kind.as_str().to_uppercase()),
+        format!("analysis_jobs.{}", kind.as_str()),
+    )
+}
+
+/// Exchange and queue holding retries until `delay` has passed, named after the delay since
+/// a queue's TTL can't be changed once declared.
+fn retry_queue(delay: Duration) -> String {
+    format!("analysis_retry.{}s", delay.as_secs())
+}
+
+/// Declares a delay queue per retry attempt. Messages wait there for the attempt's delay, then
+/// are dead-lettered back to the analysis exchange under their original routing key.
+async fn declare_retry_queues(
+    channel: &lapin::Channel,
+    exchange: &str,
+) -> Result<(), lapin::Error> {
+    let max_retries: u32 = env_or("ANALYSIS_MAX_RETRIES", 3);
+    for attempt in 1..=max_retries {
+        let delay = retry_delay(attempt - 1);
+        let name = retry_queue(delay);
+        channel
+            .exchange_declare(
+                &name,
+                lapin::ExchangeKind::Fanout,
+                lapin::options::ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        let mut arguments = FieldTable::default();
+        arguments.insert(
+            "x-message-ttl".into(),
+            AMQPValue::LongLongInt(i64::try_from(delay.as_millis()).unwrap_or(i64::MAX)),
+        );
+        arguments.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString(exchange.into()),
+        );
+        channel
+            .queue_declare(
+                &name,
+                lapin::options::QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                arguments,
+            )
+            .await?;
+        channel
+            .queue_bind(
+                &name,
+                &name,
+                "",
+                lapin::options::QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn connect_and_declare(connection_string: &str) -> Result<lapin::Channel, lapin::Error> {
+    let conn = Connection::connect(connection_string, ConnectionProperties::default()).await?;
+
+    let channel = conn.create_channel().await?;
+
+    channel
+        .confirm_select(lapin::options::ConfirmSelectOptions::default())
+        .await?;
+
+    channel
+        .exchange_declare(
+            DEAD_LETTER_EXCHANGE,
+            lapin::ExchangeKind::Fanout,
+            lapin::options::ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
 
     channel
         .queue_declare(
-            "analysis_queue",
+            DEAD_LETTER_QUEUE,
             lapin::options::QueueDeclareOptions {
                 durable: true,
                 ..Default::default()
             },
-            lapin::types::FieldTable::default(),
+            FieldTable::default(),
         )
-        .await
-        .expect("Failed to declare analysis_queue");
+        .await?;
 
-    rocket.manage(channel)
-}
+    channel
+        .queue_bind(
+            DEAD_LETTER_QUEUE,
+            DEAD_LETTER_EXCHANGE,
+            "",
+            lapin::options::QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
 
-async fn send_message(
-    channel: &lapin::Channel,
-    payload: &Vec<u8>,
-) -> Result<PublisherConfirm, lapin::Error> {
+    let exchange = analysis_exchange();
     channel
+        .exchange_declare(
+            &exchange,
+            lapin::ExchangeKind::Topic,
+            lapin::options::ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    for kind in ANALYSIS_KINDS {
+        let queue = analysis_queue(kind);
+        let mut arguments = FieldTable::default();
+        arguments.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString(DEAD_LETTER_EXCHANGE.into()),
+        );
+        arguments.insert(
+            "x-max-priority".into(),
+            AMQPValue::ShortShortUInt(max_priority()),
+        );
+        channel
+            .queue_declare(
+                &queue,
+                lapin::options::QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                arguments,
+            )
+            .await?;
+        channel
+            .queue_bind(
+                &queue,
+                &exchange,
+                &analysis_routing_key(kind),
+                lapin::options::QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+    }
+
+    declare_retry_queues(&channel, &exchange).await?;
+
+    channel
+        .exchange_declare(
+            CONTROL_EXCHANGE,
+            lapin::ExchangeKind::Fanout,
+            lapin::options::ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    Ok(channel)
+}
+
+/// Publishes a persistent message and waits until the broker confirms it.
+async fn publish_confirmed(
+    channel: &RabbitChannel,
+    exchange: &str,
+    routing_key: &str,
+    payload: &[u8],
+    properties: lapin::BasicProperties,
+) -> Result<(), PublishError> {
+    let timeout = Duration::from_secs(env_or("RABBIT_CONFIRM_TIMEOUT_SECS", 5));
+    let channel = channel.get().ok_or(PublishError::Unavailable)?;
+    let confirm = channel
         .basic_publish(
-            "",
-            "analysis_queue",
+            exchange,
+            routing_key,
             lapin::options::BasicPublishOptions::default(),
             payload,
-            lapin::BasicProperties::default(),
+            properties.with_delivery_mode(PERSISTENT_DELIVERY),
+        )
+        .await?;
+    match tokio::time::timeout(timeout, confirm).await {
+        Ok(Ok(Confirmation::Nack(_))) => Err(PublishError::Nack),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(PublishError::Timeout),
+    }
+}
+
+pub async fn consume_dead_letters(channel: &lapin::Channel) -> Result<Consumer, lapin::Error> {
+    channel
+        .basic_consume(
+            DEAD_LETTER_QUEUE,
+            "external-api-dead-letters",
+            lapin::options::BasicConsumeOptions::default(),
+            FieldTable::default(),
         )
         .await
 }
 
-pub async fn send_task_to_text_analysis_queue(
-    channel: &lapin::Channel,
-    task_id: &uuid::Uuid,
-    payload: Vec<&str>,
-) -> Result<(), lapin::Error> {
-    let task = Message {
-        task_type: "text".to_string(),
-        payload,
-        task_id: task_id.to_string(),
-    };
-    let payload = serde_json::to_vec(&task).expect("Failed to serialize task to JSON");
-    let _confirm = send_message(channel, &payload).await?;
-    Ok(())
+pub fn delivery_attempt(delivery: &Delivery) -> u32 {
+    let attempt = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(ATTEMPT_HEADER).cloned());
+    match attempt {
+        Some(AMQPValue::LongUInt(value)) => value,
+        Some(AMQPValue::LongInt(value)) => value.max(0) as u32,
+        Some(AMQPValue::LongLongInt(value)) => value.max(0) as u32,
+        _ => 0,
+    }
 }
 
-pub async fn send_task_to_photo_analysis_queue(
-    channel: &lapin::Channel,
-    task_id: &uuid::Uuid,
-    payload: Vec<&str>,
-) -> Result<(), lapin::Error> {
-    let task = Message {
-        task_type: "photo".to_string(),
-        payload,
-        task_id: task_id.to_string(),
-    };
-    let payload = serde_json::to_vec(&task).expect("Failed to serialize task to JSON");
-    let _confirm = send_message(channel, &payload).await?;
-    Ok(())
+pub fn delivery_content_type(delivery: &Delivery) -> ContentType {
+    ContentType::from_header(
+        delivery
+            .properties
+            .content_type()
+            .as_ref()
+            .map(|value| value.as_str()),
+    )
 }
 
-pub async fn send_task_to_reviews_analysis_queue(
-    channel: &lapin::Channel,
-    task_id: &uuid::Uuid,
-    payload: Vec<Vec<String>>,
-) -> Result<(), lapin::Error> {
-    let task = MessageReview {
-        task_type: "reviews".to_string(),
-        payload,
-        task_id: task_id.to_string(),
-    };
-    let payload = serde_json::to_vec(&task).expect("Failed to serialize task to JSON");
-    let _confirm = send_message(channel, &payload).await?;
-    Ok(())
+pub fn delivery_priority(delivery: &Delivery) -> u8 {
+    delivery.properties.priority().unwrap_or(0)
+}
+
+pub fn retry_delay(attempt: u32) -> Duration {
+    let base: u64 = env_or("ANALYSIS_RETRY_BASE_SECS", 5);
+    Duration::from_secs(base.saturating_mul(1 << attempt.min(10)))
+}
+
+fn job_properties(content_type: ContentType, priority: u8, attempt: u32) -> lapin::BasicProperties {
+    let mut headers = FieldTable::default();
+    headers.insert(ATTEMPT_HEADER.into(), AMQPValue::LongUInt(attempt));
+    lapin::BasicProperties::default()
+        .with_content_type(content_type.as_str().into())
+        .with_priority(priority.min(max_priority()))
+        .with_headers(headers)
+}
+
+impl RabbitChannel {
+    /// Publishes a dead-lettered job to the delay queue of its `attempt`, from which it goes back
+    /// to the workers once the retry delay has passed.
+    pub async fn publish_retry(
+        &self,
+        kind: AnalysisKind,
+        content_type: ContentType,
+        payload: &[u8],
+        priority: u8,
+        attempt: u32,
+    ) -> Result<(), PublishError> {
+        publish_confirmed(
+            self,
+            &retry_queue(retry_delay(attempt - 1)),
+            &analysis_routing_key(kind),
+            payload,
+            job_properties(content_type, priority, attempt),
+        )
+        .await
+    }
+}
+
+#[rocket::async_trait]
+impl JobPublisher for RabbitChannel {
+    async fn publish_job(
+        &self,
+        _task_id: &Uuid,
+        kind: AnalysisKind,
+        content_type: ContentType,
+        payload: &[u8],
+        priority: u8,
+        attempt: u32,
+    ) -> Result<(), PublishError> {
+        publish_confirmed(
+            self,
+            &analysis_exchange(),
+            &analysis_routing_key(kind),
+            payload,
+            job_properties(content_type, priority, attempt),
+        )
+        .await
+    }
+
+    async fn publish_cancel(&self, task_id: &Uuid) -> Result<(), PublishError> {
+        let content_type = ContentType::configured();
+        let payload = Envelope::new(*task_id, ControlMessage::Cancel).encode(content_type);
+        publish_confirmed(
+            self,
+            CONTROL_EXCHANGE,
+            "",
+            &payload,
+            lapin::BasicProperties::default().with_content_type(content_type.as_str().into()),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(headers: Option<FieldTable>) -> Delivery {
+        let mut properties = lapin::BasicProperties::default();
+        if let Some(headers) = headers {
+            properties = properties.with_headers(headers);
+        }
+        Delivery {
+            delivery_tag: 1,
+            exchange: DEAD_LETTER_EXCHANGE.into(),
+            routing_key: "text".into(),
+            redelivered: false,
+            properties,
+            data: vec![],
+            acker: Default::default(),
+        }
+    }
+
+    fn attempt_header(value: AMQPValue) -> Option<FieldTable> {
+        let mut headers = FieldTable::default();
+        headers.insert(ATTEMPT_HEADER.into(), value);
+        Some(headers)
+    }
+
+    #[test]
+    fn delivery_attempt_reads_the_attempt_header() {
+        for (headers, attempt) in [
+            (None, 0),
+            (Some(FieldTable::default()), 0),
+            (attempt_header(AMQPValue::LongUInt(3)), 3),
+            (attempt_header(AMQPValue::LongInt(2)), 2),
+            (attempt_header(AMQPValue::LongInt(-1)), 0),
+            (attempt_header(AMQPValue::LongLongInt(4)), 4),
+            (attempt_header(AMQPValue::LongString("5".into())), 0),
+        ] {
+            assert_eq!(
+                delivery_attempt(&delivery(headers.clone())),
+                attempt,
+                "{:?}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn published_attempt_is_read_back() {
+        let properties = job_properties(ContentType::Json, 0, 7);
+        let headers = properties.headers().clone();
+        assert_eq!(delivery_attempt(&delivery(headers)), 7);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_a_cap() {
+        let base = retry_delay(0);
+        assert_eq!(retry_delay(1), base * 2);
+        assert_eq!(retry_delay(3), base * 8);
+        assert_eq!(retry_delay(10), base * 1024);
+        assert_eq!(retry_delay(u32::MAX), retry_delay(10));
+    }
+
+    #[test]
+    fn job_priority_is_clamped_to_the_queue_maximum() {
+        let max = max_priority();
+        for (priority, sent) in [(0, 0), (6, 6), (max, max), (max + 1, max), (u8::MAX, max)] {
+            let properties = job_properties(ContentType::Json, priority, 0);
+            assert_eq!(*properties.priority(), Some(sent), "{}", priority);
+        }
+    }
+
+    #[test]
+    fn each_kind_has_its_own_routing_key() {
+        for (kind, key) in [
+            (AnalysisKind::Text, "analysis.text"),
+            (AnalysisKind::Photo, "analysis.photo"),
+            (AnalysisKind::Reviews, "analysis.reviews"),
+        ] {
+            assert_eq!(analysis_routing_key(kind), key);
+        }
+    }
+
+    /// The only test touching `RABBIT_*_QUEUE`, so no other test sees the overrides.
+    #[test]
+    fn queue_names_default_per_kind_and_can_be_overridden() {
+        for (kind, variable, default) in [
+            (
+                AnalysisKind::Text,
+                "RABBIT_TEXT_QUEUE",
+                "analysis_jobs.text",
+            ),
+            (
+                AnalysisKind::Photo,
+                "RABBIT_PHOTO_QUEUE",
+                "analysis_jobs.photo",
+            ),
+            (
+                AnalysisKind::Reviews,
+                "RABBIT_REVIEWS_QUEUE",
+                "analysis_jobs.reviews",
+            ),
+        ] {
+            env::remove_var(variable);
+            assert_eq!(analysis_queue(kind), default);
+            env::set_var(variable, format!("legacy.{}", kind.as_str()));
+            assert_eq!(analysis_queue(kind), format!("legacy.{}", kind.as_str()));
+            env::remove_var(variable);
+        }
+    }
 }
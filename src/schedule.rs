@@ -0,0 +1,211 @@
+use crate::database_function::function_postgre::SubscriptionPlan;
+use crate::utils::env_or;
+use chrono::{DateTime, Duration, Utc};
+use std::str::FromStr;
+
+/// Upcoming cron fires checked against the minimum interval.
+const CRON_SAMPLE: usize = 16;
+
+/// When a scheduled task runs again: a cron expression evaluated in UTC, or a fixed interval.
+pub enum Cadence {
+    Cron(Box<cron::Schedule>),
+    Every(Duration),
+}
+
+impl Cadence {
+    /// Parses a stored or requested cadence; exactly one of `cron` and `interval_secs` is set.
+    /// Cron expressions take the usual five fields, or six with leading seconds.
+    pub fn parse(cron: Option<&str>, interval_secs: Option<i64>) -> Result<Self, String> {
+        match (cron, interval_secs) {
+            (Some(expression), None) => {
+                let expression = expression.trim();
+                let expression = if expression.split_whitespace().count() == 5 {
+                    format!("0 {}", expression)
+                } else {
+                    expression.to_string()
+                };
+                cron::Schedule::from_str(&expression)
+                    .map(|schedule| Self::Cron(Box::new(schedule)))
+                    .map_err(|e| format!("invalid cron expression: {}", e))
+            }
+            (None, Some(secs)) if secs > 0 => Ok(Self::Every(Duration::seconds(secs))),
+            (None, Some(_)) => Err("intervalSecs must be positive".to_string()),
+            _ => Err("set either cron or intervalSecs".to_string()),
+        }
+    }
+
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Cron(schedule) => schedule.after(&after).next(),
+            Self::Every(interval) => Some(after + *interval),
+        }
+    }
+
+    /// Rejects cadences firing more often than `SCHEDULE_MIN_INTERVAL_SECS`, or never.
+    pub fn validate(&self, now: DateTime<Utc>) -> Result<(), String> {
+        let min = Duration::seconds(env_or("SCHEDULE_MIN_INTERVAL_SECS", 3600));
+        let gap = match self {
+            Self::Every(interval) => *interval,
+            Self::Cron(schedule) => {
+                let upcoming: Vec<_> = schedule.after(&now).take(CRON_SAMPLE).collect();
+                if upcoming.is_empty() {
+                    return Err("cron expression never fires".to_string());
+                }
+                match upcoming.windows(2).map(|pair| pair[1] - pair[0]).min() {
+                    Some(gap) => gap,
+                    None => return Ok(()),
+                }
+            }
+        };
+        if gap < min {
+            return Err(format!(
+                "runs must be at least {} seconds apart",
+                min.num_seconds()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How many scheduled tasks a user may have: unlimited for admins, otherwise by active plan, and
+/// none without one.
+pub fn schedule_allowance(is_admin: bool, plan: Option<SubscriptionPlan>) -> i64 {
+    if is_admin {
+        return i64::MAX;
+    }
+    plan.map_or(0, |plan| plan.max_schedules())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 2, hour, minute, 15).unwrap()
+    }
+
+    #[test]
+    fn cadences_are_parsed() {
+        for (cron, interval_secs) in [
+            (Some("0 * * * *"), None),
+            (Some("  0 9 * * Mon  "), None),
+            (Some("0 0 9 * * *"), None),
+            (None, Some(3600)),
+        ] {
+            assert!(
+                Cadence::parse(cron, interval_secs).is_ok(),
+                "{:?} {:?}",
+                cron,
+                interval_secs
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_cadences_are_rejected() {
+        for (cron, interval_secs, error) in [
+            (None, None, "set either cron or intervalSecs"),
+            (
+                Some("0 * * * *"),
+                Some(3600),
+                "set either cron or intervalSecs",
+            ),
+            (None, Some(0), "intervalSecs must be positive"),
+            (None, Some(-60), "intervalSecs must be positive"),
+            (Some("every hour"), None, "invalid cron expression"),
+            (Some("61 * * * *"), None, "invalid cron expression"),
+        ] {
+            match Cadence::parse(cron, interval_secs) {
+                Ok(_) => panic!("{:?} {:?} was accepted", cron, interval_secs),
+                Err(e) => assert!(e.starts_with(error), "{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn next_run_follows_the_cadence() {
+        for (cron, interval_secs, after, next) in [
+            (None, Some(5400), at(10, 0), at(11, 30)),
+            (
+                Some("0 * * * *"),
+                None,
+                at(10, 0),
+                at(11, 0) - Duration::seconds(15),
+            ),
+            (
+                Some("30 9 * * *"),
+                None,
+                at(10, 0),
+                at(9, 30) + Duration::days(1) - Duration::seconds(15),
+            ),
+            // Five-field expressions fire on the minute, six-field ones on their seconds field.
+            (
+                Some("45 0 11 * * *"),
+                None,
+                at(10, 0),
+                at(11, 0) + Duration::seconds(30),
+            ),
+        ] {
+            let cadence = Cadence::parse(cron, interval_secs).unwrap();
+            assert_eq!(
+                cadence.next_after(after),
+                Some(next),
+                "{:?} {:?}",
+                cron,
+                interval_secs
+            );
+        }
+    }
+
+    #[test]
+    fn cadences_firing_too_often_are_rejected() {
+        let now = at(10, 0);
+        for (cron, interval_secs, valid) in [
+            (None, Some(3600), true),
+            (None, Some(3599), false),
+            (Some("0 * * * *"), None, true),
+            (Some("*/30 * * * *"), None, false),
+            (Some("* * * * *"), None, false),
+            (Some("0 9 * * *"), None, true),
+        ] {
+            let cadence = Cadence::parse(cron, interval_secs).unwrap();
+            assert_eq!(
+                cadence.validate(now).is_ok(),
+                valid,
+                "{:?} {:?}",
+                cron,
+                interval_secs
+            );
+        }
+    }
+
+    #[test]
+    fn cron_that_never_fires_is_rejected() {
+        let cadence = Cadence::parse(Some("0 0 0 1 1 * 2000"), None).unwrap();
+        assert_eq!(
+            cadence.validate(at(10, 0)).unwrap_err(),
+            "cron expression never fires"
+        );
+    }
+
+    #[test]
+    fn schedule_allowance_follows_the_plan() {
+        for (is_admin, plan, allowance) in [
+            (false, None, 0),
+            (false, Some(SubscriptionPlan::Trial), 1),
+            (false, Some(SubscriptionPlan::Standard), 5),
+            (false, Some(SubscriptionPlan::Premium), 25),
+            (true, None, i64::MAX),
+            (true, Some(SubscriptionPlan::Trial), i64::MAX),
+        ] {
+            assert_eq!(
+                schedule_allowance(is_admin, plan),
+                allowance,
+                "{} {:?}",
+                is_admin,
+                plan
+            );
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use serde::{Deserialize, Deserializer, Serialize};
+
+pub const ANALYSIS_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalysisKind {
+    Text,
+    Photo,
+    Reviews,
+}
+
+impl AnalysisKind {
+    pub const ALL: [AnalysisKind; 3] = [Self::Text, Self::Photo, Self::Reviews];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(Self::Text),
+            "photo" => Some(Self::Photo),
+            "reviews" => Some(Self::Reviews),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Photo => "photo",
+            Self::Reviews => "reviews",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeywordScore {
+    pub word: String,
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProductTextAnalysis {
+    pub product_id: u64,
+    pub keywords: Vec<KeywordScore>,
+    pub summary: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextAnalysis {
+    pub version: u32,
+    pub products: Vec<ProductTextAnalysis>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageFinding {
+    pub issue: String,
+    pub severity: Severity,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProductPhotoAnalysis {
+    pub product_id: u64,
+    pub quality_score: f32,
+    pub findings: Vec<ImageFinding>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhotoAnalysis {
+    pub version: u32,
+    pub products: Vec<ProductPhotoAnalysis>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sentiment {
+    Positive,
+    Neutral,
+    Negative,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReviewTheme {
+    pub theme: String,
+    pub mentions: u32,
+    pub sentiment: Sentiment,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProductReviewAnalysis {
+    pub product_id: u64,
+    pub sentiment: Sentiment,
+    pub sentiment_score: f32,
+    pub themes: Vec<ReviewTheme>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReviewAnalysis {
+    pub version: u32,
+    pub products: Vec<ProductReviewAnalysis>,
+}
+
+pub trait ValidateAnalysis {
+    fn validate(&self) -> Result<(), String>;
+
+    /// Products the result is about, in its order.
+    fn product_ids(&self) -> Vec<u64>;
+
+    /// Rejects results about products that are neither the task's main product nor one of its
+    /// competitors.
+    fn check_products(&self, known: &[u64]) -> Result<(), String> {
+        match self
+            .product_ids()
+            .into_iter()
+            .find(|id| !known.contains(id))
+        {
+            Some(id) => Err(format!("product {} is not part of the task", id)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn check_version(version: u32) -> Result<(), String> {
+    if version != ANALYSIS_SCHEMA_VERSION {
+        return Err(format!(
+            "unsupported schema version {}, expected {}",
+            version, ANALYSIS_SCHEMA_VERSION
+        ));
+    }
+    Ok(())
+}
+
+fn check_score(name: &str, product_id: u64, value: f32, min: f32) -> Result<(), String> {
+    if !(min..=1.0).contains(&value) {
+        return Err(format!(
+            "{} of product {} must be in [{}, 1], got {}",
+            name, product_id, min, value
+        ));
+    }
+    Ok(())
+}
+
+impl ValidateAnalysis for TextAnalysis {
+    fn validate(&self) -> Result<(), String> {
+        check_version(self.version)?;
+        if self.products.is_empty() {
+            return Err("text analysis has no products".to_string());
+        }
+        for product in &self.products {
+            for keyword in &product.keywords {
+                if keyword.word.trim().is_empty() {
+                    return Err(format!("empty keyword in product {}", product.product_id));
+                }
+                check_score("keyword score", product.product_id, keyword.score, 0.0)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn product_ids(&self) -> Vec<u64> {
+        self.products.iter().map(|p| p.product_id).collect()
+    }
+}
+
+impl ValidateAnalysis for PhotoAnalysis {
+    fn validate(&self) -> Result<(), String> {
+        check_version(self.version)?;
+        if self.products.is_empty() {
+            return Err("photo analysis has no products".to_string());
+        }
+        for product in &self.products {
+            check_score(
+                "quality score",
+                product.product_id,
+                product.quality_score,
+                0.0,
+            )?;
+            if product.findings.iter().any(|f| f.issue.trim().is_empty()) {
+                return Err(format!("empty finding in product {}", product.product_id));
+            }
+        }
+        Ok(())
+    }
+
+    fn product_ids(&self) -> Vec<u64> {
+        self.products.iter().map(|p| p.product_id).collect()
+    }
+}
+
+impl ValidateAnalysis for ReviewAnalysis {
+    fn validate(&self) -> Result<(), String> {
+        check_version(self.version)?;
+        if self.products.is_empty() {
+            return Err("review analysis has no products".to_string());
+        }
+        for product in &self.products {
+            check_score(
+                "sentiment score",
+                product.product_id,
+                product.sentiment_score,
+                -1.0,
+            )?;
+            if product.themes.iter().any(|t| t.theme.trim().is_empty()) {
+                return Err(format!("empty theme in product {}", product.product_id));
+            }
+        }
+        Ok(())
+    }
+
+    fn product_ids(&self) -> Vec<u64> {
+        self.products.iter().map(|p| p.product_id).collect()
+    }
+}
+
+/// Parses a raw worker result and validates it against its schema.
+pub fn parse_analysis<T>(value: serde_json::Value) -> Result<T, String>
+where
+    T: ValidateAnalysis + for<'de> Deserialize<'de>,
+{
+    let analysis: T = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    analysis.validate()?;
+    Ok(analysis)
+}
+
+/// Parses a worker result for a task with the products `product_ids`, see [`parse_analysis`].
+pub fn parse_task_analysis<T>(value: serde_json::Value, product_ids: &[u64]) -> Result<T, String>
+where
+    T: ValidateAnalysis + for<'de> Deserialize<'de>,
+{
+    let analysis: T = parse_analysis(value)?;
+    analysis.check_products(product_ids)?;
+    Ok(analysis)
+}
+
+/// Documents written before typed results stored free text; those are read back as `None`, as
+/// are results that no longer match their schema.
+pub fn deserialize_legacy<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: for<'a> Deserialize<'a>,
+{
+    let Some(value) = Option::<bson::Bson>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let legacy = matches!(value, bson::Bson::String(_));
+    match bson::from_bson(value) {
+        Ok(analysis) => Ok(Some(analysis)),
+        Err(e) if legacy => {
+            log::debug!("Skipping free-text analysis result: {}", e);
+            Ok(None)
+        }
+        Err(e) => {
+            log::warn!(
+                "Skipping analysis result that doesn't match its schema: {}",
+                e
+            );
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn text_analysis() -> serde_json::Value {
+        json!({
+            "version": 1,
+            "products": [{
+                "productId": 1,
+                "keywords": [{ "word": "cotton", "score": 0.8 }],
+                "summary": "soft"
+            }]
+        })
+    }
+
+    #[test]
+    fn valid_results_are_parsed() {
+        let analysis: TextAnalysis = parse_analysis(text_analysis()).unwrap();
+        assert_eq!(analysis.products[0].keywords[0].word, "cotton");
+
+        let photo: PhotoAnalysis = parse_analysis(json!({
+            "version": 1,
+            "products": [{ "productId": 1, "qualityScore": 1.0, "findings": [] }]
+        }))
+        .unwrap();
+        assert_eq!(photo.products.len(), 1);
+
+        let reviews: ReviewAnalysis = parse_analysis(json!({
+            "version": 1,
+            "products": [{
+                "productId": 1,
+                "sentiment": "negative",
+                "sentimentScore": -1.0,
+                "themes": [{ "theme": "size", "mentions": 3, "sentiment": "negative" }]
+            }]
+        }))
+        .unwrap();
+        assert_eq!(reviews.products[0].sentiment, Sentiment::Negative);
+    }
+
+    #[test]
+    fn other_schema_versions_are_refused() {
+        let mut value = text_analysis();
+        value["version"] = json!(2);
+        let error = parse_analysis::<TextAnalysis>(value).unwrap_err();
+        assert_eq!(error, "unsupported schema version 2, expected 1");
+    }
+
+    #[test]
+    fn results_without_products_are_refused() {
+        let error =
+            parse_analysis::<PhotoAnalysis>(json!({ "version": 1, "products": [] })).unwrap_err();
+        assert_eq!(error, "photo analysis has no products");
+    }
+
+    #[test]
+    fn scores_out_of_range_are_refused() {
+        let mut value = text_analysis();
+        value["products"][0]["keywords"][0]["score"] = json!(1.5);
+        let error = parse_analysis::<TextAnalysis>(value).unwrap_err();
+        assert_eq!(
+            error,
+            "keyword score of product 1 must be in [0, 1], got 1.5"
+        );
+
+        let error = parse_analysis::<ReviewAnalysis>(json!({
+            "version": 1,
+            "products": [{ "productId": 2, "sentiment": "neutral", "sentimentScore": -1.5, "themes": [] }]
+        }))
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "sentiment score of product 2 must be in [-1, 1], got -1.5"
+        );
+    }
+
+    #[test]
+    fn empty_keywords_findings_and_themes_are_refused() {
+        let mut value = text_analysis();
+        value["products"][0]["keywords"][0]["word"] = json!(" ");
+        assert_eq!(
+            parse_analysis::<TextAnalysis>(value).unwrap_err(),
+            "empty keyword in product 1"
+        );
+
+        let photo = json!({
+            "version": 1,
+            "products": [{
+                "productId": 3,
+                "qualityScore": 0.5,
+                "findings": [{ "issue": "", "severity": "low" }]
+            }]
+        });
+        assert_eq!(
+            parse_analysis::<PhotoAnalysis>(photo).unwrap_err(),
+            "empty finding in product 3"
+        );
+    }
+
+    #[test]
+    fn results_for_other_products_are_refused() {
+        let value = json!({
+            "version": 1,
+            "products": [
+                { "productId": 1, "qualityScore": 0.5, "findings": [] },
+                { "productId": 9, "qualityScore": 0.5, "findings": [] }
+            ]
+        });
+        assert_eq!(
+            parse_task_analysis::<PhotoAnalysis>(value.clone(), &[1, 2]).unwrap_err(),
+            "product 9 is not part of the task"
+        );
+        let photo: PhotoAnalysis = parse_task_analysis(value, &[1, 2, 9]).unwrap();
+        assert_eq!(photo.product_ids(), [1, 9]);
+
+        // A result may leave out products, e.g. competitors without photos.
+        let text: TextAnalysis = parse_task_analysis(text_analysis(), &[1, 2, 3]).unwrap();
+        assert_eq!(text.product_ids(), [1]);
+        assert_eq!(
+            parse_task_analysis::<TextAnalysis>(text_analysis(), &[]).unwrap_err(),
+            "product 1 is not part of the task"
+        );
+    }
+
+    #[test]
+    fn malformed_results_are_refused() {
+        assert!(parse_analysis::<TextAnalysis>(json!("free text")).is_err());
+        assert!(parse_analysis::<TextAnalysis>(json!({ "version": 1 })).is_err());
+    }
+
+    #[derive(Deserialize)]
+    struct Stored {
+        #[serde(default, deserialize_with = "deserialize_legacy")]
+        text_analysis: Option<TextAnalysis>,
+    }
+
+    #[test]
+    fn legacy_and_mismatched_results_are_read_as_missing() {
+        let stored: Stored =
+            bson::from_document(bson::doc! { "text_analysis": "free text" }).unwrap();
+        assert!(stored.text_analysis.is_none());
+
+        let stored: Stored =
+            bson::from_document(bson::doc! { "text_analysis": { "version": 1 } }).unwrap();
+        assert!(stored.text_analysis.is_none());
+
+        let stored: Stored = bson::from_document(bson::doc! {}).unwrap();
+        assert!(stored.text_analysis.is_none());
+
+        let analysis = parse_analysis::<TextAnalysis>(text_analysis()).unwrap();
+        let document = bson::doc! { "text_analysis": bson::to_bson(&analysis).unwrap() };
+        let stored: Stored = bson::from_document(document).unwrap();
+        assert_eq!(stored.text_analysis.unwrap().products[0].summary, "soft");
+    }
+}
@@ -1,2 +1,3 @@
+pub mod analysis_structures;
 pub mod receive_structures;
 pub mod send_structures;
@@ -1,4 +1,6 @@
+use crate::database_function::function_postgre::SubscriptionPlan;
 use chrono::{DateTime, Utc};
+use rocket::FromForm;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -75,6 +77,12 @@ pub struct DeleteTask {
     pub id: uuid::Uuid,
 }
 
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CancelTask {
+    pub id: uuid::Uuid,
+}
+
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct EditTask {
@@ -85,6 +93,14 @@ pub struct EditTask {
     pub unused_words: Vec<String>,
 }
 
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RerunAnalysis {
+    pub id: uuid::Uuid,
+    #[serde(rename = "taskType")]
+    pub task_type: String,
+}
+
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct EditTaskName {
@@ -101,9 +117,68 @@ pub struct GetTask {
 
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
-pub struct TaskMessage {
-    pub message: String,
-    pub task_type: String,
+pub struct RequeueDeadLetter {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CreateWebhook {
+    pub url: String,
+    /// Only notify about this task instead of all of the user's tasks.
+    #[serde(rename = "taskId", default)]
+    pub task_id: Option<uuid::Uuid>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DeleteWebhook {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EditTaskTags {
+    pub id: uuid::Uuid,
+    pub tags: Vec<String>,
+}
+
+/// Query of `/get/history`; without any of it the first page of all tasks, newest first.
+#[derive(FromForm, Default)]
+pub struct HistoryQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    /// Created at or after, RFC 3339 or `YYYY-MM-DD`.
+    pub from: Option<String>,
+    /// Created before, RFC 3339 or `YYYY-MM-DD`.
+    pub to: Option<String>,
+    /// Comma-separated task statuses.
+    pub status: Option<String>,
+    pub tag: Option<String>,
+    /// Words the task name starts with.
+    pub q: Option<String>,
+    /// `createdAt` (default) or `name`.
+    pub sort: Option<String>,
+    /// `asc` or `desc`; defaults to `desc` for `createdAt` and `asc` for `name`.
+    pub order: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CreateSchedule {
+    #[serde(rename = "taskId")]
+    pub task_id: uuid::Uuid,
+    /// Five-field cron expression in UTC, e.g. `0 6 * * 1` for Mondays at 06:00.
+    #[serde(default)]
+    pub cron: Option<String>,
+    #[serde(rename = "intervalSecs", default)]
+    pub interval_secs: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DeleteSchedule {
+    pub id: uuid::Uuid,
 }
 
 #[derive(Deserialize)]
@@ -112,7 +187,10 @@ pub struct InformationTask {
     pub id: uuid::Uuid,
     #[serde(rename = "taskType")]
     pub task_type: String,
-    pub message: String,
+    pub message: serde_json::Value,
+    /// Run of the job the result answers; results of earlier runs are refused.
+    #[serde(default)]
+    pub run: Option<u32>,
 }
 
 #[derive(Deserialize)]
@@ -128,4 +206,6 @@ pub struct CreateSubscribe {
     pub created_at: DateTime<Utc>,
     pub valid_to: DateTime<Utc>,
     pub user_id: String,
+    #[serde(default)]
+    pub plan: SubscriptionPlan,
 }
@@ -1,3 +1,6 @@
+use crate::structure::analysis_structures::{
+    PhotoAnalysis, ReviewAnalysis, Sentiment, Severity, TextAnalysis,
+};
 use chrono::{TimeDelta, Utc};
 use serde::Serialize;
 use uuid::Uuid;
@@ -24,49 +27,128 @@ pub struct ErrorMessage {
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct UsedWord {
-    pub id_word: i64,
-    pub word: String,
-    pub used: bool,
+pub struct Product {
+    pub id: u64,
+    pub root: u64,
+    pub name: String,
+    pub brand: String,
+    pub price: f64,
+    pub review_rating: f64,
+    pub description: String,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct RequestMessage {
-    pub id_tasks: i64,
+pub struct Task {
+    pub run: u32,
+    pub main: Product,
+    pub products: Vec<Product>,
+    pub used_words: Vec<String>,
+    pub unused_words: Vec<String>,
+    pub text_analyses: Option<TextAnalysis>,
+    pub photo_analyses: Option<PhotoAnalysis>,
+    pub review_analyses: Option<ReviewAnalysis>,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct HistoryAnswer {
-    pub id: i32,
+pub struct TaskRunElement {
+    pub run: u32,
+    pub created_at: chrono::DateTime<Utc>,
+    pub current: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskRuns {
+    pub runs: Vec<TaskRunElement>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffProduct {
+    pub id: u64,
     pub name: String,
-    pub status: String,
-    pub updated: chrono::DateTime<Utc>,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Product {
+pub struct ProductChange {
     pub id: u64,
-    pub root: u64,
     pub name: String,
-    pub brand: String,
-    pub price: f64,
-    pub review_rating: f64,
-    pub description: String,
+    pub price_before: f64,
+    pub price_after: f64,
+    pub price_delta: f64,
+    pub rating_before: f64,
+    pub rating_after: f64,
+    pub rating_delta: f64,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Task {
-    pub main: Product,
-    pub products: Vec<Product>,
-    pub used_words: Vec<String>,
-    pub unused_words: Vec<String>,
-    pub text_analyses: Option<String>,
-    pub photo_analyses: Option<String>,
-    pub review_analyses: Option<String>,
+pub struct KeywordChanges {
+    pub moved_to_used: Vec<String>,
+    pub moved_to_unused: Vec<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "lowercase",
+    rename_all_fields = "camelCase"
+)]
+pub enum AnalysisChange {
+    Text {
+        product_id: u64,
+        before: Option<String>,
+        after: Option<String>,
+    },
+    Photo {
+        product_id: u64,
+        before: Option<f32>,
+        after: Option<f32>,
+    },
+    Reviews {
+        product_id: u64,
+        before: Option<Sentiment>,
+        after: Option<Sentiment>,
+        score_delta: Option<f32>,
+    },
+    Keyword {
+        product_id: u64,
+        word: String,
+        before: Option<f32>,
+        after: Option<f32>,
+    },
+    Finding {
+        product_id: u64,
+        issue: String,
+        before: Option<Severity>,
+        after: Option<Severity>,
+    },
+    Theme {
+        product_id: u64,
+        theme: String,
+        before: Option<Sentiment>,
+        after: Option<Sentiment>,
+        mentions_delta: i64,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskDiff {
+    pub from_run: u32,
+    pub to_run: u32,
+    /// Price and rating of the main product, when either changed.
+    pub main_product: Option<ProductChange>,
+    pub added_competitors: Vec<DiffProduct>,
+    pub removed_competitors: Vec<DiffProduct>,
+    pub changed_competitors: Vec<ProductChange>,
+    pub keywords: KeywordChanges,
+    pub analyses: Vec<AnalysisChange>,
 }
 
 #[derive(Serialize)]
@@ -75,12 +157,16 @@ pub struct HistoryElement {
     pub id: Uuid,
     pub name: String,
     pub created_at: chrono::DateTime<Utc>,
+    pub status: String,
+    pub tags: Vec<String>,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct History {
     pub elements: Vec<HistoryElement>,
+    /// Pass as `cursor` to get the next page; `null` on the last one.
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -109,6 +195,88 @@ pub struct TaskId {
 pub struct SendMessage {
     pub message: String,
     pub task_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Set on `error` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ProgressErrorCode>,
+}
+
+/// Why a progress `error` event was sent.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressErrorCode {
+    /// A worker sent a message that could not be decoded; the task goes on.
+    MalformedMessage,
+    /// The analysis failed and nothing more will be sent.
+    AnalysisFailed,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetterElement {
+    pub id: Uuid,
+    pub task_id: String,
+    pub task_type: String,
+    pub reason: String,
+    pub attempts: u32,
+    pub dead_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookElement {
+    pub id: Uuid,
+    pub task_id: Option<Uuid>,
+    pub url: String,
+    /// Only returned when the webhook is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleElement {
+    pub id: Uuid,
+    pub task_id: Uuid,
+    pub cron: Option<String>,
+    pub interval_secs: Option<i64>,
+    pub paused: bool,
+    pub next_run_at: chrono::DateTime<Utc>,
+    pub last_run_at: Option<chrono::DateTime<Utc>>,
+    /// Why the last run was skipped or could not start.
+    pub last_error: Option<String>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookDeliveryElement {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub task_id: Option<Uuid>,
+    pub event: String,
+    pub status: &'static str,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: chrono::DateTime<Utc>,
+    pub delivered_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// Body POSTed to webhooks, signed in the `X-Webhook-Signature` header.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookPayload {
+    /// `task.finished`, or `webhook.test` for test deliveries.
+    pub event: &'static str,
+    pub task_id: Option<Uuid>,
+    pub name: Option<String>,
+    pub status: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+    pub finished_at: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(Serialize)]
@@ -0,0 +1,622 @@
+use crate::broker::{JobPublisher, ProgressSubscriber, PublishError};
+use crate::contracts::{decode_job, ContentType, ProgressEvent};
+use crate::database_function::{
+    archive_progress, claim_notifications, claim_outbox_messages, claim_webhook_deliveries,
+    connection_mongo::Pool as MongoPool, enqueue_finished_task_emails,
+    enqueue_finished_task_webhooks, enqueue_subscription_reminders, fail_expired_tasks,
+    fail_outbox_message, fail_task, finished_tasks_to_archive, function_mongo::DeadLetter,
+    function_postgre::DeliveryStatus, function_postgre::Schedule, get_task_product_id,
+    mark_notification_sent, mark_outbox_published, park_dead_letter, record_schedule_run,
+    record_webhook_attempt, reschedule_notification, reschedule_outbox_message,
+    start_scheduled_run, sub_is_exist, take_due_schedules,
+};
+use crate::mail::{self, Mail, MailSender};
+use crate::parser;
+use crate::rabbit::{
+    consume_dead_letters, delivery_attempt, delivery_content_type, delivery_priority, retry_delay,
+    RabbitChannel,
+};
+use crate::structure::analysis_structures::AnalysisKind;
+use crate::utils::{env_or, reconnect_delay};
+use crate::webhooks::{self, WebhookSender};
+use chrono::Utc;
+use deadpool_postgres::Pool as PostgresPool;
+use futures::StreamExt;
+use lapin::{
+    message::Delivery,
+    options::{BasicAckOptions, BasicNackOptions},
+};
+use log::{error, info, warn};
+use mongodb::bson::DateTime;
+use rocket::{Orbit, Rocket};
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+const DEADLINE_REASON: &str = "analysis deadline exceeded";
+
+pub fn spawn_supervisor(rocket: &Rocket<Orbit>) {
+    let (
+        Some(postgre_pool),
+        Some(mongo_pool),
+        Some(jobs),
+        Some(progress),
+        Some(webhook_sender),
+        Some(mail_sender),
+    ) = (
+        rocket.state::<PostgresPool>().cloned(),
+        rocket.state::<MongoPool>().cloned(),
+        rocket.state::<Arc<dyn JobPublisher>>().cloned(),
+        rocket.state::<Arc<dyn ProgressSubscriber>>().cloned(),
+        rocket.state::<WebhookSender>().cloned(),
+        rocket.state::<Arc<dyn MailSender>>().cloned(),
+    )
+    else {
+        error!("Analysis supervisor is not started: missing managed state");
+        return;
+    };
+
+    tokio::spawn(run_outbox_relay(postgre_pool.clone(), jobs));
+    tokio::spawn(run_deadline_watchdog(
+        postgre_pool.clone(),
+        progress.clone(),
+    ));
+    tokio::spawn(run_progress_archiver(
+        postgre_pool.clone(),
+        mongo_pool.clone(),
+        progress.clone(),
+    ));
+    tokio::spawn(run_webhook_dispatcher(postgre_pool.clone(), webhook_sender));
+    tokio::spawn(run_notifier(postgre_pool.clone(), mail_sender));
+    tokio::spawn(run_scheduler(
+        postgre_pool.clone(),
+        mongo_pool.clone(),
+        progress.clone(),
+    ));
+    // Only RabbitMQ dead-letters jobs; other brokers have nothing to retry.
+    if let Some(channel) = rocket.state::<RabbitChannel>().cloned() {
+        tokio::spawn(run_dead_letter_consumer(
+            channel,
+            postgre_pool,
+            mongo_pool,
+            progress,
+        ));
+    }
+}
+
+async fn report_failure(
+    postgre_pool: &PostgresPool,
+    progress: &dyn ProgressSubscriber,
+    id: Uuid,
+    reason: &str,
+) {
+    match fail_task(postgre_pool, &id, reason).await {
+        Ok(true) => {
+            let reason = reason.to_string();
+            if let Err(e) = progress.publish(id, ProgressEvent::Failed { reason }).await {
+                error!("Failed to publish failure of task {}: {}", id, e);
+            }
+        }
+        Ok(false) => info!("Task {} is not running, failure not reported", id),
+        Err(e) => error!("Failed to mark task {} as failed: {}", id, e),
+    }
+}
+
+/// Publishes outbox messages committed with their tasks, rescheduling failed ones with backoff.
+/// Messages are leased to one relay at a time, so several instances can run side by side.
+async fn run_outbox_relay(postgre_pool: PostgresPool, jobs: Arc<dyn JobPublisher>) {
+    let period = Duration::from_millis(env_or("OUTBOX_POLL_MILLIS", 500));
+    let batch: i64 = env_or("OUTBOX_BATCH_SIZE", 100);
+    let lease = chrono::Duration::seconds(env_or("OUTBOX_LEASE_SECS", 60));
+    loop {
+        tokio::time::sleep(period).await;
+        let pending = match claim_outbox_messages(&postgre_pool, batch, lease).await {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Failed to read outbox: {}", e);
+                continue;
+            }
+        };
+        for message in pending {
+            let Some(kind) = AnalysisKind::parse(&message.kind) else {
+                error!(
+                    "Outbox message {} has unknown kind {}",
+                    message.id, message.kind
+                );
+                let reason = format!("unknown kind {}", message.kind);
+                if let Err(e) = fail_outbox_message(&postgre_pool, &message.id, &reason).await {
+                    error!("Failed to fail outbox message {}: {}", message.id, e);
+                }
+                continue;
+            };
+            match jobs
+                .publish_job(
+                    &message.task_id,
+                    kind,
+                    ContentType::from_header(Some(&message.content_type)),
+                    &message.payload,
+                    message.priority.max(0) as u8,
+                    0,
+                )
+                .await
+            {
+                Ok(()) => {
+                    if let Err(e) = mark_outbox_published(&postgre_pool, &message.id).await {
+                        error!(
+                            "Failed to mark outbox message {} as published: {}",
+                            message.id, e
+                        );
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to publish {} job of task {} (attempt {}): {}",
+                        message.kind,
+                        message.task_id,
+                        message.attempts + 1,
+                        e
+                    );
+                    let delay = reconnect_delay(message.attempts.max(0) as u32);
+                    let next_attempt_at = Utc::now()
+                        + chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::zero());
+                    if let Err(e) = reschedule_outbox_message(
+                        &postgre_pool,
+                        &message.id,
+                        next_attempt_at,
+                        &e.to_string(),
+                    )
+                    .await
+                    {
+                        error!("Failed to reschedule outbox message {}: {}", message.id, e);
+                    }
+                    if matches!(e, PublishError::Unavailable) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn run_deadline_watchdog(postgre_pool: PostgresPool, progress: Arc<dyn ProgressSubscriber>) {
+    let period = Duration::from_secs(env_or("ANALYSIS_DEADLINE_CHECK_SECS", 30));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let expired = match fail_expired_tasks(&postgre_pool, DEADLINE_REASON).await {
+            Ok(expired) => expired,
+            Err(e) => {
+                error!("Failed to check task deadlines: {}", e);
+                continue;
+            }
+        };
+        for id in expired {
+            warn!("Task {} exceeded its deadline", id);
+            let reason = DEADLINE_REASON.to_string();
+            if let Err(e) = progress.publish(id, ProgressEvent::Failed { reason }).await {
+                error!("Failed to publish failure of task {}: {}", id, e);
+            }
+        }
+    }
+}
+
+/// Copies the progress of finished tasks to MongoDB before the broker's retention drops it.
+///
+/// Tasks are archived `PROGRESS_ARCHIVE_DELAY_SECS` after finishing, so events published
+/// right after the status change are included.
+async fn run_progress_archiver(
+    postgre_pool: PostgresPool,
+    mongo_pool: MongoPool,
+    progress: Arc<dyn ProgressSubscriber>,
+) {
+    let period = Duration::from_secs(env_or("PROGRESS_ARCHIVE_SECS", 60));
+    let delay = chrono::Duration::seconds(env_or("PROGRESS_ARCHIVE_DELAY_SECS", 30));
+    let batch: i64 = env_or("PROGRESS_ARCHIVE_BATCH_SIZE", 50);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let finished =
+            match finished_tasks_to_archive(&postgre_pool, Utc::now() - delay, batch).await {
+                Ok(finished) => finished,
+                Err(e) => {
+                    error!("Failed to find tasks to archive: {}", e);
+                    continue;
+                }
+            };
+        for (id, run_start) in finished {
+            let history = match progress.history(id, run_start).await {
+                Ok(history) => history,
+                Err(e) => {
+                    warn!("Failed to read progress of task {}: {}", id, e);
+                    continue;
+                }
+            };
+            if let Err(e) =
+                archive_progress(&postgre_pool, &mongo_pool, &id, run_start, history).await
+            {
+                error!("Failed to archive progress of task {}: {:?}", id, e);
+            }
+        }
+    }
+}
+
+/// Queues deliveries for newly finished tasks and sends the due ones, retrying failures with
+/// backoff until `WEBHOOK_MAX_ATTEMPTS`.
+async fn run_webhook_dispatcher(postgre_pool: PostgresPool, sender: WebhookSender) {
+    let period = Duration::from_millis(env_or("WEBHOOK_POLL_MILLIS", 1000));
+    let batch: i64 = env_or("WEBHOOK_BATCH_SIZE", 50);
+    let max_attempts: i32 = env_or("WEBHOOK_MAX_ATTEMPTS", 8);
+    let lease = chrono::Duration::seconds(env_or("WEBHOOK_LEASE_SECS", 60));
+    loop {
+        tokio::time::sleep(period).await;
+        if let Err(e) = enqueue_finished_task_webhooks(&postgre_pool, batch).await {
+            error!("Failed to queue webhooks of finished tasks: {}", e);
+        }
+        let due = match claim_webhook_deliveries(&postgre_pool, batch, lease).await {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Failed to read webhook deliveries: {}", e);
+                continue;
+            }
+        };
+        let results = futures::future::join_all(due.iter().map(|delivery| {
+            sender.send(
+                &delivery.id,
+                &delivery.url,
+                &delivery.secret,
+                &delivery.payload,
+            )
+        }))
+        .await;
+        for (delivery, result) in due.iter().zip(results) {
+            let attempt = delivery.attempts + 1;
+            let (status, response_status, error, delay) = match result {
+                Ok(response_status) => (
+                    DeliveryStatus::Delivered,
+                    Some(response_status),
+                    None,
+                    Duration::ZERO,
+                ),
+                Err(e) => {
+                    let status = if attempt >= max_attempts {
+                        warn!(
+                            "Giving up webhook delivery {} after {} attempts: {}",
+                            delivery.id, attempt, e
+                        );
+                        DeliveryStatus::Failed
+                    } else {
+                        info!(
+                            "Webhook delivery {} failed (attempt {}): {}",
+                            delivery.id, attempt, e
+                        );
+                        DeliveryStatus::Pending
+                    };
+                    let delay = webhooks::retry_delay(delivery.attempts.max(0) as u32);
+                    (status, e.response_status(), Some(e.to_string()), delay)
+                }
+            };
+            let next_attempt_at =
+                Utc::now() + chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::zero());
+            if let Err(e) = record_webhook_attempt(
+                &postgre_pool,
+                &delivery.id,
+                status,
+                response_status.map(i32::from),
+                error.as_deref(),
+                next_attempt_at,
+            )
+            .await
+            {
+                error!("Failed to record webhook delivery {}: {}", delivery.id, e);
+            }
+        }
+    }
+}
+
+/// Queues emails about finished tasks and expiring subscriptions, then sends what is due.
+async fn run_notifier(postgre_pool: PostgresPool, sender: Arc<dyn MailSender>) {
+    let period = Duration::from_secs(env_or("NOTIFY_POLL_SECS", 10));
+    let batch: i64 = env_or("NOTIFY_BATCH_SIZE", 50);
+    let max_attempts: i32 = env_or("NOTIFY_MAX_ATTEMPTS", 5);
+    let lease = chrono::Duration::seconds(env_or("NOTIFY_LEASE_SECS", 300));
+    let remind_within = chrono::Duration::days(env_or("SUBSCRIPTION_REMINDER_DAYS", 3));
+    loop {
+        tokio::time::sleep(period).await;
+        if let Err(e) = enqueue_finished_task_emails(&postgre_pool, batch).await {
+            error!("Failed to queue emails of finished tasks: {}", e);
+        }
+        if let Err(e) = enqueue_subscription_reminders(&postgre_pool, remind_within).await {
+            error!("Failed to queue subscription reminders: {}", e);
+        }
+        let due = match claim_notifications(&postgre_pool, max_attempts, batch, lease).await {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Failed to read notifications: {}", e);
+                continue;
+            }
+        };
+        for notification in due {
+            let mail = Mail {
+                to: notification.recipient,
+                subject: notification.subject,
+                body: notification.body,
+            };
+            let result = match sender.send(&mail).await {
+                Ok(()) => mark_notification_sent(&postgre_pool, &notification.id).await,
+                Err(e) => {
+                    let attempt = notification.attempts + 1;
+                    if attempt >= max_attempts {
+                        warn!(
+                            "Giving up notification {} after {} attempts: {}",
+                            notification.id, attempt, e
+                        );
+                    } else {
+                        info!(
+                            "Notification {} failed (attempt {}): {}",
+                            notification.id, attempt, e
+                        );
+                    }
+                    let delay = mail::retry_delay(notification.attempts.max(0) as u32);
+                    let next_attempt_at = Utc::now()
+                        + chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::zero());
+                    reschedule_notification(
+                        &postgre_pool,
+                        &notification.id,
+                        next_attempt_at,
+                        &e.to_string(),
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = result {
+                error!("Failed to record notification {}: {}", notification.id, e);
+            }
+        }
+    }
+}
+
+/// Starts the next run of tasks whose schedule is due.
+async fn run_scheduler(
+    postgre_pool: PostgresPool,
+    mongo_pool: MongoPool,
+    progress: Arc<dyn ProgressSubscriber>,
+) {
+    let period = Duration::from_secs(env_or("SCHEDULE_POLL_SECS", 30));
+    let batch: i64 = env_or("SCHEDULE_BATCH_SIZE", 20);
+    loop {
+        tokio::time::sleep(period).await;
+        let due = match take_due_schedules(&postgre_pool, batch).await {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Failed to read due schedules: {}", e);
+                continue;
+            }
+        };
+        for schedule in due {
+            let skipped = run_schedule(&postgre_pool, &mongo_pool, progress.as_ref(), &schedule)
+                .await
+                .err();
+            if let Some(reason) = &skipped {
+                info!(
+                    "Scheduled run of task {} did not start: {}",
+                    schedule.task_id, reason
+                );
+            }
+            if let Err(e) =
+                record_schedule_run(&postgre_pool, &schedule.id, skipped.as_deref()).await
+            {
+                error!("Failed to record run of schedule {}: {}", schedule.id, e);
+            }
+        }
+    }
+}
+
+/// Refreshes the task's keywords through the parser and starts its next run, or says why not.
+async fn run_schedule(
+    postgre_pool: &PostgresPool,
+    mongo_pool: &MongoPool,
+    progress: &dyn ProgressSubscriber,
+    schedule: &Schedule,
+) -> Result<(), String> {
+    let subscribed = sub_is_exist(postgre_pool, &schedule.user_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to check subscription existence: {}", e);
+            "can't check subscription".to_string()
+        })?;
+    if !subscribed {
+        return Err("subscription is not active".to_string());
+    }
+    let product_id = get_task_product_id(mongo_pool, &schedule.user_id, &schedule.task_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to get task {}: {}", schedule.task_id, e);
+            "can't get task".to_string()
+        })?
+        .ok_or("task is not exist".to_string())?;
+    // Without fresh keywords the run goes ahead with the ones from the previous run.
+    let terms = match i32::try_from(product_id) {
+        Ok(product_id) => match parser::parsed_terms(product_id).await {
+            Ok(terms) => Some(terms),
+            Err(e) => {
+                warn!(
+                    "Failed to refresh keywords of task {}: {}",
+                    schedule.task_id, e
+                );
+                None
+            }
+        },
+        Err(_) => None,
+    };
+    let progress_start = progress.position(schedule.task_id).await.map_err(|e| {
+        error!(
+            "Failed to read progress of task {}: {}",
+            schedule.task_id, e
+        );
+        "can't read task progress".to_string()
+    })?;
+    let started = start_scheduled_run(
+        postgre_pool,
+        mongo_pool,
+        &schedule.user_id,
+        &schedule.task_id,
+        terms,
+        progress_start,
+    )
+    .await
+    .map_err(|e| {
+        error!(
+            "Failed to start scheduled run of task {}: {:?}",
+            schedule.task_id, e
+        );
+        "can't start run".to_string()
+    })?;
+    if !started {
+        return Err("previous run is still running".to_string());
+    }
+    Ok(())
+}
+
+/// Subscribes to the dead-letter queue, subscribing again whenever the RabbitMQ channel is replaced.
+async fn run_dead_letter_consumer(
+    channel: RabbitChannel,
+    postgre_pool: PostgresPool,
+    mongo_pool: MongoPool,
+    progress: Arc<dyn ProgressSubscriber>,
+) {
+    let max_retries: u32 = env_or("ANALYSIS_MAX_RETRIES", 3);
+    let mut attempt = 0;
+    loop {
+        let consumer = match channel.get() {
+            Some(current) => consume_dead_letters(&current).await,
+            None => {
+                tokio::time::sleep(reconnect_delay(attempt)).await;
+                attempt += 1;
+                continue;
+            }
+        };
+        let mut consumer = match consumer {
+            Ok(consumer) => consumer,
+            Err(e) => {
+                error!("Failed to consume dead letters: {}", e);
+                tokio::time::sleep(reconnect_delay(attempt)).await;
+                attempt += 1;
+                continue;
+            }
+        };
+        attempt = 0;
+
+        while let Some(delivery) = consumer.next().await {
+            let delivery = match delivery {
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    error!("Failed to receive dead letter: {}", e);
+                    break;
+                }
+            };
+            handle_dead_letter(
+                &channel,
+                &postgre_pool,
+                &mongo_pool,
+                progress.as_ref(),
+                delivery,
+                max_retries,
+            )
+            .await;
+        }
+        warn!("Dead letter consumer stopped, resubscribing");
+    }
+}
+
+async fn handle_dead_letter(
+    channel: &RabbitChannel,
+    postgre_pool: &PostgresPool,
+    mongo_pool: &MongoPool,
+    progress: &dyn ProgressSubscriber,
+    delivery: Delivery,
+    max_retries: u32,
+) {
+    let attempt = delivery_attempt(&delivery) + 1;
+    let priority = delivery_priority(&delivery);
+    let content_type = delivery_content_type(&delivery);
+    let job = decode_job(&delivery.data, content_type);
+    let kind = job.as_ref().ok().map(|job| job.body.kind());
+
+    if let (Some(kind), true) = (kind, attempt <= max_retries) {
+        info!(
+            "Retrying dead-lettered job in {:?} (attempt {}/{})",
+            retry_delay(attempt - 1),
+            attempt,
+            max_retries
+        );
+        // Acked only once the retry is safely queued, so a restart or a failed publish leaves
+        // the job in the dead-letter queue.
+        if let Err(e) = channel
+            .publish_retry(kind, content_type, &delivery.data, priority, attempt)
+            .await
+        {
+            error!(
+                "Failed to requeue dead-lettered job, leaving it in queue: {}",
+                e
+            );
+            return_dead_letter(delivery).await;
+            return;
+        }
+    } else {
+        // Parked jobs are kept as JSON so admins can read them regardless of the wire format.
+        // `attempt` counts this delivery, so it is also the number of attempts made.
+        let (task_type, task_id, payload, reason) = match job {
+            Ok(job) => (
+                job.body.kind().as_str().to_string(),
+                job.correlation_id.to_string(),
+                String::from_utf8_lossy(&job.encode(ContentType::Json)).into_owned(),
+                format!(
+                    "{} analysis failed after {} attempts",
+                    job.body.kind().as_str(),
+                    attempt
+                ),
+            ),
+            Err(e) => (
+                "unknown".to_string(),
+                "unknown".to_string(),
+                String::from_utf8_lossy(&delivery.data).into_owned(),
+                format!("job could not be decoded: {}", e),
+            ),
+        };
+        warn!("Parking job for task {}: {}", task_id, reason);
+        let dead_letter = DeadLetter {
+            id: Uuid::new_v4(),
+            task_id: task_id.clone(),
+            task_type,
+            payload,
+            reason: reason.clone(),
+            attempts: attempt,
+            priority,
+            dead_at: DateTime::now(),
+        };
+        if let Err(e) = park_dead_letter(mongo_pool, dead_letter).await {
+            error!("Failed to park dead letter, leaving it in queue: {}", e);
+            return_dead_letter(delivery).await;
+            return;
+        }
+        if let Ok(id) = Uuid::parse_str(&task_id) {
+            report_failure(postgre_pool, progress, id, &reason).await;
+        }
+    }
+
+    if let Err(e) = delivery.acker.ack(BasicAckOptions::default()).await {
+        error!("Failed to ack dead letter: {}", e);
+    }
+}
+
+/// Puts a dead letter that could not be handled back in its queue after a pause, so it is
+/// retried instead of being held unacked until the channel closes.
+async fn return_dead_letter(delivery: Delivery) {
+    tokio::time::sleep(reconnect_delay(0)).await;
+    let requeue = BasicNackOptions {
+        requeue: true,
+        ..Default::default()
+    };
+    if let Err(e) = delivery.acker.nack(requeue).await {
+        error!("Failed to return dead letter to queue: {}", e);
+    }
+}
@@ -1,7 +1,25 @@
 use sha2::{Digest, Sha512};
+use std::env;
 
 pub fn hash_str(path: &str) -> Result<String, std::io::Error> {
     let mut hasher = Sha512::new();
     hasher.update(path.as_bytes());
     Ok(format!("{:x}", hasher.finalize()))
 }
+
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn analysis_deadline() -> chrono::Duration {
+    chrono::Duration::seconds(env_or("ANALYSIS_DEADLINE_SECS", 1800))
+}
+
+/// Exponential backoff for broker reconnects, capped at `BROKER_RECONNECT_MAX_SECS`.
+pub fn reconnect_delay(attempt: u32) -> std::time::Duration {
+    let max: u64 = env_or("BROKER_RECONNECT_MAX_SECS", 30);
+    std::time::Duration::from_secs((1u64 << attempt.min(10)).min(max))
+}
@@ -0,0 +1,288 @@
+use crate::utils::env_or;
+use hmac::{Hmac, Mac};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    Url,
+};
+use sha2::Sha256;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use uuid::Uuid;
+
+pub const TASK_FINISHED_EVENT: &str = "task.finished";
+pub const TEST_EVENT: &str = "webhook.test";
+
+const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// The endpoint answered with a non-2xx status.
+    Status(u16),
+    Request(reqwest::Error),
+    /// The URL may not be called, see [`check_url`].
+    Refused(String),
+}
+
+impl DeliveryError {
+    pub fn response_status(&self) -> Option<u16> {
+        match self {
+            Self::Status(status) => Some(*status),
+            Self::Request(e) => e.status().map(|status| status.as_u16()),
+            Self::Refused(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "endpoint answered {}", status),
+            Self::Request(e) => write!(f, "request failed: {}", e),
+            Self::Refused(reason) => write!(f, "refused: {}", reason),
+        }
+    }
+}
+
+/// A secret shared with the receiver to verify signatures, shown once when the webhook is created.
+pub fn generate_secret() -> String {
+    format!(
+        "whsec_{}{}",
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    )
+}
+
+/// Hex HMAC-SHA256 of `<timestamp>.<body>`; the timestamp lets receivers reject replays.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Delay before retrying a delivery that failed `attempt` times, from `WEBHOOK_RETRY_BASE_SECS`.
+pub fn retry_delay(attempt: u32) -> Duration {
+    let base: u64 = env_or("WEBHOOK_RETRY_BASE_SECS", 30);
+    Duration::from_secs(base.saturating_mul(1 << attempt.min(10)))
+}
+
+/// Whether webhooks may reach `ip`. Loopback, private, link-local, unspecified and other
+/// addresses that aren't routed on the internet are refused, so a webhook can't be used to
+/// call or probe the internal network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10.
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // Unique local fc00::/7 and link-local fe80::/10.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The address a URL names literally, e.g. `127.0.0.1` or `[::1]`.
+fn literal_ip(url: &Url) -> Option<IpAddr> {
+    let host = url.host_str()?;
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// Parses a webhook URL, refusing other schemes than http(s) and addresses that aren't public.
+/// Host names are checked once they are resolved.
+fn check_url(url: &str) -> Result<Url, String> {
+    let url = Url::parse(url).map_err(|e| e.to_string())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("url must be http or https".to_string());
+    }
+    if url.host_str().is_none() {
+        return Err("url has no host".to_string());
+    }
+    match literal_ip(&url) {
+        Some(ip) if !is_public(ip) => Err(format!("{} is not a public address", ip)),
+        _ => Ok(url),
+    }
+}
+
+async fn resolve(host: &str) -> Result<Vec<SocketAddr>, String> {
+    Ok(tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(|e| format!("can't resolve {}: {}", host, e))?
+        .collect())
+}
+
+/// Checks a URL given for a webhook: every address its host resolves to must be public.
+pub async fn validate_url(url: &str) -> Result<(), String> {
+    let url = check_url(url)?;
+    let Some(host) = url.host_str().filter(|_| literal_ip(&url).is_none()) else {
+        return Ok(());
+    };
+    let addrs = resolve(host).await?;
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+        return Err(format!("{} does not resolve to a public address", host));
+    }
+    Ok(())
+}
+
+/// Connects webhooks only to public addresses, whatever the host resolves to by the time the
+/// delivery is sent.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = resolve(host)
+                .await?
+                .into_iter()
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// POSTs signed webhook payloads.
+#[derive(Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+}
+
+impl Default for WebhookSender {
+    fn default() -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(env_or("WEBHOOK_TIMEOUT_SECS", 10)))
+            .redirect(reqwest::redirect::Policy::none())
+            // A proxy would resolve the host itself, around the resolver.
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .expect("Invalid webhook client config");
+        WebhookSender { client }
+    }
+}
+
+impl WebhookSender {
+    /// Sends one delivery, returning the response status on success.
+    pub async fn send(
+        &self,
+        delivery_id: &Uuid,
+        url: &str,
+        secret: &str,
+        body: &str,
+    ) -> Result<u16, DeliveryError> {
+        let url = check_url(url).map_err(DeliveryError::Refused)?;
+        let timestamp = chrono::Utc::now().timestamp();
+        let response = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(DELIVERY_HEADER, delivery_id.to_string())
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign(secret, timestamp, body)),
+            )
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(DeliveryError::Request)?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(status.as_u16())
+        } else {
+            Err(DeliveryError::Status(status.as_u16()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} is internal", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn internal_urls_are_refused() {
+        for url in [
+            "http://127.0.0.1:6379/",
+            "http://2130706433/",
+            "http://[::1]/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://localhost:5432/",
+            "ftp://example.com/",
+        ] {
+            assert!(validate_url(url).await.is_err(), "{} is refused", url);
+        }
+        assert!(validate_url("https://93.184.216.34/hook").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn deliveries_to_internal_addresses_are_refused() {
+        let sent = WebhookSender::default()
+            .send(&Uuid::new_v4(), "http://127.0.0.1:1/", "secret", "{}")
+            .await;
+        assert!(matches!(sent, Err(DeliveryError::Refused(_))));
+
+        // Names are only known to be internal once resolved, when the resolver drops them.
+        let sent = WebhookSender::default()
+            .send(&Uuid::new_v4(), "http://localhost:1/", "secret", "{}")
+            .await;
+        let Err(DeliveryError::Request(e)) = sent else {
+            panic!("delivery to localhost was not refused: {:?}", sent);
+        };
+        assert!(format!("{:?}", e).contains("no public address"), "{:?}", e);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::broker::ProgressSubscriber;
+use crate::database_function::{
+    connection_mongo::Pool as MongoPool, function_postgre::TaskStatus, get_task_progress,
+    get_task_statuses,
+};
+use crate::progress::ProgressFeed;
+use crate::structure::send_structures::SendMessage;
+use crate::utils::env_or;
+use deadpool_postgres::Pool as PostgresPool;
+use futures::{SinkExt, StreamExt};
+use log::{error, info};
+use rocket::{
+    data::{IoHandler, IoStream},
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    response::{self, Responder, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, pin::Pin, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use uuid::Uuid;
+
+/// The `Sec-WebSocket-Key` of a request asking to upgrade to a WebSocket.
+pub struct WebSocketKey(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebSocketKey {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let upgrade = headers
+            .get("Upgrade")
+            .any(|value| value.eq_ignore_ascii_case("websocket"));
+        match headers.get_one("Sec-WebSocket-Key") {
+            Some(key) if upgrade => Outcome::Success(WebSocketKey(key.to_string())),
+            _ => Outcome::Error((
+                Status::UpgradeRequired,
+                "expected a WebSocket upgrade".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Command {
+    Subscribe { id: Uuid },
+    Unsubscribe { id: Uuid },
+}
+
+/// Messages sent to the client, each naming the task it is about.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum LiveMessage {
+    Subscribed {
+        id: Uuid,
+        status: &'static str,
+    },
+    Unsubscribed {
+        id: Uuid,
+    },
+    Progress {
+        id: Uuid,
+        sequence: u64,
+        event: &'static str,
+        data: SendMessage,
+    },
+    Status {
+        id: Uuid,
+        status: &'static str,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<Uuid>,
+        message: String,
+    },
+}
+
+/// Upgrades the request to a WebSocket multiplexing the progress and status of the user's tasks.
+pub struct LiveConnection {
+    key: WebSocketKey,
+    user_id: String,
+    pool: PostgresPool,
+    mongo_pool: MongoPool,
+    progress: Arc<dyn ProgressSubscriber>,
+}
+
+impl LiveConnection {
+    pub fn new(
+        key: WebSocketKey,
+        user_id: String,
+        pool: PostgresPool,
+        mongo_pool: MongoPool,
+        progress: Arc<dyn ProgressSubscriber>,
+    ) -> Self {
+        LiveConnection {
+            key,
+            user_id,
+            pool,
+            mongo_pool,
+            progress,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for LiveConnection {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let accept = derive_accept_key(self.key.0.as_bytes());
+        Response::build()
+            .raw_header("Sec-WebSocket-Accept", accept)
+            .upgrade("websocket", self)
+            .ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for LiveConnection {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let socket = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        let connection = *Pin::into_inner(self);
+        connection.serve(socket).await;
+        Ok(())
+    }
+}
+
+struct Subscription {
+    status: Option<TaskStatus>,
+    forward: JoinHandle<()>,
+}
+
+impl Subscription {
+    /// Whether the task may still send progress; deleted tasks and finished ones whose feed has
+    /// ended won't.
+    fn is_active(&self) -> bool {
+        match self.status {
+            Some(TaskStatus::Running) => true,
+            Some(_) => !self.forward.is_finished(),
+            None => false,
+        }
+    }
+}
+
+impl LiveConnection {
+    async fn serve(self, socket: WebSocketStream<IoStream>) {
+        let ping_every = Duration::from_secs(env_or("WS_PING_SECS", 20));
+        let max_subscriptions: usize = env_or("WS_MAX_SUBSCRIPTIONS", 50);
+        let (mut sink, mut source) = socket.split();
+        let (sender, mut outgoing) = mpsc::channel::<LiveMessage>(256);
+        let mut subscriptions: HashMap<Uuid, Subscription> = HashMap::new();
+        let mut ping = tokio::time::interval(ping_every);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_seen = Instant::now();
+
+        'connection: loop {
+            let replies = tokio::select! {
+                incoming = source.next() => {
+                    last_seen = Instant::now();
+                    match incoming {
+                        Some(Ok(Message::Text(text))) => {
+                            vec![self.handle(&text, &mut subscriptions, max_subscriptions, &sender).await]
+                        }
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Err(e)) => {
+                            info!("WebSocket of user {} failed: {}", self.user_id, e);
+                            break;
+                        }
+                        Some(Ok(_)) => continue,
+                    }
+                }
+                Some(message) = outgoing.recv() => vec![message],
+                _ = ping.tick() => {
+                    if last_seen.elapsed() > ping_every * 2 {
+                        info!("WebSocket of user {} stopped answering pings", self.user_id);
+                        break;
+                    }
+                    if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                    self.check_statuses(&mut subscriptions).await
+                }
+            };
+            for reply in replies {
+                let text = match serde_json::to_string(&reply) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        error!("Failed to serialize live message: {}", e);
+                        continue;
+                    }
+                };
+                if sink.send(Message::Text(text)).await.is_err() {
+                    break 'connection;
+                }
+            }
+        }
+
+        for subscription in subscriptions.into_values() {
+            subscription.forward.abort();
+        }
+        let _ = sink.close().await;
+    }
+
+    async fn handle(
+        &self,
+        text: &str,
+        subscriptions: &mut HashMap<Uuid, Subscription>,
+        max_subscriptions: usize,
+        sender: &mpsc::Sender<LiveMessage>,
+    ) -> LiveMessage {
+        let command = match serde_json::from_str::<Command>(text) {
+            Ok(command) => command,
+            Err(e) => {
+                return LiveMessage::Error {
+                    id: None,
+                    message: format!("invalid command: {}", e),
+                }
+            }
+        };
+        match command {
+            Command::Unsubscribe { id } => {
+                if let Some(subscription) = subscriptions.remove(&id) {
+                    subscription.forward.abort();
+                }
+                LiveMessage::Unsubscribed { id }
+            }
+            Command::Subscribe { id } if subscriptions.contains_key(&id) => LiveMessage::Error {
+                id: Some(id),
+                message: "already subscribed".to_string(),
+            },
+            Command::Subscribe { id } if subscriptions.len() >= max_subscriptions => {
+                LiveMessage::Error {
+                    id: Some(id),
+                    message: format!("at most {} subscriptions", max_subscriptions),
+                }
+            }
+            Command::Subscribe { id } => match self.subscribe(id, sender).await {
+                Ok(subscription) => {
+                    let status = subscription.status.map_or("running", |s| s.as_str());
+                    subscriptions.insert(id, subscription);
+                    LiveMessage::Subscribed { id, status }
+                }
+                Err(message) => LiveMessage::Error {
+                    id: Some(id),
+                    message,
+                },
+            },
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        id: Uuid,
+        sender: &mpsc::Sender<LiveMessage>,
+    ) -> Result<Subscription, String> {
+        let task = get_task_progress(&self.pool, &self.user_id, &id)
+            .await
+            .map_err(|e| {
+                error!("Failed to get task status: {}", e);
+                "can't get task status".to_string()
+            })?
+            .ok_or("task is not exist".to_string())?;
+        let feed = ProgressFeed::open(&self.mongo_pool, self.progress.as_ref(), id, &task, None)
+            .await
+            .map_err(|e| {
+                error!("Failed to create consumer: {}", e);
+                "can't create consumer".to_string()
+            })?;
+        let sender = sender.clone();
+        let forward = tokio::spawn(async move {
+            let ProgressFeed {
+                mut messages,
+                closing,
+                mut view,
+            } = feed;
+            let mut sequence = 0;
+            while let Some(message) = messages.next().await {
+                sequence = message.sequence;
+                for event in view.events(message.event) {
+                    let live = LiveMessage::Progress {
+                        id,
+                        sequence,
+                        event: event.name,
+                        data: event.message,
+                    };
+                    if sender.send(live).await.is_err() || event.terminal {
+                        return;
+                    }
+                }
+            }
+            if let Some(closing) = closing {
+                let live = LiveMessage::Progress {
+                    id,
+                    sequence,
+                    event: closing.name,
+                    data: closing.message,
+                };
+                let _ = sender.send(live).await;
+            }
+        });
+        Ok(Subscription {
+            status: Some(task.status),
+            forward,
+        })
+    }
+
+    /// Reports tasks whose status changed since the last check, and drops the subscriptions of
+    /// finished tasks once their progress has been sent so they stop counting against
+    /// `WS_MAX_SUBSCRIPTIONS`.
+    async fn check_statuses(
+        &self,
+        subscriptions: &mut HashMap<Uuid, Subscription>,
+    ) -> Vec<LiveMessage> {
+        if subscriptions.is_empty() {
+            return Vec::new();
+        }
+        let ids: Vec<Uuid> = subscriptions.keys().copied().collect();
+        let statuses = match get_task_statuses(&self.pool, &self.user_id, &ids).await {
+            Ok(statuses) => statuses,
+            Err(e) => {
+                error!("Failed to get task statuses: {}", e);
+                return Vec::new();
+            }
+        };
+        let mut changes = Vec::new();
+        for (id, subscription) in subscriptions.iter_mut() {
+            let status = statuses.get(id).copied();
+            if status == subscription.status {
+                continue;
+            }
+            subscription.status = status;
+            let live = match status {
+                Some(status) => LiveMessage::Status {
+                    id: *id,
+                    status: status.as_str(),
+                },
+                None => LiveMessage::Error {
+                    id: Some(*id),
+                    message: "task is not exist".to_string(),
+                },
+            };
+            changes.push(live);
+        }
+        subscriptions.retain(|id, subscription| {
+            if subscription.is_active() {
+                return true;
+            }
+            subscription.forward.abort();
+            changes.push(LiveMessage::Unsubscribed { id: *id });
+            false
+        });
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn subscription(status: Option<TaskStatus>, feed_ended: bool) -> Subscription {
+        let forward = if feed_ended {
+            let forward = tokio::spawn(async {});
+            while !forward.is_finished() {
+                tokio::task::yield_now().await;
+            }
+            forward
+        } else {
+            tokio::spawn(std::future::pending())
+        };
+        Subscription { status, forward }
+    }
+
+    #[tokio::test]
+    async fn running_tasks_stay_subscribed() {
+        assert!(subscription(Some(TaskStatus::Running), true)
+            .await
+            .is_active());
+    }
+
+    #[tokio::test]
+    async fn finished_tasks_stay_subscribed_until_their_feed_ends() {
+        let sending = subscription(Some(TaskStatus::Completed), false).await;
+        assert!(sending.is_active());
+        sending.forward.abort();
+        assert!(!subscription(Some(TaskStatus::Completed), true)
+            .await
+            .is_active());
+    }
+
+    #[tokio::test]
+    async fn deleted_tasks_are_dropped() {
+        let deleted = subscription(None, false).await;
+        assert!(!deleted.is_active());
+        deleted.forward.abort();
+    }
+}